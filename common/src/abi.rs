@@ -0,0 +1,52 @@
+// Solidity ABI encoding of an L2 header, for an L1 contract that reads the header out of
+// the public values instead of only the commitments. Keys are the 65 byte uncompressed
+// points WithdrawalRecord::leaf_hash covers, with the withdrawal's index in the array,
+// so the contract can check them against withdrawals_root.
+use alloy_primitives::{Bytes, FixedBytes};
+use alloy_sol_types::{sol, SolValue};
+use crate::common::*;
+
+sol! {
+    struct SolWithdrawal {
+        bytes to;
+        uint128 amount;
+    }
+
+    struct SolBlockHeaderL2 {
+        bytes32 parent;
+        bytes32 state_root;
+        uint32 sqn;
+        uint64 timestamp;
+        bytes32 txns_hash;
+        bytes32 inbox_msg_hash;
+        uint32 inbox_msg_count;
+        SolWithdrawal[] withdrawals;
+        uint128 total_fees;
+        bytes32 withdrawals_root;
+    }
+}
+
+impl BlockHeaderL2 {
+    pub fn to_sol(&self) -> SolBlockHeaderL2 {
+        SolBlockHeaderL2 {
+            parent: FixedBytes(self.parent),
+            state_root: FixedBytes(self.state_root),
+            sqn: self.sqn,
+            timestamp: self.timestamp,
+            txns_hash: FixedBytes(self.txns_hash),
+            inbox_msg_hash: FixedBytes(self.inbox_msg_hash),
+            inbox_msg_count: self.inbox_msg_count,
+            withdrawals: self.withdrawals.iter().map(|w| SolWithdrawal {
+                to: Bytes::copy_from_slice(w.to.to_encoded_point(false).as_bytes()),
+                amount: w.amount.0,
+            }).collect(),
+            total_fees: self.total_fees,
+            withdrawals_root: FixedBytes(self.withdrawals_root),
+        }
+    }
+
+    // deterministic, the same header always gives the same bytes
+    pub fn abi_encode(&self) -> Vec<u8> {
+        self.to_sol().abi_encode()
+    }
+}
@@ -0,0 +1,62 @@
+// The one place that decides the wire format of serialized blocks, headers and receipts.
+// Format: little endian, fixed width integers, trailing bytes allowed, at most SIZE_LIMIT bytes.
+// This is what bincode v1 `bincode::serialize` produces, and what bincode v2 calls `legacy`,
+// so switching the `bincode2` feature on must not change a single byte.
+use serde::{de::DeserializeOwned, Serialize};
+use crate::common::{EngineError, ResultT};
+
+pub const SIZE_LIMIT: usize = 1 << 30;
+
+#[cfg(not(feature = "bincode2"))]
+fn options() -> impl bincode::Options {
+    use bincode::Options;
+    bincode::DefaultOptions::new()
+        .with_little_endian()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(SIZE_LIMIT as u64)
+}
+
+#[cfg(not(feature = "bincode2"))]
+pub fn serialize<T: Serialize>(value: &T) -> ResultT<Vec<u8>> {
+    use bincode::Options;
+    options().serialize(value).map_err(|_| EngineError::Serialize)
+}
+
+#[cfg(not(feature = "bincode2"))]
+pub fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> ResultT<T> {
+    use bincode::Options;
+    options().deserialize(bytes).map_err(|_| EngineError::Deserialize)
+}
+
+// deserialize, with bytes past the value an error rather than ignored
+#[cfg(not(feature = "bincode2"))]
+pub fn deserialize_exact<T: DeserializeOwned>(bytes: &[u8]) -> ResultT<T> {
+    use bincode::Options;
+    options().reject_trailing_bytes().deserialize(bytes).map_err(|_| EngineError::Deserialize)
+}
+
+#[cfg(feature = "bincode2")]
+fn options() -> bincode2::config::Configuration<bincode2::config::LittleEndian, bincode2::config::Fixint, bincode2::config::Limit<SIZE_LIMIT>> {
+    bincode2::config::legacy().with_limit::<SIZE_LIMIT>()
+}
+
+#[cfg(feature = "bincode2")]
+pub fn serialize<T: Serialize>(value: &T) -> ResultT<Vec<u8>> {
+    bincode2::serde::encode_to_vec(value, options()).map_err(|_| EngineError::Serialize)
+}
+
+#[cfg(feature = "bincode2")]
+pub fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> ResultT<T> {
+    bincode2::serde::decode_from_slice(bytes, options())
+        .map(|(value, _)| value)
+        .map_err(|_| EngineError::Deserialize)
+}
+
+#[cfg(feature = "bincode2")]
+pub fn deserialize_exact<T: DeserializeOwned>(bytes: &[u8]) -> ResultT<T> {
+    match bincode2::serde::decode_from_slice(bytes, options()) {
+        Ok((value, read)) if read == bytes.len() => Ok(value),
+        _ => Err(EngineError::Deserialize),
+    }
+}
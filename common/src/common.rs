@@ -1,6 +1,8 @@
-use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt;
 use std::fmt::Debug;
+use std::marker::PhantomData;
+use std::sync::OnceLock;
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
@@ -9,12 +11,17 @@ use k256::{
     ecdsa::{signature::{Signer, Verifier}, Signature},
     // EncodedPoint,
 };
+use serde::de::DeserializeOwned;
 
 pub type SigningKey = k256::ecdsa::SigningKey;
 pub type VerifyingKey = k256::ecdsa::VerifyingKey;
 
 
 use partial_binary_merkle::PartialMerkleTrie;
+use crate::bincode_config;
+use crate::events::EngineObserver;
+#[cfg(any(test, feature = "metrics"))]
+use crate::metrics::Metrics;
 
 pub const ONE_BILLION: u128 = 1_000_000_000;
 pub const GENESIS_AMOUNT: u128 = ONE_BILLION;
@@ -31,11 +38,153 @@ pub fn clock() -> u128 {
 pub const HASH_LEN: usize = 32;
 
 pub type Hash = [u8; HASH_LEN];
+// what a process_* call reports for a leaf it leaves update_tree to hash, see Account::leaf
+pub const UNHASHED: Hash = [0u8; HASH_LEN];
 pub type AccountID = Hash;
 pub type DefaultHasher = Sha256;
-pub type ResultT<T> = Result<T, &'static str>;
+pub type ResultT<T> = Result<T, EngineError>;
 pub type TxResult = ResultT<Vec<(AccountID, Hash)>>;
 
+// Why the engines rejected a tx or a block. as_str is the short message the engines
+// returned before the enum, Display prints it, and comparing with a &str compares it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineError {
+    BadSig,
+    // from verify_all_sigs, the index of the first txn of the block with a bad signature
+    BadSigAt(u32),
+    // signed for another chain, see ChainParams::chain_id
+    WrongChain,
+    Expired,
+    BadSqn,
+    // the sender is at the last sqn, see sender_check
+    SqnOverflow,
+    // the payload's sender_qualify failed, e.g. the amount is over the balance
+    SenderUnqualified,
+    UnknownAccount,
+    InsufficientBalance,
+    Overflow,
+    // an account the data should have, e.g. the rollup of a deposit
+    MissingAccount,
+    NotRollupAccount,
+    // one account where two distinct ones are needed
+    SameAccount,
+    // a rollup update from an account without rollup state
+    SenderNotRollup,
+    RollupExists,
+    AccountExists,
+    // CloseAccount of an account with a balance or a rollup
+    AccountNotEmpty,
+    RollupSelf,
+    GenesisInbox,
+    BelowMinimum,
+    // a rollup created with less than ChainParams::min_rollup_bond
+    BondTooLow,
+    ZeroAmount,
+    // a rollup update withdraws more than the rollup holds
+    WithdrawalsExceedBalance,
+    // the withdrawals of an update don't even sum in a u128
+    WithdrawOverflow,
+    NoReceipts,
+    InvalidReceipt,
+    BadParent,
+    InboxMismatch,
+    // a header consumes more messages than the inbox holds
+    InboxCount,
+    // the consumed messages add up to more than the rollup's pending_deposits
+    PendingDeposits,
+    InboxFull,
+    StaleTimestamp,
+    ConditionUnmet,
+    TotalMismatch,
+    Counterparty,
+    BadCounterSig,
+    SwapSelf,
+    DelegateNotAllowed,
+    AllowanceExceeded,
+    NoOutputs,
+    UnknownTx,
+    // a kind the call doesn't take on any layer, e.g. rollup updates without a receipt validator
+    UnsupportedTx,
+    WrongLayer(TxKind),
+    SupplyMismatch,
+    // a pre-state sent by ids that doesn't match the receiver's book
+    StaleBook,
+    Commitments,
+    Serialize,
+    Deserialize,
+    // Transaction::decode of another encoding version
+    EncodingVersion,
+}
+
+impl EngineError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EngineError::BadSig | EngineError::BadSigAt(_) => "sig",
+            EngineError::WrongChain => "chain",
+            EngineError::Expired => "expired",
+            EngineError::BadSqn => "sqn",
+            EngineError::SqnOverflow => "sqn_overflow",
+            EngineError::SenderUnqualified => "sender",
+            EngineError::UnknownAccount => "account",
+            EngineError::InsufficientBalance => "balance",
+            EngineError::Overflow => "overflow",
+            EngineError::MissingAccount => "missing",
+            EngineError::SameAccount => "same",
+            EngineError::NotRollupAccount => "not rollup account",
+            EngineError::SenderNotRollup => "account_rollup",
+            EngineError::RollupExists => "exist",
+            EngineError::AccountExists => "account_exist",
+            EngineError::AccountNotEmpty => "not_empty",
+            EngineError::RollupSelf => "rollup_self",
+            EngineError::GenesisInbox => "genesis_inbox",
+            EngineError::BelowMinimum => "min_deposit",
+            EngineError::BondTooLow => "bond",
+            EngineError::ZeroAmount => "zero",
+            EngineError::WithdrawalsExceedBalance => "withdraw",
+            EngineError::WithdrawOverflow => "withdraw_overflow",
+            EngineError::NoReceipts => "receipts",
+            EngineError::InvalidReceipt => "receipt",
+            EngineError::BadParent => "parent",
+            EngineError::InboxMismatch => "inbox",
+            EngineError::InboxCount => "inbox_count",
+            EngineError::PendingDeposits => "pending",
+            EngineError::InboxFull => "inbox_full",
+            EngineError::StaleTimestamp => "timestamp",
+            EngineError::ConditionUnmet => "condition",
+            EngineError::TotalMismatch => "total_mismatch",
+            EngineError::Counterparty => "counterparty",
+            EngineError::BadCounterSig => "counter_sig",
+            EngineError::SwapSelf => "swap_self",
+            EngineError::DelegateNotAllowed => "delegate",
+            EngineError::AllowanceExceeded => "allowance",
+            EngineError::NoOutputs => "outputs",
+            EngineError::UnknownTx => "unknown tx",
+            EngineError::UnsupportedTx => "tx type",
+            EngineError::WrongLayer(kind) => kind.wrong_layer(),
+            EngineError::SupplyMismatch => "supply",
+            EngineError::StaleBook => "stale",
+            EngineError::Commitments => "commitments",
+            EngineError::Serialize => "serialize",
+            EngineError::Deserialize => "deserialize",
+            EngineError::EncodingVersion => "version",
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl PartialEq<&str> for EngineError {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
 
 pub fn pk_to_hash(pk: &VerifyingKey) -> Hash {
     let mut hasher = Sha256::new();
@@ -44,66 +193,292 @@ pub fn pk_to_hash(pk: &VerifyingKey) -> Hash {
     x
 }
 
+// The hash behind account leaves, tx ids, txns hashes and the withdrawals tree: an
+// AccountBook<H> hashes its leaves with H, the others have *_with variants. Any Digest
+// with a 32 byte output will do, e.g. a cheaper one for the zkVM; the plain variants use
+// DefaultHasher. Signatures are always over the DefaultHasher preimage, wallets don't
+// follow the choice, and the account trie hashes its inner nodes inside
+// partial_binary_merkle, only its leaves follow it.
+pub trait MerkleHasher: Digest {
+    fn finish(self) -> Hash;
+}
+
+impl<D: Digest> MerkleHasher for D {
+    fn finish(self) -> Hash {
+        self.finalize().as_slice().try_into().expect("hash")
+    }
+}
+
+fn hash_valid_until<H: MerkleHasher>(valid_until: &Option<u32>, hasher: &mut H) {
+    match valid_until {
+        None => hasher.update([0u8]),
+        Some(h) => {
+            hasher.update([1u8]);
+            hasher.update(h.to_be_bytes());
+        }
+    }
+}
+
 pub trait TxPayload {
-    fn hash(&self, hasher: &mut DefaultHasher);
+    // signed ahead of the payload, so payload types with the same fields
+    // (e.g. Payment and L1ToL2Deposit) can't stand in for each other
+    const DOMAIN: u8;
+    // the sender pays the fee on top of spend(), so a delegate's allowance covers both
+    const PAYS_FEE: bool = false;
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H);
     fn sender_qualify(&self, account: &Account) -> bool;
+    // authorization needed beyond the sender's signature, e.g. a counterparty's
+    // of a tx from sender at sqn, for chain_id and valid_until
+    fn cosigner_check<H: MerkleHasher>(&self, _sender: &VerifyingKey, _sqn: u32, _chain_id: u32, _valid_until: Option<u32>,
+                                       _book: &AccountBook<H>) -> ResultT<()> {
+        Ok(())
+    }
+    // what a delegate's allowance is charged for the payload, None if only the owner may sign it
+    fn spend(&self) -> Option<u128> {
+        None
+    }
+}
+
+// How a Tx is signed. Txns are hashed the same way whatever the scheme, the scheme
+// signs and verifies the 32 byte hash. Accounts, Transaction and EngineData are over
+// secp256k1 keys, so the engines only take Tx<T, Secp256k1>, the default. A Tx of another
+// scheme can be built, signed, encoded and verified, but no engine processes it.
+pub trait SignatureScheme: Clone + Debug {
+    type SigningKey;
+    type VerifyingKey: Serialize + DeserializeOwned + Clone + Debug + PartialEq;
+    type Signature: Serialize + DeserializeOwned + Clone + Debug;
+    type Bytes: AsRef<[u8]>;
+    fn verifying_key(sk: &Self::SigningKey) -> Self::VerifyingKey;
+    fn sign(sk: &Self::SigningKey, x: &Hash) -> Self::Signature;
+    fn verify(pk: &Self::VerifyingKey, x: &Hash, sig: &Self::Signature) -> bool;
+    // what a tx hash covers of the key, and the id of the signature
+    fn pubkey_bytes(pk: &Self::VerifyingKey) -> Self::Bytes;
+    fn sig_bytes(sig: &Self::Signature) -> Vec<u8>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Secp256k1;
+
+impl SignatureScheme for Secp256k1 {
+    type SigningKey = SigningKey;
+    type VerifyingKey = VerifyingKey;
+    type Signature = Signature;
+    type Bytes = k256::EncodedPoint;
+
+    fn verifying_key(sk: &SigningKey) -> VerifyingKey {
+        *sk.verifying_key()
+    }
+
+    // RFC 6979: the nonce is derived from the key and the message, so signing the same
+    // tx twice gives the same signature, and the same id since id covers it
+    fn sign(sk: &SigningKey, x: &Hash) -> Signature {
+        sk.sign(x)
+    }
+
+    fn verify(pk: &VerifyingKey, x: &Hash, sig: &Signature) -> bool {
+        pk.verify(x, sig).is_ok()
+    }
+
+    fn pubkey_bytes(pk: &VerifyingKey) -> k256::EncodedPoint {
+        pk.to_encoded_point(false)
+    }
+
+    fn sig_bytes(sig: &Signature) -> Vec<u8> {
+        sig.to_bytes().to_vec()
+    }
+}
+
+// 32 byte keys and cheaper verification, deterministic like RFC 6979. Signing only, for
+// e.g. a gateway that checks Ed25519 signed requests before submitting them as secp256k1
+// txns: there are no Ed25519 accounts, see SignatureScheme
+#[cfg(feature = "ed25519")]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ed25519;
+
+#[cfg(feature = "ed25519")]
+impl SignatureScheme for Ed25519 {
+    type SigningKey = ed25519_dalek::SigningKey;
+    type VerifyingKey = ed25519_dalek::VerifyingKey;
+    type Signature = ed25519_dalek::Signature;
+    type Bytes = [u8; 32];
+
+    fn verifying_key(sk: &Self::SigningKey) -> Self::VerifyingKey {
+        sk.verifying_key()
+    }
+
+    fn sign(sk: &Self::SigningKey, x: &Hash) -> Self::Signature {
+        ed25519_dalek::Signer::sign(sk, x)
+    }
+
+    // strict, so a signature has one encoding and a tx one id
+    fn verify(pk: &Self::VerifyingKey, x: &Hash, sig: &Self::Signature) -> bool {
+        pk.verify_strict(x, sig).is_ok()
+    }
+
+    fn pubkey_bytes(pk: &Self::VerifyingKey) -> [u8; 32] {
+        pk.to_bytes()
+    }
+
+    fn sig_bytes(sig: &Self::Signature) -> Vec<u8> {
+        sig.to_bytes().to_vec()
+    }
+}
+
+// Only hashed when set, so txns signed by the sender keep their ids. The tag can't be
+// mistaken for the DOMAIN byte that follows otherwise.
+fn hash_delegate<S: SignatureScheme, H: MerkleHasher>(delegate: &Option<S::VerifyingKey>, hasher: &mut H) {
+    if let Some(d) = delegate {
+        hasher.update(b"delegate");
+        hasher.update(S::pubkey_bytes(d));
+    }
+}
+
+// Always hashed, 0 included, so no chain id signs the same bytes as another
+fn hash_chain_id<H: MerkleHasher>(chain_id: u32, hasher: &mut H) {
+    hasher.update(b"chain");
+    hasher.update(chain_id.to_be_bytes());
+}
+
+// what the sender, or the delegate, signs
+fn signing_hash<T: TxPayload, S: SignatureScheme, H: MerkleHasher>(sender: &S::VerifyingKey,
+                                                                   sqn: u32,
+                                                                   chain_id: u32,
+                                                                   valid_until: &Option<u32>,
+                                                                   delegate: &Option<S::VerifyingKey>,
+                                                                   payload: &T,
+) -> H {
+    let mut hasher = H::new();
+    hasher.update(S::pubkey_bytes(sender));
+    hasher.update(sqn.to_be_bytes());
+    hash_valid_until(valid_until, &mut hasher);
+    hash_chain_id(chain_id, &mut hasher);
+    hash_delegate::<S, H>(delegate, &mut hasher);
+    hasher.update([T::DOMAIN]);
+    payload.hash(&mut hasher);
+    hasher
 }
 
 #[repr(align(4))]
 #[derive(Serialize, Deserialize, Clone)]
-pub struct Tx<T>
-    where T: TxPayload
+#[serde(bound = "T: Serialize + DeserializeOwned")]
+pub struct Tx<T, S = Secp256k1>
+    where T: TxPayload, S: SignatureScheme
 {
-    pub sender: VerifyingKey,
+    pub sender: S::VerifyingKey,
     pub sqn: u32,
+    // ChainParams::chain_id of the chain the tx is for, so it can't be replayed on another
+    pub chain_id: u32,
+    // last block sqn the tx can be included in
+    pub valid_until: Option<u32>,
+    // signer of the tx when it is not the sender, charged against the sender's delegates
+    pub delegate: Option<S::VerifyingKey>,
     pub payload: T,
-    sig: Signature,
+    sig: S::Signature,
+    // see id_cached
+    #[serde(skip)]
+    id: OnceLock<Hash>,
 }
 
-impl<T: Debug + TxPayload> fmt::Debug for Tx<T> {
+impl<T: Debug + TxPayload, S: SignatureScheme> fmt::Debug for Tx<T, S> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Tx")
             .field("sender", &self.sender)
             .field("sqn", &self.sqn)
+            .field("chain_id", &self.chain_id)
+            .field("valid_until", &self.valid_until)
+            .field("delegate", &self.delegate)
             .field("payload", &self.payload)
             .finish()
     }
 }
 
+// The constructors take the default scheme, so Tx::new needs no annotation
 impl<T> Tx<T>
     where T: TxPayload
 {
+    // for chain 0, a bare AccountBook's. The engines' books are on Layer::default_chain_id
     pub fn new(sender: VerifyingKey,
                sqn: u32,
                payload: T,
                signing_key: &mut SigningKey,
     ) -> Tx<T> {
-        let mut hasher = DefaultHasher::new();
-        hasher.update(sender.to_encoded_point(false));
-        hasher.update(sqn.to_be_bytes());
-        payload.hash(&mut hasher);
+        Self::new_valid_until(sender, sqn, None, payload, signing_key)
+    }
+
+    pub fn new_valid_until(sender: VerifyingKey,
+                           sqn: u32,
+                           valid_until: Option<u32>,
+                           payload: T,
+                           signing_key: &mut SigningKey,
+    ) -> Tx<T> {
+        Self::new_signed(sender, sqn, 0, valid_until, None, payload, signing_key)
+    }
+
+    // for a chain other than 0, e.g. L1_CHAIN_ID, see ChainParams::chain_id
+    pub fn new_on_chain(chain_id: u32,
+                        sender: VerifyingKey,
+                        sqn: u32,
+                        payload: T,
+                        signing_key: &mut SigningKey,
+    ) -> Tx<T> {
+        Self::new_signed(sender, sqn, chain_id, None, None, payload, signing_key)
+    }
+
+    // signed by a delegate of the sender, see Account::delegates
+    pub fn new_delegated(chain_id: u32,
+                         sender: VerifyingKey,
+                         sqn: u32,
+                         payload: T,
+                         delegate_key: &mut SigningKey,
+    ) -> Tx<T> {
+        let delegate = Some(*delegate_key.verifying_key());
+        Self::new_signed(sender, sqn, chain_id, None, delegate, payload, delegate_key)
+    }
+}
+
+impl<T, S> Tx<T, S>
+    where T: TxPayload, S: SignatureScheme
+{
+    // any scheme; delegate is the key of signing_key when it is not the sender's
+    pub fn new_signed(sender: S::VerifyingKey,
+                      sqn: u32,
+                      chain_id: u32,
+                      valid_until: Option<u32>,
+                      delegate: Option<S::VerifyingKey>,
+                      payload: T,
+                      signing_key: &mut S::SigningKey,
+    ) -> Tx<T, S> {
+        let hasher = signing_hash::<T, S, DefaultHasher>(&sender, sqn, chain_id, &valid_until, &delegate, &payload);
         let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
-        let sig: Signature = signing_key.sign(&x);
-        Tx { sender: sender, sqn: sqn, payload: payload, sig: sig }
+        let sig = S::sign(signing_key, &x);
+        Tx { sender: sender, sqn: sqn, chain_id: chain_id, valid_until: valid_until, delegate: delegate, payload: payload, sig: sig, id: OnceLock::new() }
+    }
+
+    pub fn expired(&self, height: u32) -> bool {
+        self.valid_until.is_some_and(|h| height > h)
     }
 
     pub fn id(&self) -> Hash {
-        let mut hasher = DefaultHasher::new();
-        hasher.update(self.sender.to_encoded_point(false));
-        hasher.update(self.sqn.to_be_bytes());
-        self.payload.hash(&mut hasher);
-        hasher.update(self.sig.to_bytes());
-        let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
-        x
+        self.id_with::<DefaultHasher>()
+    }
+
+    pub fn id_with<H: MerkleHasher>(&self) -> Hash {
+        let mut hasher = signing_hash::<T, S, H>(&self.sender, self.sqn, self.chain_id, &self.valid_until, &self.delegate, &self.payload);
+        hasher.update(S::sig_bytes(&self.sig));
+        hasher.finish()
+    }
+
+    // id hashed once per tx, for the paths that need it several times per block. Not
+    // serialized, a decoded tx hashes again. The fields are public, a tx changed after
+    // this was called keeps the old id here, id() always hashes the current fields.
+    pub fn id_cached(&self) -> Hash {
+        *self.id.get_or_init(|| self.id())
     }
 
     pub fn sig_verify(&self) -> bool {
-        let mut hasher = DefaultHasher::new();
-        hasher.update(self.sender.to_encoded_point(false));
-        hasher.update(self.sqn.to_be_bytes());
-        self.payload.hash(&mut hasher);
+        let hasher = signing_hash::<T, S, DefaultHasher>(&self.sender, self.sqn, self.chain_id, &self.valid_until, &self.delegate, &self.payload);
         let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
-        self.sender.verify(&x, &self.sig).is_ok()
+        S::verify(self.delegate.as_ref().unwrap_or(&self.sender), &x, &self.sig)
     }
 }
 
@@ -111,18 +486,100 @@ impl<T> Tx<T>
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Payment {
     pub to: VerifyingKey,
-    pub amount: u128,
+    pub amount: Balance,
+    // amount plus fee as the sender worked it out, the tx is rejected if the chain
+    // charges anything else
+    pub expected_total: Option<u128>,
 }
 
 impl TxPayload for Payment {
-    fn hash(&self, hasher: &mut DefaultHasher) {
+    const DOMAIN: u8 = 1;
+    const PAYS_FEE: bool = true;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
+        hasher.update(self.to.to_encoded_point(false));
+        hasher.update(self.amount.to_be_bytes());
+        match self.expected_total {
+            None => hasher.update([0u8]),
+            Some(t) => {
+                hasher.update([1u8]);
+                hasher.update(t.to_be_bytes());
+            }
+        }
+    }
+
+    fn sender_qualify(&self, account: &Account) -> bool {
+        account.amount >= self.amount
+    }
+
+    fn spend(&self) -> Option<u128> {
+        Some(self.amount.0)
+    }
+}
+
+// one debit of the sender for many recipients, one signature to verify. A recipient
+// listed more than once gets the sum
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BatchPayment {
+    pub outputs: Vec<(VerifyingKey, u128)>,
+}
+
+impl BatchPayment {
+    // None if the sum overflows
+    pub fn total(&self) -> Option<u128> {
+        self.outputs.iter().try_fold(0u128, |total, (_, a)| total.checked_add(*a))
+    }
+}
+
+impl TxPayload for BatchPayment {
+    const DOMAIN: u8 = 11;
+    const PAYS_FEE: bool = true;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
+        hasher.update((self.outputs.len() as u64).to_be_bytes());
+        for (to, amount) in &self.outputs {
+            hasher.update(to.to_encoded_point(false));
+            hasher.update(amount.to_be_bytes());
+        }
+    }
+
+    fn sender_qualify(&self, account: &Account) -> bool {
+        self.total().is_some_and(|total| account.amount >= Balance(total))
+    }
+
+    fn spend(&self) -> Option<u128> {
+        self.total()
+    }
+}
+
+// a payment that only goes through while the recipient holds at most
+// max_recipient_balance, e.g. for topping an account up without over-funding it
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConditionalPayment {
+    pub to: VerifyingKey,
+    pub amount: Balance,
+    pub max_recipient_balance: u128,
+}
+
+impl TxPayload for ConditionalPayment {
+    const DOMAIN: u8 = 8;
+    const PAYS_FEE: bool = true;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
         hasher.update(self.to.to_encoded_point(false));
         hasher.update(self.amount.to_be_bytes());
+        hasher.update(self.max_recipient_balance.to_be_bytes());
     }
 
     fn sender_qualify(&self, account: &Account) -> bool {
         account.amount >= self.amount
     }
+
+    fn spend(&self) -> Option<u128> {
+        Some(self.amount.0)
+    }
 }
 
 #[repr(align(4))]
@@ -130,17 +587,32 @@ impl TxPayload for Payment {
 pub struct CreateRollupAccount {
     // must be a new account
     pub rollup_pk: VerifyingKey,
+    // moved from the creator into the rollup state, slashed on invalid updates
+    pub bond: u128,
+    // L1 committed messages (id, amount) the rollup starts with, e.g. when migrating
+    // a rollup. The creator funds the account with genesis_amount, their sum
+    pub genesis_inbox: Vec<(Hash, u128)>,
+    pub genesis_amount: u128,
     //pub genesis_state_hash: Hash,
 }
 
 impl TxPayload for CreateRollupAccount {
-    fn hash(&self, hasher: &mut DefaultHasher) {
+    const DOMAIN: u8 = 2;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
         hasher.update(self.rollup_pk.to_encoded_point(false));
+        hasher.update(self.bond.to_be_bytes());
+        hasher.update((self.genesis_inbox.len() as u64).to_be_bytes());
+        for (msg, amount) in &self.genesis_inbox {
+            hasher.update(msg);
+            hasher.update(amount.to_be_bytes());
+        }
+        hasher.update(self.genesis_amount.to_be_bytes());
         //hasher.update(self.genesis_state_hash);
     }
 
-    fn sender_qualify(&self, _account: &Account) -> bool {
-        true
+    fn sender_qualify(&self, account: &Account) -> bool {
+        self.bond.checked_add(self.genesis_amount).is_some_and(|total| account.amount >= Balance(total))
     }
 }
 
@@ -152,39 +624,187 @@ pub struct L1ToL2Deposit {
 }
 
 impl TxPayload for L1ToL2Deposit {
-    fn hash(&self, hasher: &mut DefaultHasher) {
+    const DOMAIN: u8 = 3;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
         hasher.update(self.rollup_pk.to_encoded_point(false));
         hasher.update(self.amount.to_be_bytes());
     }
 
     fn sender_qualify(&self, account: &Account) -> bool {
-        account.amount >= self.amount
+        account.amount >= Balance(self.amount)
+    }
+
+    fn spend(&self) -> Option<u128> {
+        Some(self.amount)
+    }
+}
+
+// payloads that leave a message in the rollup inbox on L1, crediting the sender on L2
+pub trait InboxMessage {
+    fn amount(&self) -> u128;
+}
+
+impl InboxMessage for L1ToL2Deposit {
+    fn amount(&self) -> u128 {
+        self.amount
     }
 }
 
+// CreateRollupAccount and L1ToL2Deposit in one txn, all or nothing: the rollup account
+// is created holding the amount, with the txn as its single inbox message. A rollup that
+// should exist before anything is deposited to it takes the two txns in one block, see
+// l1_engine::canonical_rank
+#[repr(align(4))]
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct L2ToL1Withdrawal {
+pub struct CreateAndFundRollup {
+    // must be a new account
+    pub rollup_pk: VerifyingKey,
     pub amount: u128,
+    // as CreateRollupAccount::bond, not part of the deposit
+    pub bond: u128,
+}
+
+impl TxPayload for CreateAndFundRollup {
+    const DOMAIN: u8 = 4;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
+        hasher.update(self.rollup_pk.to_encoded_point(false));
+        hasher.update(self.amount.to_be_bytes());
+        hasher.update(self.bond.to_be_bytes());
+    }
+
+    fn sender_qualify(&self, account: &Account) -> bool {
+        self.amount.checked_add(self.bond).is_some_and(|total| account.amount >= Balance(total))
+    }
+}
+
+impl InboxMessage for CreateAndFundRollup {
+    fn amount(&self) -> u128 {
+        self.amount
+    }
+}
+
+// sender gives `give` to the counterparty and gets `want` back, both or neither, in the
+// native asset, the only one balances hold. The counterparty signs the terms together
+// with the sender's key and sqn and the tx's chain and expiry, so its signature can't be
+// replayed in another tx, on another chain or after the sender's tx expired.
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Swap {
+    pub counterparty: VerifyingKey,
+    pub give: u128,
+    pub want: u128,
+    pub counter_sig: Signature,
+}
+
+impl Swap {
+    // for the tx sender will sign with sqn, chain_id and valid_until
+    pub fn new(sender: &VerifyingKey,
+               sqn: u32,
+               chain_id: u32,
+               valid_until: Option<u32>,
+               give: u128,
+               want: u128,
+               counterparty_key: &mut SigningKey,
+    ) -> Swap {
+        let counterparty = counterparty_key.verifying_key().clone();
+        let x = Self::terms_hash(sender, sqn, chain_id, valid_until, &counterparty, give, want);
+        let counter_sig: Signature = counterparty_key.sign(&x);
+        Swap { counterparty, give, want, counter_sig }
+    }
+
+    pub fn terms_hash(sender: &VerifyingKey,
+                      sqn: u32,
+                      chain_id: u32,
+                      valid_until: Option<u32>,
+                      counterparty: &VerifyingKey,
+                      give: u128,
+                      want: u128,
+    ) -> Hash {
+        let mut hasher = DefaultHasher::new();
+        hasher.update(sender.to_encoded_point(false));
+        hasher.update(sqn.to_be_bytes());
+        hasher.update(chain_id.to_be_bytes());
+        hash_valid_until(&valid_until, &mut hasher);
+        hasher.update(counterparty.to_encoded_point(false));
+        hasher.update(give.to_be_bytes());
+        hasher.update(want.to_be_bytes());
+        let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
+        x
+    }
+}
+
+impl TxPayload for Swap {
+    const DOMAIN: u8 = 5;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
+        hasher.update(self.counterparty.to_encoded_point(false));
+        hasher.update(self.give.to_be_bytes());
+        hasher.update(self.want.to_be_bytes());
+        hasher.update(self.counter_sig.to_bytes());
+    }
+
+    fn sender_qualify(&self, account: &Account) -> bool {
+        account.amount >= Balance(self.give)
+    }
+
+    fn cosigner_check<H: MerkleHasher>(&self, sender: &VerifyingKey, sqn: u32, chain_id: u32, valid_until: Option<u32>,
+                                       book: &AccountBook<H>) -> ResultT<()> {
+        let x = Self::terms_hash(sender, sqn, chain_id, valid_until, &self.counterparty, self.give, self.want);
+        if self.counterparty.verify(&x, &self.counter_sig).is_err() {
+            return Err(EngineError::BadCounterSig);
+        }
+        match book.accounts.get(&pk_to_hash(&self.counterparty)) {
+            None => Err(EngineError::Counterparty),
+            Some(a) if a.amount < Balance(self.want) => Err(EngineError::Counterparty),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct L2ToL1Withdrawal {
+    pub amount: Balance,
+    // L1 account credited with the amount, the sender's own if None
+    pub to: Option<VerifyingKey>,
 }
 
 impl TxPayload for L2ToL1Withdrawal {
-    fn hash(&self, hasher: &mut DefaultHasher) {
+    const DOMAIN: u8 = 6;
+    const PAYS_FEE: bool = true;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
         hasher.update(self.amount.to_be_bytes());
+        match &self.to {
+            None => hasher.update([0u8]),
+            Some(to) => {
+                hasher.update([1u8]);
+                hasher.update(to.to_encoded_point(false));
+            }
+        }
     }
     fn sender_qualify(&self, account: &Account) -> bool {
         account.amount >= self.amount
     }
+    fn spend(&self) -> Option<u128> {
+        Some(self.amount.0)
+    }
 }
 
 #[repr(align(4))]
 #[derive(Serialize, Deserialize, Debug, Clone)]
-// cross chain message, not signed since there is no dedicated relyer
+// cross chain message, signed by the rollup account itself like any other tx. There is
+// no relayer path: the signature and the rollup's sqn decide who takes an update slot,
+// and an authenticated sender is what makes slashing invalid updates meaningful
 pub struct RollupStateUpdate {
     pub proof_receipt: Vec<u8>,
 }
 
 impl TxPayload for RollupStateUpdate {
-    fn hash(&self, hasher: &mut DefaultHasher) {
+    const DOMAIN: u8 = 7;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
         //let data: Vec<u8> = bincode::serialize(&self.proof_receipt).unwrap();
         hasher.update(&self.proof_receipt);
     }
@@ -193,259 +813,1172 @@ impl TxPayload for RollupStateUpdate {
     }
 }
 
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+// settles several consecutive L2 blocks in one L1 txn, one receipt per block in chain
+// order. Same signer and checks as RollupStateUpdate, applied as a single update
+pub struct RollupBatchUpdate {
+    pub proof_receipts: Vec<Vec<u8>>,
+}
+
+impl TxPayload for RollupBatchUpdate {
+    const DOMAIN: u8 = 9;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
+        for r in &self.proof_receipts {
+            hasher.update((r.len() as u32).to_be_bytes());
+            hasher.update(r);
+        }
+    }
+    fn sender_qualify(&self, _account: &Account) -> bool {
+        true
+    }
+}
+
+// lets the delegate sign txns for the sender's account, spending up to allowance in
+// total. Replaces the delegate's previous allowance, 0 revokes it
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetDelegate {
+    pub delegate: VerifyingKey,
+    pub allowance: u128,
+}
+
+impl TxPayload for SetDelegate {
+    const DOMAIN: u8 = 10;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
+        hasher.update(self.delegate.to_encoded_point(false));
+        hasher.update(self.allowance.to_be_bytes());
+    }
+    fn sender_qualify(&self, _account: &Account) -> bool {
+        true
+    }
+}
+
+// registers an empty account for the key, e.g. to reserve its id before anything is
+// paid to it. Payments still create the accounts they pay to
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreateAccount {
+    pub pubkey: VerifyingKey,
+}
+
+impl TxPayload for CreateAccount {
+    const DOMAIN: u8 = 12;
+
+    fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
+        hasher.update(self.pubkey.to_encoded_point(false));
+    }
+    fn sender_qualify(&self, _account: &Account) -> bool {
+        true
+    }
+}
+
+// removes the sender's account, which must be empty: no balance and no rollup. Its leaf
+// becomes a tombstone, see Account::closed_leaf. Only the owner may sign it
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CloseAccount {}
+
+impl TxPayload for CloseAccount {
+    const DOMAIN: u8 = 13;
+
+    fn hash<H: MerkleHasher>(&self, _hasher: &mut H) {}
+    fn sender_qualify(&self, _account: &Account) -> bool {
+        true
+    }
+}
+
+// most deposits a rollup inbox holds until an update consumes them. The guest hashes
+// the consumed messages of every update, this bounds that work
+pub const MAX_INBOX: usize = 1024;
+
 #[repr(align(4))]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RollupState {
-    pub inbox: VecDeque<Hash>,
+    // (message id, amount) of deposits not consumed by an update yet
+    pub inbox: VecDeque<(Hash, u128)>,
     pub header_hash: Hash,
     pub sqn: u32,
+    pub bond: u128,
+    // sum of the inbox amounts, the part of the balance still owed to L2
+    pub pending_deposits: u128,
+    // of the last settled header, the next one must be later
+    pub last_timestamp: u64,
 }
 
 impl RollupState {
-    fn hash(&self, hasher: &mut DefaultHasher) {
-        for msg in &self.inbox {
+    // every field is tagged and the inbox is length prefixed, so the encoding stays
+    // unambiguous when fields of variable length are added
+    pub fn hash<H: MerkleHasher>(&self, hasher: &mut H) {
+        hasher.update(b"rollup.inbox");
+        hasher.update((self.inbox.len() as u64).to_be_bytes());
+        for (msg, amount) in &self.inbox {
             hasher.update(msg);
+            hasher.update(amount.to_be_bytes());
         }
+        hasher.update(b"rollup.header_hash");
         hasher.update(self.header_hash);
+        hasher.update(b"rollup.sqn");
         hasher.update(self.sqn.to_be_bytes());
+        hasher.update(b"rollup.bond");
+        hasher.update(self.bond.to_be_bytes());
+        hasher.update(b"rollup.pending_deposits");
+        hasher.update(self.pending_deposits.to_be_bytes());
+        hasher.update(b"rollup.last_timestamp");
+        hasher.update(self.last_timestamp.to_be_bytes());
+    }
+}
+
+
+// decimal places shown by Display, balances are integers of the smallest unit
+pub const BALANCE_DECIMALS: u32 = 6;
+
+// A token amount. It has no +/-, every arithmetic site picks checked_add or checked_sub
+// and says what a failure means. Serialized as the bare u128, so the wire format is
+// the same as before.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct Balance(pub u128);
+
+impl Balance {
+    pub const ZERO: Balance = Balance(0);
+
+    pub fn checked_add(self, rhs: Balance) -> Option<Balance> {
+        self.0.checked_add(rhs.0).map(Balance)
+    }
+
+    pub fn checked_sub(self, rhs: Balance) -> Option<Balance> {
+        self.0.checked_sub(rhs.0).map(Balance)
+    }
+
+    pub fn to_be_bytes(self) -> [u8; 16] {
+        self.0.to_be_bytes()
+    }
+}
+
+impl From<u128> for Balance {
+    fn from(x: u128) -> Balance {
+        Balance(x)
+    }
+}
+
+impl PartialEq<u128> for Balance {
+    fn eq(&self, other: &u128) -> bool {
+        self.0 == *other
     }
 }
 
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = 10u128.pow(BALANCE_DECIMALS);
+        write!(f, "{}.{:0width$}", self.0 / unit, self.0 % unit, width = BALANCE_DECIMALS as usize)
+    }
+}
 
 #[repr(align(4))]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Account {
     pub owner: VerifyingKey,
-    pub amount: u128,
+    pub amount: Balance,
     pub sqn_expect: u32,
     pub rollup: Option<RollupState>,
+    // height interest was last accrued at, or the account created at
+    pub last_accrued: u32,
+    // delegate id -> what it may still spend from the account, see SetDelegate
+    pub delegates: BTreeMap<AccountID, u128>,
+}
+
+impl Account {
+    pub fn new(owner: VerifyingKey,
+               amount: Balance,
+               rollup: Option<RollupState>,
+    ) -> Account
+    {
+        Account { owner, amount, sqn_expect: 0, rollup: rollup, last_accrued: 0, delegates: BTreeMap::new() }
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash_with::<DefaultHasher>()
+    }
+
+    pub fn hash_with<H: MerkleHasher>(&self) -> Hash {
+        let mut hasher = H::new();
+        hasher.update(self.owner.to_encoded_point(false));
+        hasher.update(self.amount.to_be_bytes());
+        hasher.update(self.sqn_expect.to_be_bytes());
+        hasher.update(self.last_accrued.to_be_bytes());
+        hasher.update((self.delegates.len() as u64).to_be_bytes());
+        for (id, allowance) in &self.delegates {
+            hasher.update(id);
+            hasher.update(allowance.to_be_bytes());
+        }
+        match &self.rollup {
+            None => {}
+            Some(ru) => ru.hash(&mut hasher),
+        }
+        hasher.finish()
+    }
+
+    pub fn id(&self) -> Hash {
+        pk_to_hash(&self.owner)
+    }
+
+    // The leaf of a closed account. It keeps the sqn the account got to, an account
+    // opened again at the id starts from it so the closed one's txns can't be replayed.
+    // An account leaf starts with the owner's encoded point, never with "closed"
+    pub fn closed_leaf(id: &AccountID, sqn: u32) -> Hash {
+        Self::closed_leaf_with::<DefaultHasher>(id, sqn)
+    }
+
+    pub fn closed_leaf_with<H: MerkleHasher>(id: &AccountID, sqn: u32) -> Hash {
+        let mut hasher = H::new();
+        hasher.update(b"closed");
+        hasher.update(id);
+        hasher.update(sqn.to_be_bytes());
+        hasher.finish()
+    }
+
+    // The leaves the process_* calls report. With the parallel feature update_tree
+    // rehashes the changed accounts itself, on all cores, so these skip the serial
+    // hashing and report UNHASHED
+    fn leaf<H: MerkleHasher>(&self) -> Hash {
+        if cfg!(feature = "parallel") { UNHASHED } else { self.hash_with::<H>() }
+    }
+
+    fn closed_leaf_of<H: MerkleHasher>(id: &AccountID, sqn: u32) -> Hash {
+        if cfg!(feature = "parallel") { UNHASHED } else { Self::closed_leaf_with::<H>(id, sqn) }
+    }
+
+    // next sqn once tx is applied, a delegate's allowance is charged for it here, the
+    // spend and the fee paid on it. sender_check made sure the sqn has a next one and the
+    // allowance covers both
+    fn advance<T: TxPayload>(&mut self, tx: &Tx<T>, fee: Balance) {
+        self.sqn_expect += 1;
+        if let Some(delegate) = &tx.delegate {
+            let id = pk_to_hash(delegate);
+            let left = self.delegates[&id] - tx.payload.spend().expect("sender_check") - fee.0;
+            match left {
+                0 => self.delegates.remove(&id),
+                _ => self.delegates.insert(id, left),
+            };
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum FeePolicy {
+    Flat(u128),
+    // fee = amount * bps / 10_000, rounded up so no nonzero transfer goes free
+    BasisPoints(u16),
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        FeePolicy::Flat(0)
+    }
+}
+
+impl FeePolicy {
+    pub fn fee(&self, amount: u128) -> ResultT<u128> {
+        match self {
+            FeePolicy::Flat(f) => Ok(*f),
+            FeePolicy::BasisPoints(bps) => {
+                let x = amount.checked_mul(*bps as u128).ok_or(EngineError::Overflow)?;
+                Ok(x.div_ceil(10_000))
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Layer {
+    L1,
+    L2,
+}
+
+// the chain ids EngineData::new gives the layers, so a tx signed for one is rejected by
+// the other. A bare AccountBook keeps ChainParams' 0
+pub const L1_CHAIN_ID: u32 = 1;
+pub const L2_CHAIN_ID: u32 = 2;
+
+impl Layer {
+    pub fn default_chain_id(&self) -> u32 {
+        match self {
+            Layer::L1 => L1_CHAIN_ID,
+            Layer::L2 => L2_CHAIN_ID,
+        }
+    }
+}
+
+impl ChainParams {
+    // the defaults, on the layer's own chain
+    pub fn for_layer(layer: Layer) -> ChainParams {
+        ChainParams { chain_id: layer.default_chain_id(), ..Default::default() }
+    }
+
+    // The rules the guest proves L2 blocks under. The program checks its input against
+    // them, so they are part of its image id: a prover can't pick another chain id, fee
+    // or interest rate and still produce a proof L1 accepts.
+    pub fn rollup() -> ChainParams {
+        ChainParams::for_layer(Layer::L2)
+    }
+}
+
+// chain wide settings the processing rules depend on
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ChainParams {
+    // share of a rollup's bond burned per invalid state update, in basis points. Above
+    // 10_000 it burns the whole bond
+    pub rollup_slash_bps: u16,
+    // the bond a rollup must be created with, by either create txn
+    pub min_rollup_bond: u128,
+    // charged on payments and withdrawals on top of the amount
+    pub fee_policy: FeePolicy,
+    // credited with the fees, fees are burned if None
+    pub fee_collector: Option<VerifyingKey>,
+    // senders that pay no fee, e.g. the collector itself. Ordered so the params encode the same everywhere
+    pub fee_exempt: BTreeSet<AccountID>,
+    pub l2_txns_scope: L2TxnsScope,
+    // signed into every tx, see Tx::chain_id. L1 and L2 must have different ones, both
+    // accept payments and swaps; EngineData::new sets Layer::default_chain_id
+    pub chain_id: u32,
+    // interest per block in parts per INTEREST_DENOMINATOR, 0 turns accrual off
+    pub interest_ppb: u32,
+    // smallest L1 deposit, so the inbox can't be filled with dust that still costs the
+    // rollup proof cycles to consume
+    pub min_deposit: u128,
+    // zero amount payments, deposits and withdrawals only bump the sender's sqn, and a
+    // zero payment still creates the recipient's account. Rejected unless this is set
+    pub allow_zero_amount: bool,
+}
+
+pub const INTEREST_DENOMINATOR: u128 = 1_000_000_000;
+
+// Which txns of an L2 block BlockHeaderL2::txns_hash covers. L2Only keeps the ones with
+// L2 semantics, transfers and the deposit/withdrawal legs of the bridge, which is what
+// the L1 validator cares about per the design doc.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum L2TxnsScope {
+    #[default]
+    All,
+    L2Only,
+}
+
+impl L2TxnsScope {
+    pub fn includes(&self, t: &Transaction) -> bool {
+        match self {
+            L2TxnsScope::All => true,
+            L2TxnsScope::L2Only => matches!(t,
+                Transaction::Pay(_) | Transaction::ConditionalPay(_) | Transaction::BatchPay(_) | Transaction::Swap(_) | Transaction::DepositL2(_)
+                | Transaction::RollupCreateFundL2(_) | Transaction::Withdrawal(_)),
+        }
+    }
+
+    // the scope goes in ahead of the txns, so the same hash can't be read under the other scope
+    pub fn txns_hash(&self, txns: &Vec<Transaction>) -> Hash {
+        let mut hasher = DefaultHasher::new();
+        hasher.update([*self as u8]);
+        hasher.update(tx_set_hash_of::<DefaultHasher>(txns.iter().filter(|t| self.includes(t)), Transaction::id));
+        let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
+        x
+    }
+}
+
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccountBook<H = DefaultHasher> {
+    pub(crate) proof_tree: PartialMerkleTrie,
+    pub(crate) accounts: BTreeMap<AccountID, Account>,
+    params: ChainParams,
+    // height of the block being processed, set by accrue_for
+    height: u32,
+    // total interest minted into accounts, for supply accounting
+    pub interest_minted: u128,
+    // host only, the guest never sees history
+    #[serde(skip)]
+    checkpoints: Checkpoints,
+    // set while an engine runs a block whose sigs verify_all_sigs checked already
+    #[serde(skip)]
+    pub(crate) sigs_verified: bool,
+    // ids update_tree was given since the book was built or decoded, what prune keeps
+    #[serde(skip)]
+    changed: BTreeSet<AccountID>,
+    // ids CloseAccount removed, with the sqn of their tombstone, see Account::closed_leaf
+    pub(crate) closed: BTreeMap<AccountID, u32>,
+    // the leaves' hash, see MerkleHasher
+    #[serde(skip)]
+    hasher: PhantomData<fn() -> H>,
+}
+
+// What processing a block changes in a book, to roll back a block applied speculatively.
+// Params and checkpoints only change between blocks
+#[derive(Debug, Clone)]
+pub struct AccountBookSnapshot {
+    proof_tree: PartialMerkleTrie,
+    accounts: BTreeMap<AccountID, Account>,
+    closed: BTreeMap<AccountID, u32>,
+    height: u32,
+    interest_minted: u128,
+}
+
+impl AccountBookSnapshot {
+    pub fn root(&self) -> &Hash {
+        &self.proof_tree.root
+    }
+}
+
+// The accounts one txn may touch as they were before it, for a lenient engine to put
+// back when it drops the txn, the interest accrued for it included. The tree is only
+// updated at the end of a block, so it is not part of it
+#[derive(Debug, Clone)]
+pub struct TxJournal {
+    accounts: Vec<(AccountID, Option<Account>, Option<u32>)>,
+    interest_minted: u128,
+}
+
+// What a deployment publishes about its genesis book, so a joining node can check the
+// one it built before processing any block
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GenesisManifest {
+    pub root: Hash,
+    pub account_count: u64,
+    pub total_supply: u128,
+    // over the encoded ChainParams, the rules matter as much as the balances
+    pub params_hash: Hash,
+}
+
+impl ChainParams {
+    pub fn hash(&self) -> Hash {
+        let mut hasher = DefaultHasher::new();
+        hasher.update(bincode_config::serialize(self).expect("params"));
+        let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
+        x
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PartialRootError {
+    // no accounts, but the root isn't the empty tree's
+    NotEmpty,
+    MissingLeaf(AccountID),
+    // the account doesn't hash to its leaf
+    LeafMismatch(AccountID),
+    // the path from the account's leaf doesn't hash up to the root, a node on it was
+    // changed, e.g. a sibling hash
+    BadPath { id: AccountID, root: Hash },
+    // the trie's nodes don't hash up to the claimed root, but every account's path does,
+    // e.g. a node on the path of an id the block creates
+    Structure { root: Hash },
+}
+
+// roots kept every `interval` blocks, keyed by the sqn of the block that produced them
+#[derive(Debug, Default)]
+struct Checkpoints {
+    interval: u32,
+    roots: BTreeMap<u32, Hash>,
+}
+
+// What AccountBook::apply needs beyond the txn, and what it leaves behind besides the
+// account changes. layer None takes the txns of both layers, e.g. for a dry run. Rollup
+// updates need valid_receipt and fail with UnsupportedTx without one.
+pub struct ApplyCtx<'a> {
+    pub layer: Option<Layer>,
+    pub height: u32,
+    pub valid_receipt: Option<&'a dyn Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>>,
+    // the applied L1 deposits, in order
+    pub deposits: Vec<Tx<L1ToL2Deposit>>,
+    // ids of the applied L2 txns that consume an inbox message, in order
+    pub inbox_msgs: Vec<Hash>,
+    pub w_records: Vec<WithdrawalRecord>,
+    // why the rollup update just applied was slashed, for the engines to take into its receipt
+    pub slashed: Option<EngineError>,
+}
+
+impl<'a> ApplyCtx<'a> {
+    pub fn new(layer: Option<Layer>, height: u32) -> Self {
+        ApplyCtx { layer, height, valid_receipt: None, deposits: Vec::new(), inbox_msgs: Vec::new(), w_records: Vec::new(),
+            slashed: None }
+    }
+
+    pub fn with_receipts(mut self, valid_receipt: &'a dyn Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>) -> Self {
+        self.valid_receipt = Some(valid_receipt);
+        self
+    }
 }
 
-impl Account {
-    pub fn new(owner: VerifyingKey,
-               amount: u128,
-               rollup: Option<RollupState>,
-    ) -> Account
-    {
-        Account { owner, amount, sqn_expect: 0, rollup: rollup }
+// A genesis book with accounts of different balances, rollups among them, where
+// new_batch gives every key the same amount. A key given twice keeps its last account.
+// Nothing is checked, e.g. a rollup's pending_deposits against its balance.
+#[derive(Default)]
+pub struct AccountBookBuilder {
+    accounts: BTreeMap<AccountID, Account>,
+    params: ChainParams,
+}
+
+impl AccountBookBuilder {
+    pub fn new() -> AccountBookBuilder {
+        AccountBookBuilder::default()
     }
 
-    pub fn hash(&self) -> Hash {
-        let mut hasher = DefaultHasher::new();
-        hasher.update(self.owner.to_encoded_point(false));
-        hasher.update(self.amount.to_be_bytes());
-        hasher.update(self.sqn_expect.to_be_bytes());
-        match &self.rollup {
-            None => {}
-            Some(ru) => ru.hash(&mut hasher),
-        }
-        let x: Hash = hasher.finalize().as_slice().try_into().expect("Hash");
-        x
+    pub fn with_account(mut self, pk: VerifyingKey, amount: u128, rollup: Option<RollupState>) -> Self {
+        let a = Account::new(pk, Balance(amount), rollup);
+        self.accounts.insert(a.id(), a);
+        self
     }
 
-    pub fn id(&self) -> Hash {
-        pk_to_hash(&self.owner)
+    pub fn with_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self
     }
-}
 
-#[repr(align(4))]
-#[derive(Serialize, Deserialize, Debug)]
-pub struct AccountBook {
-    proof_tree: PartialMerkleTrie,
-    accounts: BTreeMap<AccountID, Account>,
+    pub fn build(self) -> AccountBook {
+        self.build_with::<DefaultHasher>()
+    }
+
+    // a book whose leaves are hashed with H
+    pub fn build_with<H: MerkleHasher>(self) -> AccountBook<H> {
+        let mut tree = PartialMerkleTrie::new();
+        if !self.accounts.is_empty() {
+            tree.insert_or_replace_batch(self.accounts.iter().map(|(id, a)| (*id, a.hash_with::<H>())).collect());
+        }
+        AccountBook { proof_tree: tree, accounts: self.accounts, params: self.params, height: 0, interest_minted: 0,
+            checkpoints: Checkpoints::default(), sigs_verified: false, changed: BTreeSet::new(),
+            closed: BTreeMap::new(), hasher: PhantomData }
+    }
 }
 
 impl AccountBook {
     pub fn new(faucet_key: VerifyingKey, faucet_amout: u128) -> AccountBook {
         let mut tree = PartialMerkleTrie::new();
         let mut b = BTreeMap::new();
-        let a = Account::new(faucet_key, faucet_amout, None);
+        let a = Account::new(faucet_key, Balance(faucet_amout), None);
         let id = a.id();
         let a_hash = a.hash();
         b.insert(id, a);
         tree.insert_or_replace(id, a_hash);
-        AccountBook { proof_tree: tree, accounts: b }
+        AccountBook { proof_tree: tree, accounts: b, params: ChainParams::default(), height: 0, interest_minted: 0,
+            checkpoints: Checkpoints::default(), sigs_verified: false, changed: BTreeSet::new(),
+            closed: BTreeMap::new(), hasher: PhantomData }
     }
 
+    // The leaves go into the tree as one batch rather than a path update per key, and
+    // the map is bulk built from the collected accounts instead of grown node by node.
+    // Both staging vecs are sized from keys up front.
     pub fn new_batch(keys: Vec<VerifyingKey>, amout: u128) -> AccountBook {
-        let mut tree = PartialMerkleTrie::new();
-        let mut b = BTreeMap::new();
+        let mut leaves = Vec::with_capacity(keys.len());
+        let mut accounts = Vec::with_capacity(keys.len());
         keys.into_iter().for_each(|k| {
-            let a = Account::new(k, amout, None);
+            let a = Account::new(k, Balance(amout), None);
             let id = a.id();
-            let a_hash = a.hash();
-            b.insert(id, a);
-            tree.insert_or_replace(id, a_hash);
+            leaves.push((id, a.hash()));
+            accounts.push((id, a));
         });
-        AccountBook { proof_tree: tree, accounts: b }
+        let mut tree = PartialMerkleTrie::new();
+        if !leaves.is_empty() {
+            tree.insert_or_replace_batch(leaves);
+        }
+        AccountBook { proof_tree: tree, accounts: accounts.into_iter().collect(), params: ChainParams::default(), height: 0,
+            interest_minted: 0, checkpoints: Checkpoints::default(), sigs_verified: false, changed: BTreeSet::new(),
+            closed: BTreeMap::new(), hasher: PhantomData }
+    }
+}
+
+// Everything but the constructors, which are on DefaultHasher. A book on another hasher
+// comes from AccountBookBuilder::build_with
+impl<H: MerkleHasher> AccountBook<H> {
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // deposits waiting in a rollup's inbox, None if there is no such rollup
+    pub fn inbox_len(&self, rollup_id: &AccountID) -> Option<usize> {
+        self.accounts.get(rollup_id)?.rollup.as_ref().map(|r| r.inbox.len())
+    }
+
+    pub fn set_params(&mut self, params: ChainParams) {
+        self.params = params;
     }
 
     pub fn root(&self) -> &Hash {
         &self.proof_tree.root
     }
 
+    // The root of a fresh tree over all the accounts and tombstones, for checking the one
+    // update_tree maintains, e.g. that no block left a changed account out of its change
+    // set. Only a full book's matches root(), a partial one holds a subset of the accounts.
+    pub fn recompute_root(&self) -> Hash {
+        let mut tree = PartialMerkleTrie::new();
+        let mut leaves: Vec<(AccountID, Hash)> = self.accounts.iter().map(|(id, a)| (*id, a.hash_with::<H>())).collect();
+        leaves.extend(self.closed.iter().map(|(id, sqn)| (*id, Account::closed_leaf_with::<H>(id, *sqn))));
+        if !leaves.is_empty() {
+            tree.insert_or_replace_batch(leaves);
+        }
+        tree.root
+    }
+
+    // Overflow if the balances don't sum in a u128, see total_supply
+    pub fn genesis_manifest(&self) -> ResultT<GenesisManifest> {
+        Ok(GenesisManifest {
+            root: *self.root(),
+            account_count: self.accounts.len() as u64,
+            total_supply: self.total_supply()?,
+            params_hash: self.params.hash(),
+        })
+    }
+
+    // a book without a manifest matches none
+    pub fn verify_genesis(&self, manifest: &GenesisManifest) -> bool {
+        self.genesis_manifest().is_ok_and(|m| m == *manifest)
+    }
+
+    // keep the root of every interval-th block, 0 (the default) keeps none
+    pub fn set_checkpoint_interval(&mut self, interval: u32) {
+        self.checkpoints.interval = interval;
+    }
+
+    // called by EngineData::update once block sqn is applied
+    fn checkpoint(&mut self, sqn: u32) {
+        let interval = self.checkpoints.interval;
+        if interval != 0 && sqn % interval == 0 {
+            self.checkpoints.roots.insert(sqn, self.proof_tree.root);
+        }
+    }
+
+    // root after the last checkpointed block at or before sqn. None if there is none yet;
+    // a caller that needs the exact block replays from the checkpoint
+    pub fn root_at_height(&self, sqn: u32) -> Option<Hash> {
+        self.checkpoints.roots.range(..=sqn).next_back().map(|(_, root)| *root)
+    }
+
     pub fn get_account(&mut self, aid: &AccountID) -> Option<&mut Account> {
         self.accounts.get_mut(aid)
     }
 
+    // Two accounts borrowed mutably at once, in the order asked for. One range over the
+    // map hands out both ends, so the borrows are disjoint by construction.
+    pub fn get_account_pair(&mut self, a: &AccountID, b: &AccountID) -> ResultT<(&mut Account, &mut Account)> {
+        if a == b {
+            return Err(EngineError::SameAccount);
+        }
+        let (lo, hi) = if a < b { (a, b) } else { (b, a) };
+        let mut range = self.accounts.range_mut::<AccountID, _>(lo..=hi);
+        let first = range.next().filter(|(id, _)| *id == lo).ok_or(EngineError::MissingAccount)?.1;
+        let last = range.next_back().filter(|(id, _)| *id == hi).ok_or(EngineError::MissingAccount)?.1;
+        Ok(if a < b { (first, last) } else { (last, first) })
+    }
+
+    // read only views, for callers that only inspect the state
+    pub fn account_ref(&self, aid: &AccountID) -> Option<&Account> {
+        self.accounts.get(aid)
+    }
+
+    pub fn balance_of(&self, aid: &AccountID) -> Option<u128> {
+        self.accounts.get(aid).map(|a| a.amount.0)
+    }
+
+    // in id order. A partial book only has the accounts it was built for
+    pub fn iter_accounts(&self) -> impl Iterator<Item = (&AccountID, &Account)> {
+        self.accounts.iter()
+    }
+
     pub fn get_account_or_new(&mut self, pk: VerifyingKey) -> &mut Account {
         let aid = pk_to_hash(&pk);
 
         if !self.accounts.contains_key(&aid) {
-            self.accounts.insert(aid.clone(), Account::new(pk, 0, None));
+            self.accounts.insert(aid.clone(), self.new_account(pk, Balance::ZERO, None));
         }
         self.accounts.get_mut(&aid).unwrap()
     }
 
+    // created in the current block, so it accrues from here on. At a closed id it takes
+    // the sqn of the tombstone
+    fn new_account(&self, owner: VerifyingKey, amount: Balance, rollup: Option<RollupState>) -> Account {
+        let sqn_expect = self.closed.get(&pk_to_hash(&owner)).copied().unwrap_or(0);
+        Account { last_accrued: self.height, sqn_expect, ..Account::new(owner, amount, rollup) }
+    }
+
+    // Accrues interest into the accounts a txn touches, and the fee collector, before the
+    // txn runs. Returns the changed leaves. With the rate at 0 nothing is stamped, so
+    // turning it on later accrues from the last touch or creation.
+    pub fn accrue_for(&mut self, t: &Transaction, height: u32) -> TxResult {
+        self.height = height;
+        if self.params.interest_ppb == 0 {
+            return Ok(Vec::new());
+        }
+        let ids = self.affected_ids([t]);
+        let mut hashes = Vec::new();
+        for aid in ids {
+            if let Some(h) = self.accrue(&aid)? {
+                hashes.push((aid, h));
+            }
+        }
+        Ok(hashes)
+    }
+
+    // Simple interest over the blocks since the last accrual, rounded down:
+    // amount * interest_ppb * blocks / INTEREST_DENOMINATOR. Compounds only when the
+    // account is touched, so the result depends on the touch heights but nothing else.
+    // Rollup accounts back L2 balances one to one and never accrue.
+    fn accrue(&mut self, aid: &AccountID) -> ResultT<Option<Hash>> {
+        let rate = self.params.interest_ppb as u128;
+        let height = self.height;
+        let a = match self.accounts.get_mut(aid) {
+            Some(a) if rate != 0 && a.rollup.is_none() && a.last_accrued < height => a,
+            _ => return Ok(None),
+        };
+        let blocks = (height - a.last_accrued) as u128;
+        let interest = a.amount.0.checked_mul(rate * blocks).ok_or(EngineError::Overflow)? / INTEREST_DENOMINATOR;
+        a.amount = a.amount.checked_add(Balance(interest)).ok_or(EngineError::Overflow)?;
+        a.last_accrued = height;
+        self.interest_minted += interest;
+        Ok(Some(a.leaf::<H>()))
+    }
+
     pub fn get_num_accounts(&self) -> usize {
         self.accounts.len()
     }
 
-    pub fn sender_check<T>(&self, tx: &Tx<T>) -> Result<AccountID, &'static str>
+    // sum of the balances the book holds, of the accounts it has for a partial book.
+    // On L1 the rollup accounts hold what backs the L2 balances
+    pub fn total_supply(&self) -> ResultT<u128> {
+        self.accounts.values().try_fold(0u128, |total, a| total.checked_add(a.amount.0)).ok_or(EngineError::Overflow)
+    }
+
+    // expected is what the caller tracked, e.g. genesis plus interest_minted minus burned fees
+    pub fn assert_conservation(&self, expected: u128) -> ResultT<()> {
+        match self.total_supply()? == expected {
+            true => Ok(()),
+            false => Err(EngineError::SupplyMismatch),
+        }
+    }
+
+    // height is the sqn of the block the tx is processed in
+    pub fn sender_check<T>(&self, tx: &Tx<T>, height: u32) -> ResultT<AccountID>
         where T: TxPayload
     {
-        if !tx.sig_verify() {
-            return Err("sig");
+        if !self.sigs_verified && !tx.sig_verify() {
+            return Err(EngineError::BadSig);
+        }
+        if tx.chain_id != self.params.chain_id {
+            return Err(EngineError::WrongChain);
+        }
+        if tx.expired(height) {
+            return Err(EngineError::Expired);
         }
         let id_sender = pk_to_hash(&tx.sender);
         if let Some(a_sender) = self.accounts.get(&id_sender) {
             if a_sender.sqn_expect != tx.sqn {
-                return Err("sqn");
+                return Err(EngineError::BadSqn);
+            }
+            // there is no sqn after it, every tx advances the sender's sqn
+            if tx.sqn == u32::MAX {
+                return Err(EngineError::SqnOverflow);
             }
             if !tx.payload.sender_qualify(a_sender) {
-                return Err("sender");
+                return Err(EngineError::SenderUnqualified);
+            }
+            if let Some(delegate) = &tx.delegate {
+                let spend = tx.payload.spend().ok_or(EngineError::DelegateNotAllowed)?;
+                let fee = match T::PAYS_FEE {
+                    true => self.fee(&id_sender, Balance(spend))?.0,
+                    false => 0,
+                };
+                match (a_sender.delegates.get(&pk_to_hash(delegate)), spend.checked_add(fee)) {
+                    (Some(allowance), Some(charge)) if *allowance >= charge => {}
+                    _ => return Err(EngineError::AllowanceExceeded),
+                }
             }
+            tx.payload.cosigner_check(&tx.sender, tx.sqn, tx.chain_id, tx.valid_until, self)?;
             return Ok(id_sender);
         } else {
-            return Err("account");
+            return Err(EngineError::UnknownAccount);
+        }
+    }
+
+    fn amount_check(&self, amount: u128) -> ResultT<()> {
+        match amount == 0 && !self.params.allow_zero_amount {
+            true => Err(EngineError::ZeroAmount),
+            false => Ok(()),
         }
     }
 
-    pub fn process_payment(&mut self, tx: &Tx<Payment>) -> TxResult
+    pub fn process_payment(&mut self, tx: &Tx<Payment>, height: u32) -> TxResult
     {
-        let mut hashes = Vec::new();
-        let id_sender = self.sender_check(tx)?;
+        let id_sender = self.sender_check(tx, height)?;
+        self.amount_check(tx.payload.amount.0)?;
+        if let Some(expected) = tx.payload.expected_total {
+            let (_, total) = self.fee_and_total(&id_sender, tx.payload.amount)?;
+            if total != expected {
+                return Err(EngineError::TotalMismatch);
+            }
+        }
+        self.transfer(tx, &[(tx.payload.to, tx.payload.amount)])
+    }
+
+    pub fn process_batch_payment(&mut self, tx: &Tx<BatchPayment>, height: u32) -> TxResult
+    {
+        self.sender_check(tx, height)?;
+        if tx.payload.outputs.is_empty() {
+            return Err(EngineError::NoOutputs);
+        }
+        for (_, amount) in &tx.payload.outputs {
+            self.amount_check(*amount)?;
+        }
+        let outputs: Vec<(VerifyingKey, Balance)> = tx.payload.outputs.iter().map(|(pk, a)| (*pk, Balance(*a))).collect();
+        self.transfer(tx, &outputs)
+    }
+
+    // Rejected like any invalid tx when the recipient holds too much ("condition") or the
+    // sender can't cover the amount ("sender"). Either way nothing changes, the sqn is not
+    // used and the sender can sign another tx with it.
+    pub fn process_conditional_payment(&mut self, tx: &Tx<ConditionalPayment>, height: u32) -> TxResult
+    {
+        self.sender_check(tx, height)?;
+        self.amount_check(tx.payload.amount.0)?;
+        let recipient_balance = self.accounts.get(&pk_to_hash(&tx.payload.to)).map_or(0, |a| a.amount.0);
+        if recipient_balance > tx.payload.max_recipient_balance {
+            return Err(EngineError::ConditionUnmet);
+        }
+        self.transfer(tx, &[(tx.payload.to, tx.payload.amount)])
+    }
+
+    pub fn process_set_delegate(&mut self, tx: &Tx<SetDelegate>, height: u32) -> TxResult
+    {
+        let id_sender = self.sender_check(tx, height)?;
+        let id_delegate = pk_to_hash(&tx.payload.delegate);
+        if id_delegate == id_sender {
+            return Err(EngineError::DelegateNotAllowed);
+        }
         let a_sender = self.accounts.get_mut(&id_sender).unwrap();
-        // if a_sender.amount < tx.payload.amount {
-        //     return Err("balance");
-        // }
-        a_sender.amount -= tx.payload.amount;
-        a_sender.sqn_expect += 1;
-        let a_sender_h = a_sender.hash();
-        hashes.push((id_sender, a_sender_h));
+        match tx.payload.allowance {
+            0 => a_sender.delegates.remove(&id_delegate),
+            allowance => a_sender.delegates.insert(id_delegate, allowance),
+        };
+        a_sender.advance(tx, Balance::ZERO);
+        Ok(vec![(id_sender, a_sender.leaf::<H>())])
+    }
 
-        let id_to = pk_to_hash(&tx.payload.to);
-        hashes.push(match self.accounts.get_mut(&id_to) {
-            None => {
-                let a_to = Account::new(tx.payload.to, tx.payload.amount, None);//TODO lifetime
-                let a_to_h = a_to.hash();
-                self.accounts.insert(id_to, a_to);
-                (id_to, a_to_h)
+    pub fn process_create_account(&mut self, tx: &Tx<CreateAccount>, height: u32) -> TxResult
+    {
+        let id_sender = self.sender_check(tx, height)?;
+        let id_new = pk_to_hash(&tx.payload.pubkey);
+        if self.accounts.contains_key(&id_new) {
+            return Err(EngineError::AccountExists);
+        }
+        let a_sender = self.accounts.get_mut(&id_sender).unwrap();
+        a_sender.advance(tx, Balance::ZERO);
+        let sender_hash = a_sender.leaf::<H>();
+        let a_new = self.new_account(tx.payload.pubkey, Balance::ZERO, None);
+        let new_hash = a_new.leaf::<H>();
+        self.accounts.insert(id_new, a_new);
+        Ok(vec![(id_sender, sender_hash), (id_new, new_hash)])
+    }
+
+    pub fn process_close_account(&mut self, tx: &Tx<CloseAccount>, height: u32) -> TxResult
+    {
+        let id_sender = self.sender_check(tx, height)?;
+        let a_sender = &self.accounts[&id_sender];
+        if a_sender.amount != Balance::ZERO || a_sender.rollup.is_some() {
+            return Err(EngineError::AccountNotEmpty);
+        }
+        let mut a_sender = self.accounts.remove(&id_sender).unwrap();
+        a_sender.advance(tx, Balance::ZERO);
+        self.closed.insert(id_sender, a_sender.sqn_expect);
+        Ok(vec![(id_sender, Account::closed_leaf_of::<H>(&id_sender, a_sender.sqn_expect))])
+    }
+
+    // the sender side is checked already
+    // The sender, the recipients and the fee collector may be one account in any
+    // combination. Credits are summed per account and every balance is checked before
+    // anything changes, then each account gets its net change and is hashed once.
+    // The fee is charged once, on the sum of the outputs.
+    fn transfer<T: TxPayload>(&mut self, tx: &Tx<T>, outputs: &[(VerifyingKey, Balance)]) -> TxResult
+    {
+        let id_sender = pk_to_hash(&tx.sender);
+        let amount = outputs.iter().try_fold(Balance::ZERO, |total, (_, a)| total.checked_add(*a)).ok_or(EngineError::Overflow)?;
+        let (fee, total) = self.fee_and_total(&id_sender, amount)?;
+        let fee_credit = match self.params.fee_collector {
+            Some(collector) if fee > Balance::ZERO => Some((collector, fee)),
+            _ => None,
+        };
+        let mut credits: BTreeMap<AccountID, (VerifyingKey, Balance)> = BTreeMap::new();
+        for (pk, credit) in outputs.iter().chain(fee_credit.iter()) {
+            let c = credits.entry(pk_to_hash(pk)).or_insert((*pk, Balance::ZERO));
+            c.1 = c.1.checked_add(*credit).ok_or(EngineError::Overflow)?;
+        }
+
+        // the sender pays in full before it is credited anything back
+        let a_sender = &self.accounts[&id_sender];
+        let mut sender_amount = a_sender.amount.checked_sub(total).ok_or(EngineError::InsufficientBalance)?;
+        for (id, (_, credit)) in &credits {
+            if *id == id_sender {
+                sender_amount = sender_amount.checked_add(*credit).ok_or(EngineError::Overflow)?;
+            } else {
+                self.credit_check(id, *credit)?;
             }
-            Some(a_to) => {
-                a_to.amount += tx.payload.amount;
-                let a_to_h = a_to.hash();
-                (id_to, a_to_h)
+        }
+
+        let mut hashes = Vec::with_capacity(credits.len() + 1);
+        let a_sender = self.accounts.get_mut(&id_sender).unwrap();
+        a_sender.amount = sender_amount;
+        a_sender.advance(tx, fee);
+        hashes.push((id_sender, a_sender.leaf::<H>()));
+        for (id, (pk, credit)) in credits {
+            if id == id_sender {
+                continue;
             }
-        });
+            let a = self.get_account_or_new(pk);
+            a.amount = a.amount.checked_add(credit).expect("credit_check");
+            hashes.push((id, a.leaf::<H>()));
+        }
+        Ok(hashes)
+    }
+
+    // fee of a transfer, and what the sender pays in total
+    fn fee_and_total(&self, sender: &AccountID, amount: Balance) -> ResultT<(Balance, Balance)> {
+        let fee = self.fee(sender, amount)?;
+        let total = amount.checked_add(fee).ok_or(EngineError::Overflow)?;
+        if fee > Balance::ZERO {
+            if let Some(collector) = &self.params.fee_collector {
+                self.credit_check(&pk_to_hash(collector), fee)?;
+            }
+        }
+        Ok((fee, total))
+    }
+
+    fn fee(&self, sender: &AccountID, amount: Balance) -> ResultT<Balance> {
+        match self.params.fee_exempt.contains(sender) {
+            true => Ok(Balance::ZERO),
+            false => Ok(Balance(self.params.fee_policy.fee(amount.0)?)),
+        }
+    }
+
+    // fee an applied txn was charged, with the params it was applied under
+    pub(crate) fn fee_of(&self, t: &Transaction) -> u128 {
+        let (sender, amount) = match t {
+            Transaction::Pay(tx) => (&tx.sender, tx.payload.amount),
+            Transaction::ConditionalPay(tx) => (&tx.sender, tx.payload.amount),
+            Transaction::Withdrawal(tx) => (&tx.sender, tx.payload.amount),
+            Transaction::BatchPay(tx) => (&tx.sender, Balance(tx.payload.total().unwrap_or(0))),
+            _ => return 0,
+        };
+        self.fee(&pk_to_hash(sender), amount).map_or(0, |f| f.0)
+    }
+
+    fn credit_check(&self, aid: &AccountID, amount: Balance) -> ResultT<()> {
+        match self.accounts.get(aid) {
+            Some(a) if a.amount.checked_add(amount).is_none() => Err(EngineError::Overflow),
+            _ => Ok(()),
+        }
+    }
+
+    // credit the fee collector, checked by fee_and_total already
+    fn collect_fee(&mut self, fee: Balance) -> Option<(AccountID, Hash)> {
+        if fee == Balance::ZERO {
+            return None;
+        }
+        let collector = self.params.fee_collector?;
+        let a = self.get_account_or_new(collector);
+        a.amount = a.amount.checked_add(fee).expect("credit_check");
+        Some((pk_to_hash(&collector), a.leaf::<H>()))
+    }
+
+    pub fn process_swap(&mut self, tx: &Tx<Swap>, height: u32) -> TxResult
+    {
+        let mut hashes = Vec::new();
+        let id_sender = self.sender_check(tx, height)?;
+        let id_cp = pk_to_hash(&tx.payload.counterparty);
+        if id_cp == id_sender {
+            return Err(EngineError::SwapSelf);
+        }
+        let (give, want) = (Balance(tx.payload.give), Balance(tx.payload.want));
+        self.amount_check(give.0)?;
+        self.amount_check(want.0)?;
+
+        // both sides are checked before either changes
+        let sender_amount = self.accounts[&id_sender].amount.checked_sub(give).ok_or(EngineError::InsufficientBalance)?
+            .checked_add(want).ok_or(EngineError::Overflow)?;
+        let cp_amount = self.accounts[&id_cp].amount.checked_sub(want).ok_or(EngineError::InsufficientBalance)?
+            .checked_add(give).ok_or(EngineError::Overflow)?;
+
+        let a_sender = self.accounts.get_mut(&id_sender).unwrap();
+        a_sender.amount = sender_amount;
+        a_sender.advance(tx, Balance::ZERO);
+        let a_sender_h = a_sender.leaf::<H>();
+        hashes.push((id_sender, a_sender_h));
+
+        let a_cp = self.accounts.get_mut(&id_cp).unwrap();
+        a_cp.amount = cp_amount;
+        let a_cp_h = a_cp.leaf::<H>();
+        hashes.push((id_cp, a_cp_h));
         Ok(hashes)
     }
 
-    pub fn process_create_rollup_account(&mut self, tx: &Tx<CreateRollupAccount>) -> TxResult
+    pub fn process_create_rollup_account(&mut self, tx: &Tx<CreateRollupAccount>, height: u32) -> TxResult
     {
         let mut hashes = Vec::new();
-        let id_sender = self.sender_check(tx)?;
+        let id_sender = self.sender_check(tx, height)?;
         let id_to = pk_to_hash(&tx.payload.rollup_pk);
+        if id_to == id_sender {
+            return Err(EngineError::RollupSelf);
+        }
+        if tx.payload.bond < self.params.min_rollup_bond {
+            return Err(EngineError::BondTooLow);
+        }
+        // seeded messages are paid for, exactly
+        let seeded = tx.payload.genesis_inbox.iter()
+            .try_fold(0u128, |total, (_, amount)| total.checked_add(*amount));
+        if seeded != Some(tx.payload.genesis_amount) {
+            return Err(EngineError::GenesisInbox);
+        }
+        if tx.payload.genesis_inbox.len() > MAX_INBOX {
+            return Err(EngineError::InboxFull);
+        }
         match self.accounts.get(&id_to) {
             None => {
                 let a_sender = self.accounts.get_mut(&id_sender).unwrap();
-                a_sender.sqn_expect += 1;
-                let a_sender_h = a_sender.hash();
+                let total = Balance(tx.payload.bond.checked_add(tx.payload.genesis_amount).ok_or(EngineError::Overflow)?);
+                a_sender.amount = a_sender.amount.checked_sub(total).ok_or(EngineError::InsufficientBalance)?;
+                a_sender.advance(tx, Balance::ZERO);
+                let a_sender_h = a_sender.leaf::<H>();
                 hashes.push((id_sender, a_sender_h));
 
-                let rus = RollupState { inbox: VecDeque::new(), header_hash: Hash::default(), sqn: 0 };
+                let inbox = tx.payload.genesis_inbox.iter().cloned().collect();
+                let rus = RollupState {
+                    inbox,
+                    header_hash: Hash::default(),
+                    sqn: 0,
+                    bond: tx.payload.bond,
+                    pending_deposits: tx.payload.genesis_amount,
+                    last_timestamp: 0,
+                };
                 //tx.payload.genesis_state_hash
-                let a_to = Account::new(tx.payload.rollup_pk, 0, Some(rus));
-                let a_to_h = a_to.hash();
+                let a_to = self.new_account(tx.payload.rollup_pk, Balance(tx.payload.genesis_amount), Some(rus));
+                let a_to_h = a_to.leaf::<H>();
                 self.accounts.insert(id_to, a_to);
                 hashes.push((id_to, a_to_h));
                 return Ok(hashes);
             }
-            Some(_) => { return Err("exist"); }
+            // a rollup already lives there, or a plain account took the key
+            Some(a) if a.rollup.is_some() => { return Err(EngineError::RollupExists); }
+            Some(_) => { return Err(EngineError::AccountExists); }
         };
     }
 
-    pub fn process_deposit_l1(&mut self, tx: &Tx<L1ToL2Deposit>) -> TxResult
+    // create the rollup account and deposit into it, all or nothing
+    pub fn process_create_and_fund_rollup(&mut self, tx: &Tx<CreateAndFundRollup>, height: u32) -> TxResult
+    {
+        let mut hashes = Vec::new();
+        let id_sender = self.sender_check(tx, height)?;
+        let id_to = pk_to_hash(&tx.payload.rollup_pk);
+        if id_to == id_sender {
+            return Err(EngineError::RollupSelf);
+        }
+        if tx.payload.bond < self.params.min_rollup_bond {
+            return Err(EngineError::BondTooLow);
+        }
+        match self.accounts.get(&id_to) {
+            None => {}
+            Some(a) if a.rollup.is_some() => { return Err(EngineError::RollupExists); }
+            Some(_) => { return Err(EngineError::AccountExists); }
+        }
+
+        let a_sender = self.accounts.get_mut(&id_sender).unwrap();
+        let total = Balance(tx.payload.amount.checked_add(tx.payload.bond).ok_or(EngineError::Overflow)?);
+        a_sender.amount = a_sender.amount.checked_sub(total).ok_or(EngineError::InsufficientBalance)?;
+        a_sender.advance(tx, Balance::ZERO);
+        let a_sender_h = a_sender.leaf::<H>();
+        hashes.push((id_sender, a_sender_h));
+
+        let mut inbox = VecDeque::new();
+        inbox.push_back((tx.id_cached(), tx.payload.amount));
+        let rus = RollupState { inbox, header_hash: Hash::default(), sqn: 0, bond: tx.payload.bond, pending_deposits: tx.payload.amount,
+            last_timestamp: 0 };
+        let a_to = self.new_account(tx.payload.rollup_pk, Balance(tx.payload.amount), Some(rus));
+        let a_to_h = a_to.leaf::<H>();
+        self.accounts.insert(id_to, a_to);
+        hashes.push((id_to, a_to_h));
+        Ok(hashes)
+    }
+
+    pub fn process_deposit_l1(&mut self, tx: &Tx<L1ToL2Deposit>, height: u32) -> TxResult
     {
         let mut hashes = Vec::new();
-        let id_sender = self.sender_check(tx)?;
+        let id_sender = self.sender_check(tx, height)?;
+        self.amount_check(tx.payload.amount)?;
+        if tx.payload.amount < self.params.min_deposit {
+            return Err(EngineError::BelowMinimum);
+        }
         let id_to = pk_to_hash(&tx.payload.rollup_pk);
-        // let (a_sender, a_to) = self.get_account_pair(&id_sender, &id_to)?;
-        //
-        // if a_sender.amount < tx.payload.amount {
-        //     return Err("balance");
-        // }
-        let a_to = self.accounts.get_mut(&id_to);
-        if a_to.is_none() {
-            return Err("missing");
-        }
-        let a_to = a_to.unwrap();
-        if a_to.rollup.is_none() { return Err("not rollup account"); }
+        let (a_sender, a_to) = self.get_account_pair(&id_sender, &id_to)?;
+        // before the rollup is credited, so a deposit that fails changes nothing
+        let sender_amount = a_sender.amount.checked_sub(Balance(tx.payload.amount)).ok_or(EngineError::InsufficientBalance)?;
+        if a_to.rollup.is_none() { return Err(EngineError::NotRollupAccount); }
         let rollup_state = a_to.rollup.as_mut().unwrap();
+        if rollup_state.inbox.len() >= MAX_INBOX {
+            return Err(EngineError::InboxFull);
+        }
 
-        a_to.amount += tx.payload.amount;
-        rollup_state.inbox.push_back(tx.id());
-        let a_to_h = a_to.hash();
+        let amount = a_to.amount.checked_add(Balance(tx.payload.amount)).ok_or(EngineError::Overflow)?;
+        let pending = rollup_state.pending_deposits.checked_add(tx.payload.amount).ok_or(EngineError::Overflow)?;
+        a_to.amount = amount;
+        rollup_state.pending_deposits = pending;
+        rollup_state.inbox.push_back((tx.id_cached(), tx.payload.amount));
+        let a_to_h = a_to.leaf::<H>();
         hashes.push((id_to, a_to_h));
 
-        let a_sender = self.accounts.get_mut(&id_sender).unwrap();
-        a_sender.amount -= tx.payload.amount;
-        a_sender.sqn_expect += 1;
-        let a_sender_h = a_sender.hash();
+        a_sender.amount = sender_amount;
+        a_sender.advance(tx, Balance::ZERO);
+        let a_sender_h = a_sender.leaf::<H>();
         hashes.push((id_sender, a_sender_h));
 
         Ok(hashes)
     }
 
-    pub fn process_deposit_l2(&mut self, tx: &Tx<L1ToL2Deposit>) -> TxResult
+    pub fn process_deposit_l2<T>(&mut self, tx: &Tx<T>) -> TxResult
+        where T: TxPayload + InboxMessage
     {
         let mut hashes = Vec::new();
         let id_to = pk_to_hash(&tx.sender);
         hashes.push(match self.accounts.get_mut(&id_to) {
             None => {
-                let a_to = Account::new(tx.sender, tx.payload.amount, None);
-                let a_to_h = a_to.hash();
+                let a_to = self.new_account(tx.sender, Balance(tx.payload.amount()), None);
+                let a_to_h = a_to.leaf::<H>();
                 self.accounts.insert(id_to, a_to);
                 (id_to, a_to_h)
             }
             Some(a_to) => {
-                a_to.amount += tx.payload.amount;
-                let a_to_h = a_to.hash();
+                a_to.amount = a_to.amount.checked_add(Balance(tx.payload.amount())).ok_or(EngineError::Overflow)?;
+                let a_to_h = a_to.leaf::<H>();
                 (id_to, a_to_h)
             }
         });
         Ok(hashes)
     }
 
-    pub fn process_withdrawal(&mut self, tx: &Tx<L2ToL1Withdrawal>,
+    pub fn process_withdrawal(&mut self, tx: &Tx<L2ToL1Withdrawal>, height: u32,
                               w_records: &mut Vec<WithdrawalRecord>) -> TxResult
     {
         let mut hashes = Vec::new();
-        let id_sender = self.sender_check(tx)?;
+        let id_sender = self.sender_check(tx, height)?;
+        self.amount_check(tx.payload.amount.0)?;
+        let (fee, total) = self.fee_and_total(&id_sender, tx.payload.amount)?;
         let a_sender = self.accounts.get_mut(&id_sender).unwrap();
-        // if a_sender.amount < tx.payload.amount {
-        //     return Err("balance");
-        // }
-        a_sender.amount -= tx.payload.amount;
-        a_sender.sqn_expect += 1;
-        let a_sender_h = a_sender.hash();
+        a_sender.amount = a_sender.amount.checked_sub(total).ok_or(EngineError::InsufficientBalance)?;
+        a_sender.advance(tx, fee);
+        let a_sender_h = a_sender.leaf::<H>();
         hashes.push((id_sender, a_sender_h));
+        hashes.extend(self.collect_fee(fee));
 
-        w_records.push(WithdrawalRecord { to: tx.sender, amount: tx.payload.amount });
+        w_records.push(WithdrawalRecord { to: tx.payload.to.unwrap_or(tx.sender), amount: tx.payload.amount });
         Ok(hashes)
     }
 
-    pub fn process_rollup_state_update(&mut self, tx: &Tx<RollupStateUpdate>,
+    pub fn process_rollup_state_update(&mut self, tx: &Tx<RollupStateUpdate>, height: u32,
                                        valid_receipt: impl Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>) -> TxResult
     {
         // verify sig and account sqn
@@ -456,115 +1989,455 @@ impl AccountBook {
         // update state hash, sqn
         // process withdrawal. We don't separate this step since no gas concern
 
-        // verification steps:
-        let id_sender = self.sender_check(tx)?;
+        self.settle_rollup(tx, height, std::slice::from_ref(&tx.payload.proof_receipt), valid_receipt, &mut None)
+    }
 
-        let receipt = &tx.payload.proof_receipt;
-        let header: BlockHeaderL2 = valid_receipt(receipt)?;
+    pub fn process_rollup_batch_update(&mut self, tx: &Tx<RollupBatchUpdate>, height: u32,
+                                       valid_receipt: impl Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>) -> TxResult
+    {
+        self.settle_rollup(tx, height, &tx.payload.proof_receipts, valid_receipt, &mut None)
+    }
 
-        let a_sender = self.get_account(&id_sender).unwrap();
-        if a_sender.rollup.is_none() {
-            return Err("account_rollup");
+    // slashed is set to why the update failed validation when it is slashed for it
+    fn settle_rollup<T: TxPayload>(&mut self, tx: &Tx<T>, height: u32, receipts: &[Vec<u8>],
+                                   valid_receipt: impl Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>,
+                                   slashed: &mut Option<EngineError>) -> TxResult
+    {
+        // verification steps:
+        let id_sender = self.sender_check(tx, height)?;
+        let (headers, ws) = match self.check_rollup_update(&id_sender, receipts, valid_receipt) {
+            Ok(r) => r,
+            Err(e) => {
+                let r = self.slash_rollup(tx, &id_sender, e);
+                if r.is_ok() {
+                    *slashed = Some(e);
+                }
+                return r;
+            }
+        };
+        // a recipient that can't take its withdrawals rejects the update, it is not the
+        // rollup's fault so there is no slashing. Checked before anything changes; the
+        // recipients are affected ids, so accrue_for has accrued them already
+        let mut credits: BTreeMap<AccountID, Balance> = BTreeMap::new();
+        for w in headers.iter().flat_map(|h| &h.withdrawals) {
+            let c = credits.entry(pk_to_hash(&w.to)).or_default();
+            *c = c.checked_add(w.amount).ok_or(EngineError::Overflow)?;
+        }
+        for (id, credit) in &credits {
+            if *id == id_sender {
+                self.accounts[id].amount.checked_sub(ws).ok_or(EngineError::WithdrawalsExceedBalance)?
+                    .checked_add(*credit).ok_or(EngineError::Overflow)?;
+            } else {
+                self.credit_check(id, *credit)?;
+            }
         }
 
+        // check_rollup_update made sure the messages are there, they must also be pending
+        let rollup = self.accounts[&id_sender].rollup.as_ref().unwrap();
+        let consumed: usize = headers.iter().map(|h| h.inbox_msg_count as usize).sum();
+        let drained = rollup.inbox.iter().take(consumed).try_fold(0u128, |total, (_, amount)| total.checked_add(*amount))
+            .ok_or(EngineError::Overflow)?;
+        let pending = rollup.pending_deposits.checked_sub(drained).ok_or(EngineError::PendingDeposits)?;
+
+        // update
+        let a_sender = self.get_account(&id_sender).unwrap();
         let rollup = a_sender.rollup.as_mut().unwrap();
-        if header.parent != rollup.header_hash {
-            return Err("parent");
+        rollup.inbox.drain(..consumed);
+        rollup.pending_deposits = pending;
+        for header in &headers {
+            rollup.sqn += 1;
+            rollup.header_hash = header.hash();
+            rollup.last_timestamp = header.timestamp;
         }
+        a_sender.amount = a_sender.amount.checked_sub(ws).ok_or(EngineError::WithdrawalsExceedBalance)?;
+        a_sender.advance(tx, Balance::ZERO);
+        let a_sender_h = a_sender.leaf::<H>();
+        let mut hashes = Vec::new();
+        hashes.push((id_sender, a_sender_h));
 
-        if header.sqn != rollup.sqn {
-            return Err("sqn");
+        // process withdrawal. A recipient may be new to L1, its leaf is created here.
+        // Repeated recipients are pushed again, the last hash wins in the engines.
+        // The credits were checked above, they can't overflow here
+        for w in headers.into_iter().flat_map(|h| h.withdrawals) {
+            let acc = self.get_account_or_new(w.to);
+            acc.amount = acc.amount.checked_add(w.amount).expect("credit_check");
+            hashes.push((pk_to_hash(&w.to), acc.leaf::<H>()));
         }
 
-        let mut hasher = DefaultHasher::new();
-        for i in 0..header.inbox_msg_count as usize {
-            hasher.update(rollup.inbox[i]);
+        Ok(hashes)
+    }
+
+    // returns the headers carried by the receipts and their total withdrawal. Each header
+    // must extend the previous one and consume the inbox messages that follow its parent's
+    fn check_rollup_update(&self, id_sender: &AccountID, receipts: &[Vec<u8>],
+                           valid_receipt: impl Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>) -> ResultT<(Vec<BlockHeaderL2>, Balance)>
+    {
+        if receipts.is_empty() {
+            return Err(EngineError::NoReceipts);
         }
-        let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
-        if x != header.inbox_msg_hash {
-            return Err("inbox");
+        let a_sender = self.accounts.get(id_sender).unwrap();
+        if a_sender.rollup.is_none() {
+            return Err(EngineError::SenderNotRollup);
         }
+        let rollup = a_sender.rollup.as_ref().unwrap();
+
+        let mut parent = rollup.header_hash;
+        let mut sqn = rollup.sqn;
+        let mut last_timestamp = rollup.last_timestamp;
+        let mut consumed = 0;
+        let mut ws = Balance::ZERO;
+        let mut headers = Vec::with_capacity(receipts.len());
+        for receipt in receipts {
+            let header: BlockHeaderL2 = valid_receipt(receipt)?;
+            if header.parent != parent {
+                return Err(EngineError::BadParent);
+            }
+
+            if header.sqn != sqn {
+                return Err(EngineError::BadSqn);
+            }
+
+            let count = header.inbox_msg_count as usize;
+            if count > rollup.inbox.len() - consumed {
+                return Err(EngineError::InboxCount);
+            }
+            let mut hasher = DefaultHasher::new();
+            for (id, _) in rollup.inbox.range(consumed..consumed + count) {
+                hasher.update(id);
+            }
+            let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
+            if x != header.inbox_msg_hash {
+                return Err(EngineError::InboxMismatch);
+            }
+
+            // a header from a fork that reuses the sqn must also move time forward. Nothing
+            // to compare the first settlement against
+            if sqn != 0 && header.timestamp <= last_timestamp {
+                return Err(EngineError::StaleTimestamp);
+            }
 
-        let mut ws = 0;
-        for w in &header.withdrawals {
-            ws += w.amount;
+            for w in &header.withdrawals {
+                ws = ws.checked_add(w.amount).ok_or(EngineError::WithdrawOverflow)?;
+            }
+            parent = header.hash();
+            sqn = sqn.checked_add(1).ok_or(EngineError::BadSqn)?;
+            last_timestamp = header.timestamp;
+            consumed += count;
+            headers.push(header);
         }
         if ws > a_sender.amount {
-            return Err("withdraw");
+            return Err(EngineError::WithdrawalsExceedBalance);
         }
+        Ok((headers, ws))
+    }
 
-        // update
-        for _ in 0..header.inbox_msg_count {
-            rollup.inbox.pop_front();
-        }
-        rollup.sqn += 1;
-        rollup.header_hash = header.hash();
-        a_sender.amount -= ws;
-        a_sender.sqn_expect += 1;
-        let a_sender_h = a_sender.hash();
-        let mut hashes = Vec::new();
-        hashes.push((id_sender, a_sender_h));
-
-        // process withdrawal.
-        for w in header.withdrawals {
-            let acc = self.get_account_or_new(w.to);
-            acc.amount += w.amount;
+    // An authenticated rollup update that fails validation still takes its sqn slot and
+    // burns part of the rollup's bond, so invalid updates are not free to spam.
+    // With slashing off (the default) it is simply rejected.
+    fn slash_rollup<T: TxPayload>(&mut self, tx: &Tx<T>, id_sender: &AccountID, e: EngineError) -> TxResult {
+        let slash_bps = (self.params.rollup_slash_bps as u128).min(10_000);
+        let a_sender = self.accounts.get_mut(id_sender).unwrap();
+        if slash_bps == 0 || a_sender.rollup.is_none() {
+            return Err(e);
         }
-
-        Ok(hashes)
+        let rollup = a_sender.rollup.as_mut().unwrap();
+        // bond * bps / 10_000 rounded down, split so a bond near u128::MAX can't overflow
+        let slashed = rollup.bond / 10_000 * slash_bps + rollup.bond % 10_000 * slash_bps / 10_000;
+        rollup.bond = rollup.bond.saturating_sub(slashed);
+        a_sender.advance(tx, Balance::ZERO);
+        Ok(vec![(*id_sender, a_sender.leaf::<H>())])
     }
 
 
     //for supporting a more richer set of txns, the account store must support versioning or
     //other ways to pre-run and get affected accounts before modifying the accounts
-    fn get_affected_account_ids(&self, txns: &Vec<Transaction>) -> Vec<AccountID> {
-        let mut ids = HashSet::new();
+    // sorted, so the same block always yields the same ids in the same order
+    pub fn get_affected_account_ids(&self, txns: &Vec<Transaction>) -> Vec<AccountID> {
+        self.affected_ids(txns).into_iter().collect()
+    }
+
+    // the accounts the txns may touch, with the fee collector any fee is credited to
+    pub(crate) fn affected_ids<'a>(&self, txns: impl IntoIterator<Item = &'a Transaction>) -> BTreeSet<AccountID> {
+        let mut ids = BTreeSet::new();
         for tx in txns {
-            match tx {
-                // only layer 2 txns
-                Transaction::Pay(tx) => {
-                    ids.insert(pk_to_hash(&tx.sender));
-                    ids.insert(pk_to_hash(&tx.payload.to));
+            insert_affected_ids(tx, &mut ids);
+        }
+        if let Some(collector) = &self.params.fee_collector {
+            ids.insert(pk_to_hash(collector));
+        }
+        ids
+    }
+
+    // Apply txns of the given layer and update the tree once, without building a block.
+    // L1 txns run in the canonical order of l1_engine::process. Returns the net change set. Rollup updates need a receipt validator and go
+    // through l1_engine::process instead; withdrawal records are dropped.
+    pub fn apply_transactions(&mut self, txns: &[Transaction], layer: Layer, height: u32) -> ResultT<Vec<(AccountID, Hash)>> {
+        let mut to_update = BTreeMap::new();
+        let order: Vec<usize> = match layer {
+            Layer::L1 => crate::l1_engine::canonical_order(txns),
+            Layer::L2 => (0..txns.len()).collect(),
+        };
+        let mut ctx = ApplyCtx::new(Some(layer), height);
+        for t in order.into_iter().map(|i| &txns[i]) {
+            to_update.extend(self.accrue_for(t, height)?);
+            to_update.extend(self.apply(t, &mut ctx)?);
+        }
+        Ok(crate::engine_util::apply_updates(self, to_update))
+    }
+
+    // Dry run of a tx for wallets and mempools: the checks and the error the engine of
+    // layer would give in the block at height, the sqn the engines process it at, see
+    // EngineData::validate. Nothing in the book is touched. It runs the real process_* on a
+    // partial copy of the accounts the tx touches, so the two can't drift.
+    // Rollup updates need a receipt validator and are not covered.
+    pub fn validate(&self, tx: &Transaction, layer: Layer, height: u32) -> ResultT<()> {
+        let ids: Vec<AccountID> = self.affected_ids([tx]).into_iter().collect();
+        let mut scratch = self.partial_for(&ids);
+        scratch.accrue_for(tx, height)?;
+        scratch.apply(tx, &mut ApplyCtx::new(Some(layer), height)).map(|_| ())
+    }
+
+    // Runs the process_* of the txn's variant, the one dispatch the engines, apply_transactions
+    // and validate share. Interest is not accrued, see accrue_for.
+    pub fn apply(&mut self, t: &Transaction, ctx: &mut ApplyCtx) -> TxResult {
+        let height = ctx.height;
+        match (ctx.layer, t) {
+            (_, Transaction::Pay(tx)) => self.process_payment(tx, height),
+            (_, Transaction::ConditionalPay(tx)) => self.process_conditional_payment(tx, height),
+            (_, Transaction::Swap(tx)) => self.process_swap(tx, height),
+            (_, Transaction::SetDelegate(tx)) => self.process_set_delegate(tx, height),
+            (_, Transaction::BatchPay(tx)) => self.process_batch_payment(tx, height),
+            (_, Transaction::CreateAccount(tx)) => self.process_create_account(tx, height),
+            (_, Transaction::CloseAccount(tx)) => self.process_close_account(tx, height),
+            (None | Some(Layer::L1), Transaction::Deposit(tx)) => {
+                let r = self.process_deposit_l1(tx, height);
+                if r.is_ok() {
+                    ctx.deposits.push(tx.clone());
                 }
-                Transaction::Deposit(_tx) => { panic!("only l2 txns") }
-                Transaction::RollupCreate(_tx) => { panic!("only l2 txns") }
-                Transaction::RollupUpdate(_tx) => { panic!("only l2 txns") }
-                Transaction::DepositL2(tx) => {
-                    ids.insert(pk_to_hash(&tx.sender));
+                r
+            }
+            (None | Some(Layer::L1), Transaction::RollupCreate(tx)) => self.process_create_rollup_account(tx, height),
+            (None | Some(Layer::L1), Transaction::RollupCreateFund(tx)) => self.process_create_and_fund_rollup(tx, height),
+            (None | Some(Layer::L1), Transaction::RollupUpdate(tx)) => match ctx.valid_receipt {
+                Some(valid) => self.settle_rollup(tx, height, std::slice::from_ref(&tx.payload.proof_receipt), valid, &mut ctx.slashed),
+                None => Err(EngineError::UnsupportedTx),
+            },
+            (None | Some(Layer::L1), Transaction::RollupBatchUpdate(tx)) => match ctx.valid_receipt {
+                Some(valid) => self.settle_rollup(tx, height, &tx.payload.proof_receipts, valid, &mut ctx.slashed),
+                None => Err(EngineError::UnsupportedTx),
+            },
+            (None | Some(Layer::L2), Transaction::DepositL2(tx)) => {
+                let r = self.process_deposit_l2(tx);
+                if r.is_ok() {
+                    ctx.inbox_msgs.push(tx.id_cached());
                 }
-                Transaction::Withdrawal(tx) => {
-                    ids.insert(pk_to_hash(&tx.sender));
+                r
+            }
+            (None | Some(Layer::L2), Transaction::RollupCreateFundL2(tx)) => {
+                let r = self.process_deposit_l2(tx);
+                if r.is_ok() {
+                    ctx.inbox_msgs.push(tx.id_cached());
                 }
+                r
+            }
+            (None | Some(Layer::L2), Transaction::Withdrawal(tx)) => self.process_withdrawal(tx, height, &mut ctx.w_records),
+            (_, Transaction::Unknown { .. }) => Err(EngineError::UnknownTx),
+            _ => Err(EngineError::WrongLayer(t.kind())),
+        }
+    }
+
+    // Puts the changed leaves in the tree and returns them. With the parallel feature
+    // the hashes given are ignored, the accounts are rehashed on all cores instead and an
+    // id with neither an account nor a tombstone has no leaf to put and is skipped. The
+    // guest builds without the feature, the zkVM runs single threaded.
+    pub fn update_tree(&mut self, changes: Vec<(AccountID, Hash)>) -> Vec<(AccountID, Hash)> {
+        #[cfg(feature = "parallel")]
+        let changes = self.leaves_par(&changes);
+        self.changed.extend(changes.iter().map(|(id, _)| *id));
+        // opened again, the account's leaf replaces the tombstone
+        for (id, _) in &changes {
+            if self.accounts.contains_key(id) {
+                self.closed.remove(id);
             }
         }
-        ids.into_iter().collect()
+        self.proof_tree.insert_or_replace_batch(changes.clone());
+        changes
+    }
+
+    #[cfg(feature = "parallel")]
+    fn leaves_par(&self, changes: &[(AccountID, Hash)]) -> Vec<(AccountID, Hash)> {
+        use rayon::prelude::*;
+        let mut leaves: Vec<(AccountID, Hash)> = changes.par_iter().filter_map(|(id, _)| match self.accounts.get(id) {
+            Some(a) => Some((*id, a.hash_with::<H>())),
+            None => self.closed.get(id).map(|sqn| (*id, Account::closed_leaf_with::<H>(id, *sqn))),
+        }).collect();
+        leaves.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        leaves.dedup_by(|a, b| a.0 == b.0);
+        leaves
+    }
+
+    // Cuts the book down to the accounts update_tree changed since it was built or
+    // decoded, e.g. a guest's book after its block, before it is serialized again. The
+    // trie keeps only their paths, which is all the root needs to be recomputed from
+    // them. The root stays and verify_partial_root holds; the accounts the block only
+    // read, and those it failed to change, are gone from accounts and from the trie.
+    // With nothing changed the lowest id is kept as the root's witness, a book with no
+    // account or tombstone only verifies as the empty tree.
+    pub fn prune(&mut self) {
+        let mut ids: Vec<AccountID> = std::mem::take(&mut self.changed).into_iter().collect();
+        if ids.is_empty() {
+            ids.extend(self.accounts.keys().chain(self.closed.keys()).min().copied());
+        }
+        self.accounts.retain(|id, _| ids.binary_search(id).is_ok());
+        self.closed.retain(|id, _| ids.binary_search(id).is_ok());
+        self.proof_tree = self.proof_tree.get_partial(&ids.iter().collect());
+    }
+
+    // a full copy of the state, so keep it to the block being tried
+    pub fn snapshot(&self) -> AccountBookSnapshot {
+        AccountBookSnapshot {
+            proof_tree: self.proof_tree.clone(),
+            accounts: self.accounts.clone(),
+            closed: self.closed.clone(),
+            height: self.height,
+            interest_minted: self.interest_minted,
+        }
     }
 
-    pub fn update_tree(&mut self, changes: Vec<(AccountID, Hash)>) {
-        self.proof_tree.insert_or_replace_batch(changes);
+    pub fn restore(&mut self, snap: AccountBookSnapshot) {
+        self.proof_tree = snap.proof_tree;
+        self.accounts = snap.accounts;
+        self.closed = snap.closed;
+        self.height = snap.height;
+        self.interest_minted = snap.interest_minted;
     }
 
-    pub fn get_partial(&self, txns: &Vec<Transaction>) -> AccountBook {
+    // copies only the accounts t may touch, see TxJournal
+    pub fn journal(&self, t: &Transaction) -> TxJournal {
+        let accounts = self.affected_ids([t]).into_iter()
+            .map(|id| (id, self.accounts.get(&id).cloned(), self.closed.get(&id).copied()))
+            .collect();
+        TxJournal { accounts, interest_minted: self.interest_minted }
+    }
+
+    pub fn undo(&mut self, journal: TxJournal) {
+        for (id, account, closed) in journal.accounts {
+            match account {
+                Some(a) => self.accounts.insert(id, a),
+                None => self.accounts.remove(&id),
+            };
+            match closed {
+                Some(sqn) => self.closed.insert(id, sqn),
+                None => self.closed.remove(&id),
+            };
+        }
+        self.interest_minted = journal.interest_minted;
+    }
+
+    pub fn get_partial(&self, txns: &Vec<Transaction>) -> AccountBook<H> {
         let ids = self.get_affected_account_ids(txns);
+        self.partial_for(&ids)
+    }
+
+    // for any set of ids, e.g. the accounts an audit or a settlement looks at, or the
+    // affected ids of a block computed already
+    pub fn partial_for(&self, ids: &[AccountID]) -> AccountBook<H> {
         let mut accounts = BTreeMap::new();
+        // ids without an account yet are left to the tree, the block creates them
         ids.iter().for_each(|id| {
-            let a = self.accounts.get(id).unwrap();
-            accounts.insert(id.clone(), a.clone());
+            if let Some(a) = self.accounts.get(id) {
+                accounts.insert(id.clone(), a.clone());
+            }
         });
+        // and a closed id's tombstone, the sqn an account opened there starts from
+        let closed = ids.iter().filter_map(|id| self.closed.get(id).map(|sqn| (*id, *sqn))).collect();
 
         let id_refs = ids.iter().map(|x| x).collect();
         let proof_tree = self.proof_tree.get_partial(&id_refs);
-        AccountBook { proof_tree, accounts }
+        // the guest proves against this root, it must be the full book's
+        debug_assert_eq!(proof_tree.root, self.proof_tree.root);
+        AccountBook { proof_tree, accounts, params: self.params.clone(), height: self.height, interest_minted: self.interest_minted,
+            checkpoints: Checkpoints::default(), sigs_verified: false, changed: BTreeSet::new(), closed, hasher: PhantomData }
+    }
+
+    pub fn ids_only(&self) -> AccountBookIds {
+        AccountBookIds {
+            proof_tree: self.proof_tree.clone(),
+            ids: self.accounts.keys().cloned().collect(),
+            closed: self.closed.clone(),
+            params: self.params.clone(),
+            height: self.height,
+            interest_minted: self.interest_minted,
+        }
     }
 
     pub fn verify_partial_root(&self) -> bool {
+        self.verify_partial_root_detailed().is_ok()
+    }
+
+    // same checks, saying which one failed. Accounts are checked first, so a structural
+    // error means every account matched its leaf and the trie itself is inconsistent.
+    // The paths are only walked once the trie failed, to name the account whose path broke
+    pub fn verify_partial_root_detailed(&self) -> Result<(), PartialRootError> {
+        // no account to check against the leaves, so only the empty tree is accepted
+        if self.accounts.is_empty() && self.closed.is_empty() {
+            return match *self.root() == PartialMerkleTrie::new().root {
+                true => Ok(()),
+                false => Err(PartialRootError::NotEmpty),
+            };
+        }
         for (id, a) in &self.accounts {
-            let a_h = self.proof_tree.get(id);
-            if a_h.is_none() || a_h.unwrap() != a.hash() {
-                return false;
+            match self.proof_tree.get(id) {
+                None => return Err(PartialRootError::MissingLeaf(*id)),
+                Some(leaf) if leaf != a.hash_with::<H>() => return Err(PartialRootError::LeafMismatch(*id)),
+                Some(_) => {}
+            }
+        }
+        for (id, sqn) in &self.closed {
+            match self.proof_tree.get(id) {
+                None => return Err(PartialRootError::MissingLeaf(*id)),
+                Some(leaf) if leaf != Account::closed_leaf_with::<H>(id, *sqn) => return Err(PartialRootError::LeafMismatch(*id)),
+                Some(_) => {}
             }
         }
-        self.proof_tree.verify_partial()
+        if self.proof_tree.verify_partial() {
+            return Ok(());
+        }
+        let root = *self.root();
+        for id in self.accounts.keys().chain(self.closed.keys()) {
+            match self.proof_tree.get_proof(id) {
+                Some(proof) if proof.verify(&root) => {}
+                _ => return Err(PartialRootError::BadPath { id: *id, root }),
+            }
+        }
+        Err(PartialRootError::Structure { root })
+    }
+
+    // post-state check that each account has a leaf matching its current hash, and each
+    // closed id its tombstone. A missing account fails too
+    pub fn verify_leaves<'a>(&self, ids: impl IntoIterator<Item = &'a AccountID>) -> bool {
+        ids.into_iter().all(|id| match (self.accounts.get(id), self.closed.get(id)) {
+            (Some(a), _) => self.proof_tree.get(id) == Some(a.hash_with::<H>()),
+            (None, Some(sqn)) => self.proof_tree.get(id) == Some(Account::closed_leaf_with::<H>(id, *sqn)),
+            (None, None) => false,
+        })
+    }
+
+    // for a light client, see AccountProof
+    pub fn account_proof(&self, aid: &AccountID) -> Option<AccountProof> {
+        let account = self.accounts.get(aid)?;
+        Some(AccountProof {
+            account: account.clone(),
+            hash: account.hash_with::<H>(),
+            proof: self.proof_tree.get_partial(&vec![aid]),
+        })
+    }
+
+    // for an id that has no account, e.g. the rollup_pk of a CreateRollupAccount. Made for
+    // any id, it only verifies if the id is absent. A closed id is absent, its tombstone
+    // comes along
+    pub fn absence_proof(&self, aid: &AccountID) -> AbsenceProof {
+        AbsenceProof { id: *aid, closed: self.closed.get(aid).copied(), proof: self.proof_tree.get_partial(&vec![aid]) }
     }
 
     #[cfg(test)]
@@ -583,7 +2456,7 @@ impl AccountBook {
         if !is_valid(account) {
             return false;
         }
-        let account_hash = account.hash();
+        let account_hash = account.hash_with::<H>();
 
         let leaf = self.proof_tree.get(&id);
         if leaf.is_none() {
@@ -598,14 +2471,165 @@ impl AccountBook {
     }
 }
 
+// One account and its path to the root, checked without the book. The path is a partial
+// trie rather than a MerkleProof as the trie holds the leaf, so verify ties the account
+// to the root and not only its id.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountProof {
+    pub account: Account,
+    pub hash: Hash,
+    pub proof: PartialMerkleTrie,
+}
+
+impl AccountProof {
+    pub fn verify(&self, root: &Hash) -> bool {
+        self.verify_with::<DefaultHasher>(root)
+    }
+
+    // for a proof out of an AccountBook<H>
+    pub fn verify_with<H: MerkleHasher>(&self, root: &Hash) -> bool {
+        self.account.hash_with::<H>() == self.hash
+            && self.proof.root == *root
+            && self.proof.verify_partial()
+            && self.proof.get(&self.account.id()) == Some(self.hash)
+    }
+}
+
+// The path of an id that ends in an empty leaf, the same check the guest relies on for
+// the accounts a block creates from a partial pre-state
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AbsenceProof {
+    pub id: AccountID,
+    // the sqn of the id's tombstone if it was closed, no leaf at all otherwise
+    pub closed: Option<u32>,
+    pub proof: PartialMerkleTrie,
+}
+
+impl AbsenceProof {
+    pub fn verify(&self, root: &Hash) -> bool {
+        self.verify_with::<DefaultHasher>(root)
+    }
+
+    pub fn verify_with<H: MerkleHasher>(&self, root: &Hash) -> bool {
+        self.proof.root == *root
+            && self.proof.verify_partial()
+            && self.proof.get(&self.id) == self.closed.map(|sqn| Account::closed_leaf_with::<H>(&self.id, sqn))
+    }
+}
+
 #[repr(align(4))]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WithdrawalRecord {
     pub to: VerifyingKey,
-    pub amount: u128,
+    pub amount: Balance,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl WithdrawalRecord {
+    // leaf of withdrawals_root at index, the position of the withdrawal in its block. The
+    // 0 prefix, 1 for inner nodes, keeps a leaf from passing for a node, and the index
+    // keeps a proof from claiming the withdrawal at another position
+    pub fn leaf_hash(&self, index: u32) -> Hash {
+        self.leaf_hash_with::<DefaultHasher>(index)
+    }
+
+    pub fn leaf_hash_with<H: MerkleHasher>(&self, index: u32) -> Hash {
+        let mut hasher = H::new();
+        hasher.update([0u8]);
+        hasher.update(index.to_be_bytes());
+        hasher.update(self.to.to_encoded_point(false));
+        hasher.update(self.amount.to_be_bytes());
+        hasher.finish()
+    }
+}
+
+fn withdrawal_node<H: MerkleHasher>(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = H::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finish()
+}
+
+// Binary tree over the leaves in block order, a node without a sibling moves up a level
+// as it is. All zeros for no withdrawals, what a default header holds.
+pub fn withdrawals_root(withdrawals: &[WithdrawalRecord]) -> Hash {
+    withdrawals_root_with::<DefaultHasher>(withdrawals)
+}
+
+// WithdrawalProof is over the DefaultHasher tree only
+pub fn withdrawals_root_with<H: MerkleHasher>(withdrawals: &[WithdrawalRecord]) -> Hash {
+    let mut level: Vec<Hash> = withdrawals.iter().enumerate().map(|(i, w)| w.leaf_hash_with::<H>(i as u32)).collect();
+    if level.is_empty() {
+        return Hash::default();
+    }
+    while level.len() > 1 {
+        level = level.chunks(2).map(|pair| match pair {
+            [l, r] => withdrawal_node::<H>(l, r),
+            [single] => *single,
+            _ => unreachable!(),
+        }).collect();
+    }
+    level[0]
+}
+
+// The siblings from a leaf up to withdrawals_root. Which side each is on, and which
+// levels have none, follow from index and the number of withdrawals of the block
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WithdrawalProof {
+    pub index: u32,
+    pub leaves: u32,
+    pub siblings: Vec<Hash>,
+}
+
+impl WithdrawalProof {
+    pub fn new(withdrawals: &[WithdrawalRecord], index: usize) -> Option<WithdrawalProof> {
+        if index >= withdrawals.len() {
+            return None;
+        }
+        let mut level: Vec<Hash> = withdrawals.iter().enumerate().map(|(i, w)| w.leaf_hash(i as u32)).collect();
+        let mut pos = index;
+        let mut siblings = Vec::new();
+        while level.len() > 1 {
+            if let Some(sibling) = level.get(pos ^ 1) {
+                siblings.push(*sibling);
+            }
+            level = level.chunks(2).map(|pair| match pair {
+                [l, r] => withdrawal_node::<DefaultHasher>(l, r),
+                [single] => *single,
+                _ => unreachable!(),
+            }).collect();
+            pos /= 2;
+        }
+        Some(WithdrawalProof { index: index as u32, leaves: withdrawals.len() as u32, siblings })
+    }
+
+    pub fn verify(&self, withdrawal: &WithdrawalRecord, root: &Hash) -> bool {
+        if self.index >= self.leaves {
+            return false;
+        }
+        let (mut pos, mut len) = (self.index as usize, self.leaves as usize);
+        let mut siblings = self.siblings.iter();
+        let mut node = withdrawal.leaf_hash(self.index);
+        while len > 1 {
+            if pos ^ 1 < len {
+                let Some(sibling) = siblings.next() else {
+                    return false;
+                };
+                node = match pos % 2 {
+                    1 => withdrawal_node::<DefaultHasher>(sibling, &node),
+                    _ => withdrawal_node::<DefaultHasher>(&node, sibling),
+                };
+            }
+            pos /= 2;
+            len = len.div_ceil(2);
+        }
+        siblings.next().is_none() && node == *root
+    }
+}
+
+// On the wire a Transaction is (tag, bytes), bytes being the encoded Tx of the variant.
+// The length prefix lets a node decode and skip variants it doesn't know yet.
+#[derive(Debug, Clone)]
 pub enum Transaction {
     Pay(Tx<Payment>),
     Deposit(Tx<L1ToL2Deposit>),
@@ -613,22 +2637,379 @@ pub enum Transaction {
     RollupUpdate(Tx<RollupStateUpdate>),
     DepositL2(Tx<L1ToL2Deposit>),
     Withdrawal(Tx<L2ToL1Withdrawal>),
+    RollupCreateFund(Tx<CreateAndFundRollup>),
+    RollupCreateFundL2(Tx<CreateAndFundRollup>),
+    Swap(Tx<Swap>),
+    ConditionalPay(Tx<ConditionalPayment>),
+    RollupBatchUpdate(Tx<RollupBatchUpdate>),
+    SetDelegate(Tx<SetDelegate>),
+    BatchPay(Tx<BatchPayment>),
+    CreateAccount(Tx<CreateAccount>),
+    CloseAccount(Tx<CloseAccount>),
+    // decodable but never valid, the tag and bytes of encode as they were read
+    Unknown { tag: u8, bytes: Vec<u8> },
+}
+
+// Every signature of a block in one pass, before any txn is applied, so a forged txn
+// fails the block up front and the engines skip the per txn check. This is not batch
+// verification: k256 has none for ECDSA, so each signature is verified on its own and
+// the pass costs what the per txn checks would.
+pub fn verify_all_sigs(txns: &[Transaction]) -> ResultT<()> {
+    match txns.iter().position(|t| !t.sig_verify()) {
+        Some(i) => Err(EngineError::BadSigAt(i as u32)),
+        None => Ok(()),
+    }
+}
+
+impl Transaction {
+    // true for the txns the engines don't check the signature of: inbox messages were
+    // checked on L1, and unknown txns are rejected anyway
+    pub fn sig_verify(&self) -> bool {
+        match self {
+            Transaction::Pay(t) => t.sig_verify(),
+            Transaction::ConditionalPay(t) => t.sig_verify(),
+            Transaction::Deposit(t) => t.sig_verify(),
+            Transaction::RollupCreate(t) => t.sig_verify(),
+            Transaction::RollupUpdate(t) => t.sig_verify(),
+            Transaction::Withdrawal(t) => t.sig_verify(),
+            Transaction::RollupCreateFund(t) => t.sig_verify(),
+            Transaction::Swap(t) => t.sig_verify(),
+            Transaction::RollupBatchUpdate(t) => t.sig_verify(),
+            Transaction::SetDelegate(t) => t.sig_verify(),
+            Transaction::BatchPay(t) => t.sig_verify(),
+            Transaction::CreateAccount(t) => t.sig_verify(),
+            Transaction::CloseAccount(t) => t.sig_verify(),
+            Transaction::DepositL2(_) | Transaction::RollupCreateFundL2(_) | Transaction::Unknown { .. } => true,
+        }
+    }
+
+    pub fn id(&self) -> Hash {
+        match self {
+            Transaction::Pay(t) => t.id_cached(),
+            Transaction::ConditionalPay(t) => t.id_cached(),
+            Transaction::Deposit(t) => t.id_cached(),
+            Transaction::RollupCreate(t) => t.id_cached(),
+            Transaction::RollupUpdate(t) => t.id_cached(),
+            Transaction::DepositL2(t) => t.id_cached(),
+            Transaction::Withdrawal(t) => t.id_cached(),
+            Transaction::RollupCreateFund(t) => t.id_cached(),
+            Transaction::RollupCreateFundL2(t) => t.id_cached(),
+            Transaction::Swap(t) => t.id_cached(),
+            Transaction::RollupBatchUpdate(t) => t.id_cached(),
+            Transaction::SetDelegate(t) => t.id_cached(),
+            Transaction::BatchPay(t) => t.id_cached(),
+            Transaction::CreateAccount(t) => t.id_cached(),
+            Transaction::CloseAccount(t) => t.id_cached(),
+            Transaction::Unknown { .. } => self.id_with::<DefaultHasher>(),
+        }
+    }
+
+    // not cached, see Tx::id_cached
+    pub fn id_with<H: MerkleHasher>(&self) -> Hash {
+        match self {
+            Transaction::Pay(t) => t.id_with::<H>(),
+            Transaction::ConditionalPay(t) => t.id_with::<H>(),
+            Transaction::Deposit(t) => t.id_with::<H>(),
+            Transaction::RollupCreate(t) => t.id_with::<H>(),
+            Transaction::RollupUpdate(t) => t.id_with::<H>(),
+            Transaction::DepositL2(t) => t.id_with::<H>(),
+            Transaction::Withdrawal(t) => t.id_with::<H>(),
+            Transaction::RollupCreateFund(t) => t.id_with::<H>(),
+            Transaction::RollupCreateFundL2(t) => t.id_with::<H>(),
+            Transaction::Swap(t) => t.id_with::<H>(),
+            Transaction::RollupBatchUpdate(t) => t.id_with::<H>(),
+            Transaction::SetDelegate(t) => t.id_with::<H>(),
+            Transaction::BatchPay(t) => t.id_with::<H>(),
+            Transaction::CreateAccount(t) => t.id_with::<H>(),
+            Transaction::CloseAccount(t) => t.id_with::<H>(),
+            Transaction::Unknown { tag, bytes } => {
+                let mut hasher = H::new();
+                hasher.update(tag.to_be_bytes());
+                hasher.update(bytes);
+                hasher.finish()
+            }
+        }
+    }
+
+    // None for Unknown, which has no sender we can read
+    pub fn sender_sqn(&self) -> Option<(AccountID, u32)> {
+        match self {
+            Transaction::Pay(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::ConditionalPay(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::Deposit(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::RollupCreate(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::RollupUpdate(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::DepositL2(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::Withdrawal(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::RollupCreateFund(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::RollupCreateFundL2(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::Swap(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::RollupBatchUpdate(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::SetDelegate(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::BatchPay(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::CreateAccount(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::CloseAccount(t) => Some((pk_to_hash(&t.sender), t.sqn)),
+            Transaction::Unknown { .. } => None,
+        }
+    }
+
+    // the tag of the variant and the bincode of its tx, for encode_tagged
+    fn to_tagged(&self) -> ResultT<(u8, Vec<u8>)> {
+        Ok(match self {
+            Transaction::Pay(t) => (0, bincode_config::serialize(t)?),
+            Transaction::Deposit(t) => (1, bincode_config::serialize(t)?),
+            Transaction::RollupCreate(t) => (2, bincode_config::serialize(t)?),
+            Transaction::RollupUpdate(t) => (3, bincode_config::serialize(t)?),
+            Transaction::DepositL2(t) => (4, bincode_config::serialize(t)?),
+            Transaction::Withdrawal(t) => (5, bincode_config::serialize(t)?),
+            Transaction::RollupCreateFund(t) => (6, bincode_config::serialize(t)?),
+            Transaction::RollupCreateFundL2(t) => (7, bincode_config::serialize(t)?),
+            Transaction::Swap(t) => (8, bincode_config::serialize(t)?),
+            Transaction::ConditionalPay(t) => (9, bincode_config::serialize(t)?),
+            Transaction::RollupBatchUpdate(t) => (10, bincode_config::serialize(t)?),
+            Transaction::SetDelegate(t) => (11, bincode_config::serialize(t)?),
+            Transaction::BatchPay(t) => (12, bincode_config::serialize(t)?),
+            Transaction::CreateAccount(t) => (13, bincode_config::serialize(t)?),
+            Transaction::CloseAccount(t) => (14, bincode_config::serialize(t)?),
+            Transaction::Unknown { tag, bytes } => (*tag, bytes.clone()),
+        })
+    }
+
+    // a known tag must take up all of bytes, an Unknown keeps them all
+    fn from_tagged(tag: u8, bytes: Vec<u8>) -> ResultT<Transaction> {
+        Ok(match tag {
+            0 => Transaction::Pay(bincode_config::deserialize_exact(&bytes)?),
+            1 => Transaction::Deposit(bincode_config::deserialize_exact(&bytes)?),
+            2 => Transaction::RollupCreate(bincode_config::deserialize_exact(&bytes)?),
+            3 => Transaction::RollupUpdate(bincode_config::deserialize_exact(&bytes)?),
+            4 => Transaction::DepositL2(bincode_config::deserialize_exact(&bytes)?),
+            5 => Transaction::Withdrawal(bincode_config::deserialize_exact(&bytes)?),
+            6 => Transaction::RollupCreateFund(bincode_config::deserialize_exact(&bytes)?),
+            7 => Transaction::RollupCreateFundL2(bincode_config::deserialize_exact(&bytes)?),
+            8 => Transaction::Swap(bincode_config::deserialize_exact(&bytes)?),
+            9 => Transaction::ConditionalPay(bincode_config::deserialize_exact(&bytes)?),
+            10 => Transaction::RollupBatchUpdate(bincode_config::deserialize_exact(&bytes)?),
+            11 => Transaction::SetDelegate(bincode_config::deserialize_exact(&bytes)?),
+            12 => Transaction::BatchPay(bincode_config::deserialize_exact(&bytes)?),
+            13 => Transaction::CreateAccount(bincode_config::deserialize_exact(&bytes)?),
+            14 => Transaction::CloseAccount(bincode_config::deserialize_exact(&bytes)?),
+            _ => Transaction::Unknown { tag, bytes },
+        })
+    }
+}
+
+// leading byte of Transaction::encode, bumped when a payload's bincode changes
+pub const TX_ENCODING_VERSION: u8 = 1;
+
+impl Transaction {
+    // Compact form for embedding a txn elsewhere, e.g. L1 calldata: the version, the tag
+    // of to_tagged as one byte, then the bincode of the tx. It is also what the binary
+    // serde form of a block carries per txn, there as Unknown for the tags a node doesn't
+    // know. encode refuses an Unknown and decode rejects tags it doesn't know.
+    pub fn encode(&self) -> ResultT<Vec<u8>> {
+        match self {
+            Transaction::Unknown { .. } => Err(EngineError::UnknownTx),
+            _ => self.encode_tagged(),
+        }
+    }
+
+    // Trailing bytes are rejected too, so a txn has exactly one encoding
+    pub fn decode(bytes: &[u8]) -> ResultT<Transaction> {
+        match Transaction::decode_tagged(bytes)? {
+            Transaction::Unknown { .. } => Err(EngineError::UnknownTx),
+            t => Ok(t),
+        }
+    }
+
+    // encode with an Unknown written back as it was read
+    fn encode_tagged(&self) -> ResultT<Vec<u8>> {
+        let (tag, body) = self.to_tagged()?;
+        let mut out = Vec::with_capacity(2 + body.len());
+        out.push(TX_ENCODING_VERSION);
+        out.push(tag);
+        out.extend(body);
+        Ok(out)
+    }
+
+    // decode with an unknown tag kept as Unknown, the bytes after the tag as they are
+    fn decode_tagged(bytes: &[u8]) -> ResultT<Transaction> {
+        let [version, tag, body @ ..] = bytes else {
+            return Err(EngineError::Deserialize);
+        };
+        if *version != TX_ENCODING_VERSION {
+            return Err(EngineError::EncodingVersion);
+        }
+        Transaction::from_tagged(*tag, body.to_vec())
+    }
+}
+
+// Binary formats get the bytes of encode_tagged, the one tagged format. Human readable
+// ones, e.g. JSON for explorers, get the tx itself under the variant name instead of
+// an opaque byte array. Keys and signatures are hex strings there, k256 serializes them
+// that way for human readable formats.
+impl Serialize for Transaction {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return self.encode_tagged().map_err(serde::ser::Error::custom)?.serialize(serializer);
+        }
+        const NAME: &str = "Transaction";
+        match self {
+            Transaction::Pay(t) => serializer.serialize_newtype_variant(NAME, 0, "Pay", t),
+            Transaction::Deposit(t) => serializer.serialize_newtype_variant(NAME, 1, "Deposit", t),
+            Transaction::RollupCreate(t) => serializer.serialize_newtype_variant(NAME, 2, "RollupCreate", t),
+            Transaction::RollupUpdate(t) => serializer.serialize_newtype_variant(NAME, 3, "RollupUpdate", t),
+            Transaction::DepositL2(t) => serializer.serialize_newtype_variant(NAME, 4, "DepositL2", t),
+            Transaction::Withdrawal(t) => serializer.serialize_newtype_variant(NAME, 5, "Withdrawal", t),
+            Transaction::RollupCreateFund(t) => serializer.serialize_newtype_variant(NAME, 6, "RollupCreateFund", t),
+            Transaction::RollupCreateFundL2(t) => serializer.serialize_newtype_variant(NAME, 7, "RollupCreateFundL2", t),
+            Transaction::Swap(t) => serializer.serialize_newtype_variant(NAME, 8, "Swap", t),
+            Transaction::ConditionalPay(t) => serializer.serialize_newtype_variant(NAME, 9, "ConditionalPay", t),
+            Transaction::RollupBatchUpdate(t) => serializer.serialize_newtype_variant(NAME, 10, "RollupBatchUpdate", t),
+            Transaction::SetDelegate(t) => serializer.serialize_newtype_variant(NAME, 11, "SetDelegate", t),
+            Transaction::BatchPay(t) => serializer.serialize_newtype_variant(NAME, 12, "BatchPay", t),
+            Transaction::CreateAccount(t) => serializer.serialize_newtype_variant(NAME, 13, "CreateAccount", t),
+            Transaction::CloseAccount(t) => serializer.serialize_newtype_variant(NAME, 14, "CloseAccount", t),
+            Transaction::Unknown { tag, bytes } => serializer.serialize_newtype_variant(NAME, 15, "Unknown", &(tag, bytes)),
+        }
+    }
+}
+
+// what a human readable format holds, see Serialize for Transaction
+#[derive(Deserialize)]
+enum ReadableTransaction {
+    Pay(Tx<Payment>),
+    Deposit(Tx<L1ToL2Deposit>),
+    RollupCreate(Tx<CreateRollupAccount>),
+    RollupUpdate(Tx<RollupStateUpdate>),
+    DepositL2(Tx<L1ToL2Deposit>),
+    Withdrawal(Tx<L2ToL1Withdrawal>),
+    RollupCreateFund(Tx<CreateAndFundRollup>),
+    RollupCreateFundL2(Tx<CreateAndFundRollup>),
+    Swap(Tx<Swap>),
+    ConditionalPay(Tx<ConditionalPayment>),
+    RollupBatchUpdate(Tx<RollupBatchUpdate>),
+    SetDelegate(Tx<SetDelegate>),
+    BatchPay(Tx<BatchPayment>),
+    CreateAccount(Tx<CreateAccount>),
+    CloseAccount(Tx<CloseAccount>),
+    Unknown((u8, Vec<u8>)),
+}
+
+impl<'de> Deserialize<'de> for Transaction {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Transaction, D::Error> {
+        if !deserializer.is_human_readable() {
+            let bytes = Vec::<u8>::deserialize(deserializer)?;
+            return Transaction::decode_tagged(&bytes).map_err(serde::de::Error::custom);
+        }
+        Ok(match ReadableTransaction::deserialize(deserializer)? {
+            ReadableTransaction::Pay(t) => Transaction::Pay(t),
+            ReadableTransaction::Deposit(t) => Transaction::Deposit(t),
+            ReadableTransaction::RollupCreate(t) => Transaction::RollupCreate(t),
+            ReadableTransaction::RollupUpdate(t) => Transaction::RollupUpdate(t),
+            ReadableTransaction::DepositL2(t) => Transaction::DepositL2(t),
+            ReadableTransaction::Withdrawal(t) => Transaction::Withdrawal(t),
+            ReadableTransaction::RollupCreateFund(t) => Transaction::RollupCreateFund(t),
+            ReadableTransaction::RollupCreateFundL2(t) => Transaction::RollupCreateFundL2(t),
+            ReadableTransaction::Swap(t) => Transaction::Swap(t),
+            ReadableTransaction::ConditionalPay(t) => Transaction::ConditionalPay(t),
+            ReadableTransaction::RollupBatchUpdate(t) => Transaction::RollupBatchUpdate(t),
+            ReadableTransaction::SetDelegate(t) => Transaction::SetDelegate(t),
+            ReadableTransaction::BatchPay(t) => Transaction::BatchPay(t),
+            ReadableTransaction::CreateAccount(t) => Transaction::CreateAccount(t),
+            ReadableTransaction::CloseAccount(t) => Transaction::CloseAccount(t),
+            // still unknown, even if a newer build knows the tag
+            ReadableTransaction::Unknown((tag, bytes)) => Transaction::Unknown { tag, bytes },
+        })
+    }
+}
+
+pub(crate) fn insert_affected_ids(tx: &Transaction, ids: &mut BTreeSet<AccountID>) {
+    match tx {
+        Transaction::Pay(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            ids.insert(pk_to_hash(&tx.payload.to));
+        }
+        Transaction::ConditionalPay(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            ids.insert(pk_to_hash(&tx.payload.to));
+        }
+        Transaction::Deposit(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            ids.insert(pk_to_hash(&tx.payload.rollup_pk));
+        }
+        Transaction::RollupCreate(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            ids.insert(pk_to_hash(&tx.payload.rollup_pk));
+        }
+        Transaction::RollupCreateFund(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            ids.insert(pk_to_hash(&tx.payload.rollup_pk));
+        }
+        // and the withdrawal recipients of each header. A receipt that doesn't decode is
+        // rejected by the engine before any recipient is credited
+        Transaction::RollupUpdate(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            insert_withdrawal_ids(&tx.payload.proof_receipt, ids);
+        }
+        Transaction::RollupBatchUpdate(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            tx.payload.proof_receipts.iter().for_each(|r| insert_withdrawal_ids(r, ids));
+        }
+        Transaction::SetDelegate(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+        }
+        Transaction::CloseAccount(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+        }
+        Transaction::CreateAccount(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            ids.insert(pk_to_hash(&tx.payload.pubkey));
+        }
+        Transaction::BatchPay(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            for (to, _) in &tx.payload.outputs {
+                ids.insert(pk_to_hash(to));
+            }
+        }
+        Transaction::DepositL2(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+        }
+        Transaction::RollupCreateFundL2(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+        }
+        Transaction::Swap(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+            ids.insert(pk_to_hash(&tx.payload.counterparty));
+        }
+        Transaction::Withdrawal(tx) => {
+            ids.insert(pk_to_hash(&tx.sender));
+        }
+        Transaction::Unknown { .. } => {}
+    }
+}
+
+// the receipts carry the L2 headers as bincode_config encodes them, the same bytes the
+// validator of the host reads
+fn insert_withdrawal_ids(receipt: &[u8], ids: &mut BTreeSet<AccountID>) {
+    if let Ok(header) = bincode_config::deserialize::<BlockHeaderL2>(receipt) {
+        ids.extend(header.withdrawals.iter().map(|w| pk_to_hash(&w.to)));
+    }
 }
 
 pub fn tx_set_hash(txns: &Vec<Transaction>) -> Hash {
-    let mut hasher = DefaultHasher::new();
+    tx_set_hash_of::<DefaultHasher>(txns.iter(), Transaction::id)
+}
+
+pub fn tx_set_hash_with<H: MerkleHasher>(txns: &[Transaction]) -> Hash {
+    tx_set_hash_of::<H>(txns.iter(), Transaction::id_with::<H>)
+}
+
+fn tx_set_hash_of<'a, H: MerkleHasher>(txns: impl Iterator<Item = &'a Transaction>, id: impl Fn(&Transaction) -> Hash) -> Hash {
+    let mut hasher = H::new();
     for tx in txns {
-        match tx {
-            Transaction::Pay(t) => hasher.update(&t.id()),
-            Transaction::Deposit(t) => hasher.update(&t.id()),
-            Transaction::RollupCreate(t) => hasher.update(&t.id()),
-            Transaction::RollupUpdate(t) => hasher.update(&t.id()),
-            Transaction::DepositL2(t) => hasher.update(&t.id()),
-            Transaction::Withdrawal(t) => hasher.update(&t.id()),
-        }
+        hasher.update(id(tx));
     }
-    let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
-    x
+    hasher.finish()
 }
 
 #[repr(align(4))]
@@ -638,30 +3019,98 @@ pub struct EngineData {
     pub account_book: AccountBook,
     pub txns: Vec<Transaction>,
     pub sqn: u32,
+    // the engine that processes its blocks, what validate checks the txns against
+    pub layer: Layer,
+    // of the next block. A host sets it from its clock, left alone it advances by one
+    // per block so headers stay ordered
+    pub timestamp: u64,
+    // check every signature of a strict block in one pass before applying any txn,
+    // see verify_all_sigs. Lenient blocks drop bad txns one by one and ignore it
+    pub verify_sigs_first: bool,
+    // host only, see events
+    #[serde(skip)]
+    pub observers: Vec<Box<dyn EngineObserver>>,
+    #[cfg(any(test, feature = "metrics"))]
+    #[serde(skip)]
+    pub metrics: Metrics,
 }
 
 impl EngineData {
-    pub fn new(faucet_key: VerifyingKey, faucet_amout: u128) -> EngineData {
+    // the layer decides the chain id the txns must be signed for, see Layer::default_chain_id
+    pub fn new(faucet_key: VerifyingKey, faucet_amout: u128, layer: Layer) -> EngineData {
+        let mut account_book = AccountBook::new(faucet_key, faucet_amout);
+        account_book.set_params(ChainParams::for_layer(layer));
         EngineData {
             parent: Hash::default(),
-            account_book: AccountBook::new(faucet_key, faucet_amout),
+            account_book,
             txns: vec![],
             sqn: 0,
+            layer,
+            timestamp: 0,
+            verify_sigs_first: false,
+            observers: Vec::new(),
+            #[cfg(any(test, feature = "metrics"))]
+            metrics: Metrics::default(),
         }
     }
 
-    pub fn new_batch(keys: Vec<VerifyingKey>, amout: u128) -> EngineData {
+    pub fn new_batch(keys: Vec<VerifyingKey>, amout: u128, layer: Layer) -> EngineData {
+        let mut account_book = AccountBook::new_batch(keys, amout);
+        account_book.set_params(ChainParams::for_layer(layer));
         EngineData {
             parent: Hash::default(),
-            account_book: AccountBook::new_batch(keys, amout),
+            account_book,
             txns: vec![],
             sqn: 0,
+            layer,
+            timestamp: 0,
+            verify_sigs_first: false,
+            observers: Vec::new(),
+            #[cfg(any(test, feature = "metrics"))]
+            metrics: Metrics::default(),
+        }
+    }
+
+    // Genesis with any number of funded accounts, e.g. several faucets of a testnet, rollups
+    // among them. Unlike AccountBookBuilder a key may only be given once, and the amounts
+    // must not overflow, so total_supply is the sum of the allocations.
+    pub fn new_genesis(allocations: Vec<(VerifyingKey, u128, Option<RollupState>)>, layer: Layer) -> ResultT<EngineData> {
+        let mut ids = BTreeSet::new();
+        let mut total = 0u128;
+        let mut builder = AccountBookBuilder::new();
+        for (pk, amount, rollup) in allocations {
+            if !ids.insert(pk_to_hash(&pk)) {
+                return Err(EngineError::AccountExists);
+            }
+            total = total.checked_add(amount).ok_or(EngineError::Overflow)?;
+            builder = builder.with_account(pk, amount, rollup);
+        }
+        let params = ChainParams::for_layer(layer);
+        Ok(EngineData::with_book(builder.with_params(params).build(), Hash::default(), 0, layer))
+    }
+
+    // wrap a book that is already past genesis, e.g. restored from disk. parent is the
+    // hash of the last processed header and sqn the sqn of the next block, as update leaves them
+    pub fn with_book(account_book: AccountBook, parent: Hash, sqn: u32, layer: Layer) -> EngineData {
+        EngineData {
+            parent,
+            account_book,
+            txns: vec![],
+            sqn,
+            layer,
+            timestamp: 0,
+            verify_sigs_first: false,
+            observers: Vec::new(),
+            #[cfg(any(test, feature = "metrics"))]
+            metrics: Metrics::default(),
         }
     }
 
     pub fn update(&mut self, parent: Hash) {
+        self.account_book.checkpoint(self.sqn);
         self.txns.clear();
         self.sqn += 1;
+        self.timestamp += 1;
         self.parent = parent;
     }
 
@@ -671,7 +3120,106 @@ impl EngineData {
             account_book: self.account_book.get_partial(&self.txns),
             txns: self.txns.clone(),
             sqn: self.sqn,
+            layer: self.layer,
+            timestamp: self.timestamp,
+            verify_sigs_first: self.verify_sigs_first,
+            observers: Vec::new(),
+            #[cfg(any(test, feature = "metrics"))]
+            metrics: Metrics::default(),
+        }
+    }
+
+    // the one its txns must be signed for
+    pub fn chain_id(&self) -> u32 {
+        self.account_book.params().chain_id
+    }
+
+    // AccountBook::validate for the next block, which the engine of self.layer processes at self.sqn
+    pub fn validate(&self, tx: &Transaction) -> ResultT<()> {
+        self.account_book.validate(tx, self.layer, self.sqn)
+    }
+
+    // for a receiver that holds the pre-state already, see AccountBookIds
+    pub fn ids_only(&self) -> EngineDataIds {
+        EngineDataIds {
+            parent: self.parent,
+            account_book: self.account_book.ids_only(),
+            txns: self.txns.clone(),
+            sqn: self.sqn,
+            layer: self.layer,
+            timestamp: self.timestamp,
+            verify_sigs_first: self.verify_sigs_first,
+        }
+    }
+}
+
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EngineDataIds {
+    pub parent: Hash,
+    pub account_book: AccountBookIds,
+    pub txns: Vec<Transaction>,
+    pub sqn: u32,
+    pub layer: Layer,
+    pub timestamp: u64,
+    pub verify_sigs_first: bool,
+}
+
+impl EngineDataIds {
+    pub fn resolve(self, local: &AccountBook) -> ResultT<EngineData> {
+        Ok(EngineData {
+            parent: self.parent,
+            account_book: self.account_book.resolve(local)?,
+            txns: self.txns,
+            sqn: self.sqn,
+            layer: self.layer,
+            timestamp: self.timestamp,
+            verify_sigs_first: self.verify_sigs_first,
+            observers: Vec::new(),
+            #[cfg(any(test, feature = "metrics"))]
+            metrics: Metrics::default(),
+        })
+    }
+}
+
+// A book sent as account ids and the proof tree. The receiver takes the accounts from
+// its own store, the leaves of the tree decide whether its copies are the right ones.
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccountBookIds {
+    proof_tree: PartialMerkleTrie,
+    ids: Vec<AccountID>,
+    // tombstones are small and not in the receiver's store, they go as they are
+    closed: BTreeMap<AccountID, u32>,
+    params: ChainParams,
+    height: u32,
+    interest_minted: u128,
+}
+
+impl AccountBookIds {
+    pub fn resolve<H: MerkleHasher>(self, local: &AccountBook<H>) -> ResultT<AccountBook<H>> {
+        let mut accounts = BTreeMap::new();
+        for id in self.ids {
+            let a = local.accounts.get(&id).ok_or(EngineError::MissingAccount)?;
+            accounts.insert(id, a.clone());
         }
+        let book = AccountBook {
+            proof_tree: self.proof_tree,
+            accounts,
+            params: self.params,
+            height: self.height,
+            interest_minted: self.interest_minted,
+            checkpoints: Checkpoints::default(),
+            sigs_verified: false,
+            changed: BTreeSet::new(),
+            closed: self.closed,
+            hasher: PhantomData,
+        };
+        // a local copy at another version doesn't match its leaf
+        if !book.verify_partial_root() {
+            return Err(EngineError::StaleBook);
+        }
+        Ok(book)
     }
 }
 
@@ -692,6 +3240,11 @@ impl BlockHeaderL1 {
         hasher.update(self.state_root);
         hasher.update(self.sqn.to_be_bytes());
         hasher.update(self.txns_hash);
+        // the deposits by id, the ids the rollup inbox holds
+        hasher.update((self.events.len() as u64).to_be_bytes());
+        for e in &self.events {
+            hasher.update(e.id_cached());
+        }
         let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
         x
     }
@@ -703,10 +3256,17 @@ pub struct BlockHeaderL2 {
     pub parent: Hash,
     pub state_root: Hash,
     pub sqn: u32,
-    pub txns_hash: Hash,//TODO consider changing to l2 txns only to match design doc
+    // set by the block producer, increasing along the chain
+    pub timestamp: u64,
+    // over the txns selected by ChainParams::l2_txns_scope, see L2TxnsScope::txns_hash
+    pub txns_hash: Hash,
     pub inbox_msg_hash: Hash,
     pub inbox_msg_count: u32,
     pub withdrawals: Vec<WithdrawalRecord>,
+    // charged by the txns of the block, credited to ChainParams::fee_collector or burned
+    pub total_fees: u128,
+    // withdrawals_root of withdrawals, to claim one on L1 with a WithdrawalProof
+    pub withdrawals_root: Hash,
 }
 
 impl BlockHeaderL2 {
@@ -715,6 +3275,7 @@ impl BlockHeaderL2 {
         hasher.update(self.parent);
         hasher.update(self.state_root);
         hasher.update(self.sqn.to_be_bytes());
+        hasher.update(self.timestamp.to_be_bytes());
         hasher.update(self.txns_hash);
         hasher.update(self.inbox_msg_hash);
         hasher.update(self.inbox_msg_count.to_be_bytes());
@@ -722,9 +3283,229 @@ impl BlockHeaderL2 {
             hasher.update(w.to.to_encoded_point(false));
             hasher.update(w.amount.to_be_bytes());
         }
+        hasher.update(self.total_fees.to_be_bytes());
+        hasher.update(self.withdrawals_root);
         let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
         x
     }
+
+    pub fn withdrawal_proof(&self, index: usize) -> Option<WithdrawalProof> {
+        WithdrawalProof::new(&self.withdrawals, index)
+    }
+
+    pub fn commitments(&self) -> L2Commitments {
+        L2Commitments {
+            header_hash: self.hash(),
+            withdrawals_root: self.withdrawals_root,
+            inbox_msg_hash: self.inbox_msg_hash,
+            inbox_msg_count: self.inbox_msg_count,
+        }
+    }
+}
+
+// What the L1 bridge needs from an L2 block, as public values of the proof.
+// Laid out as four 32 byte words, the ABI encoding of (bytes32, bytes32, bytes32, uint32).
+// The guest commits them first, so they sit at offset 0 of the public values.
+// withdrawals_root is the one a WithdrawalProof checks against.
+#[derive(Debug, Clone, PartialEq)]
+pub struct L2Commitments {
+    pub header_hash: Hash,
+    pub withdrawals_root: Hash,
+    pub inbox_msg_hash: Hash,
+    pub inbox_msg_count: u32,
+}
+
+impl L2Commitments {
+    pub const LEN: usize = 4 * 32;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::LEN);
+        bytes.extend(self.header_hash);
+        bytes.extend(self.withdrawals_root);
+        bytes.extend(self.inbox_msg_hash);
+        bytes.extend([0u8; 28]);
+        bytes.extend(self.inbox_msg_count.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> ResultT<L2Commitments> {
+        if bytes.len() != Self::LEN || bytes[96..124].iter().any(|b| *b != 0) {
+            return Err(EngineError::Commitments);
+        }
+        Ok(L2Commitments {
+            header_hash: bytes[0..32].try_into().unwrap(),
+            withdrawals_root: bytes[32..64].try_into().unwrap(),
+            inbox_msg_hash: bytes[64..96].try_into().unwrap(),
+            inbox_msg_count: u32::from_be_bytes(bytes[124..128].try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TxKind {
+    Pay,
+    Deposit,
+    RollupCreate,
+    RollupUpdate,
+    DepositL2,
+    Withdrawal,
+    RollupCreateFund,
+    RollupCreateFundL2,
+    Swap,
+    ConditionalPay,
+    RollupBatchUpdate,
+    SetDelegate,
+    BatchPay,
+    CreateAccount,
+    CloseAccount,
+    Unknown,
+}
+
+impl Transaction {
+    pub fn kind(&self) -> TxKind {
+        match self {
+            Transaction::Pay(_) => TxKind::Pay,
+            Transaction::Deposit(_) => TxKind::Deposit,
+            Transaction::RollupCreate(_) => TxKind::RollupCreate,
+            Transaction::RollupUpdate(_) => TxKind::RollupUpdate,
+            Transaction::DepositL2(_) => TxKind::DepositL2,
+            Transaction::Withdrawal(_) => TxKind::Withdrawal,
+            Transaction::RollupCreateFund(_) => TxKind::RollupCreateFund,
+            Transaction::RollupCreateFundL2(_) => TxKind::RollupCreateFundL2,
+            Transaction::Swap(_) => TxKind::Swap,
+            Transaction::ConditionalPay(_) => TxKind::ConditionalPay,
+            Transaction::RollupBatchUpdate(_) => TxKind::RollupBatchUpdate,
+            Transaction::SetDelegate(_) => TxKind::SetDelegate,
+            Transaction::BatchPay(_) => TxKind::BatchPay,
+            Transaction::CreateAccount(_) => TxKind::CreateAccount,
+            Transaction::CloseAccount(_) => TxKind::CloseAccount,
+            Transaction::Unknown { .. } => TxKind::Unknown,
+        }
+    }
+}
+
+impl TxKind {
+    // error for a txn given to the engine of the other layer, naming the kind so a
+    // misrouted txn can be tracked down
+    pub fn wrong_layer(&self) -> &'static str {
+        match self {
+            TxKind::Deposit => "l1 only: Deposit",
+            TxKind::RollupCreate => "l1 only: RollupCreate",
+            TxKind::RollupUpdate => "l1 only: RollupUpdate",
+            TxKind::RollupBatchUpdate => "l1 only: RollupBatchUpdate",
+            TxKind::RollupCreateFund => "l1 only: RollupCreateFund",
+            TxKind::DepositL2 => "l2 only: DepositL2",
+            TxKind::Withdrawal => "l2 only: Withdrawal",
+            TxKind::RollupCreateFundL2 => "l2 only: RollupCreateFundL2",
+            // taken by both layers
+            TxKind::Pay | TxKind::ConditionalPay | TxKind::Swap | TxKind::SetDelegate | TxKind::BatchPay
+            | TxKind::CreateAccount | TxKind::CloseAccount | TxKind::Unknown => "tx type",
+        }
+    }
+}
+
+// what a txn did in its block
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxOutcome {
+    Applied,
+    // an invalid rollup update that still took its sqn and burned part of the bond, with
+    // the error it failed validation with
+    Slashed(EngineError),
+    Failed(EngineError),
+}
+
+// outcome of one txn of a block, for explorers to attribute state changes to txns
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxReceipt {
+    pub id: Hash,
+    pub outcome: TxOutcome,
+    // changed by the txn itself, in id order. Empty for a failed one, and interest
+    // accrued ahead of the txn is not counted
+    pub accounts: Vec<AccountID>,
+}
+
+// per kind (succeeded, failed) counts of a block, for monitoring, and a receipt per txn
+// in the order the engine ran them
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ProcessStats {
+    pub per_kind: BTreeMap<TxKind, (usize, usize)>,
+    pub receipts: Vec<TxReceipt>,
+}
+
+impl ProcessStats {
+    // slashed is ApplyCtx::slashed after t, a slashed update counts as succeeded
+    pub fn record(&mut self, t: &Transaction, r: &TxResult, slashed: Option<EngineError>) {
+        let counts = self.per_kind.entry(t.kind()).or_default();
+        match r.is_ok() {
+            true => counts.0 += 1,
+            false => counts.1 += 1,
+        }
+        let outcome = match (r, slashed) {
+            (Err(e), _) => TxOutcome::Failed(*e),
+            (Ok(_), Some(e)) => TxOutcome::Slashed(e),
+            (Ok(_), None) => TxOutcome::Applied,
+        };
+        let accounts: BTreeSet<AccountID> = r.iter().flatten().map(|(id, _)| *id).collect();
+        self.receipts.push(TxReceipt { id: t.id(), outcome, accounts: accounts.into_iter().collect() });
+    }
+}
+
+// aggregates of a processed block, committed next to the header so the host can cross-check
+#[repr(align(4))]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct BlockSummary {
+    pub num_txns: u32,
+    pub total_paid: u128,
+    pub total_deposited: u128,
+    pub total_withdrawn: u128,
+    // what BlockHeaderL2::total_fees holds for an L2 block
+    pub total_fees: u128,
+}
+
+impl BlockSummary {
+    // host side recomputation from the applied txns of a block, book for the fee rules
+    // the block was processed under
+    pub fn new<H: MerkleHasher>(txns: &Vec<Transaction>, book: &AccountBook<H>) -> ResultT<BlockSummary> {
+        let mut summary = BlockSummary::default();
+        for tx in txns {
+            summary.add(tx);
+            summary.add_fee(book.fee_of(tx))?;
+        }
+        Ok(summary)
+    }
+
+    // Unlike the other totals the fees are checked: an L2 header commits to them, so a
+    // block whose fees don't sum in a u128 fails with Overflow
+    pub fn add_fee(&mut self, fee: u128) -> ResultT<()> {
+        self.total_fees = self.total_fees.checked_add(fee).ok_or(EngineError::Overflow)?;
+        Ok(())
+    }
+
+    // The totals saturate at u128::MAX. The same coins can be paid around a block any
+    // number of times, so the sum of valid txns can overflow, and a summary must not
+    // fail a block the engines accepted.
+    pub fn add(&mut self, tx: &Transaction) {
+        self.num_txns = self.num_txns.saturating_add(1);
+        match tx {
+            Transaction::Pay(t) => self.total_paid = self.total_paid.saturating_add(t.payload.amount.0),
+            Transaction::ConditionalPay(t) => self.total_paid = self.total_paid.saturating_add(t.payload.amount.0),
+            Transaction::Deposit(t) => self.total_deposited = self.total_deposited.saturating_add(t.payload.amount),
+            Transaction::DepositL2(t) => self.total_deposited = self.total_deposited.saturating_add(t.payload.amount),
+            Transaction::Withdrawal(t) => self.total_withdrawn = self.total_withdrawn.saturating_add(t.payload.amount.0),
+            Transaction::RollupCreateFund(t) => self.total_deposited = self.total_deposited.saturating_add(t.payload.amount),
+            Transaction::RollupCreateFundL2(t) => self.total_deposited = self.total_deposited.saturating_add(t.payload.amount),
+            Transaction::Swap(_) => {}
+            Transaction::Unknown { .. } => {}
+            Transaction::RollupCreate(_) => {}
+            Transaction::RollupUpdate(_) => {}
+            Transaction::RollupBatchUpdate(_) => {}
+            Transaction::SetDelegate(_) => {}
+            Transaction::CreateAccount(_) => {}
+            Transaction::CloseAccount(_) => {}
+            // applied, so the sum doesn't overflow
+            Transaction::BatchPay(t) => self.total_paid = self.total_paid.saturating_add(t.payload.total().unwrap_or(0)),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -739,4 +3520,21 @@ impl TxSigner {
         let pk = sk.verifying_key().clone();
         TxSigner { sk, pk, sqn: 0 }
     }
+
+    pub fn batch(n: usize, rng: &mut impl k256::elliptic_curve::rand_core::CryptoRngCore) -> Vec<TxSigner> {
+        (0..n).map(|_| TxSigner::new(SigningKey::random(rng))).collect()
+    }
+
+    // payments from faucet funding each signer, signed for the book's chain. The sqns
+    // continue from the faucet's account in the book, and faucet.sqn is left at the next one to use
+    pub fn fund_all(book: &AccountBook, faucet: &mut TxSigner, signers: &[TxSigner], amount: Balance) -> Vec<Transaction> {
+        if let Some(a) = book.accounts.get(&pk_to_hash(&faucet.pk)) {
+            faucet.sqn = a.sqn_expect;
+        }
+        signers.iter().map(|s| {
+            let tx = Tx::new_on_chain(book.params().chain_id, faucet.pk, faucet.sqn, Payment { to: s.pk, amount, expected_total: None }, &mut faucet.sk);
+            faucet.sqn += 1;
+            Transaction::Pay(tx)
+        }).collect()
+    }
 }
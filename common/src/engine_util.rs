@@ -0,0 +1,40 @@
+use std::collections::BTreeMap;
+use crate::common::*;
+
+// finalize the state changes of a block, in the same way for both engines.
+// Returns the change set applied to the tree, ordered by account id
+pub(crate) fn apply_updates<H: MerkleHasher>(book: &mut AccountBook<H>, updates: BTreeMap<AccountID, Hash>) -> Vec<(AccountID, Hash)> {
+    let to_update = book.update_tree(updates.into_iter().collect());
+    // accounts created by the block had absence proofs in a partial pre-state,
+    // they must have their leaves now like every other account the block touched
+    debug_assert!(book.verify_leaves(to_update.iter().map(|(id, _)| id)));
+    to_update
+}
+
+// Runs a block all or nothing: a failed block leaves the book as it found it, instead of
+// with the txns before the failing one applied and the tree not updated. The snapshot
+// copies the book, so the guest, which aborts on a failed block, goes without it.
+pub(crate) fn atomic<R>(input: &mut EngineData, f: impl FnOnce(&mut EngineData) -> ResultT<R>) -> ResultT<R> {
+    if cfg!(feature = "guest") {
+        return f(input);
+    }
+    let snap = input.account_book.snapshot();
+    let r = f(input);
+    if r.is_err() {
+        input.account_book.restore(snap);
+    }
+    r
+}
+
+// Runs f with the book told the block's signatures are checked, when the host asked for
+// verify_all_sigs and f is the strict path. Cleared again whatever f returns.
+pub(crate) fn sigs_first<R>(input: &mut EngineData, f: impl FnOnce(&mut EngineData) -> ResultT<R>) -> ResultT<R> {
+    if !input.verify_sigs_first {
+        return f(input);
+    }
+    verify_all_sigs(&input.txns)?;
+    input.account_book.sigs_verified = true;
+    let r = f(input);
+    input.account_book.sigs_verified = false;
+    r
+}
@@ -0,0 +1,59 @@
+// Host side error, so code that does I/O and drives the engines can use `?` on both.
+// The engines themselves only know their own error and stay free of std::io.
+use std::fmt;
+use std::io;
+
+use crate::common::EngineError;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Engine(EngineError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io: {}", e),
+            Error::Engine(e) => write!(f, "engine: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Engine(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<EngineError> for Error {
+    fn from(e: EngineError) -> Self {
+        Error::Engine(e)
+    }
+}
+
+// a rejected tx or block is bad input data as far as I/O callers are concerned
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::Engine(_) => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+// so a host fn returning io::Result can use `?` on the engines directly
+impl From<EngineError> for io::Error {
+    fn from(e: EngineError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e.as_str())
+    }
+}
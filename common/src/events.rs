@@ -0,0 +1,88 @@
+use std::collections::{BTreeMap, BTreeSet};
+use crate::common::*;
+
+// Change notifications for a host node. Observers sit on EngineData but are never
+// serialized, so the guest runs without them, and they only see the block after it is
+// processed, so they can't affect the state root.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineEvent {
+    AccountCreated { id: AccountID, owner: VerifyingKey },
+    // by a CloseAccount, the id holds a tombstone now
+    AccountClosed { id: AccountID },
+    // new balance of an account a txn changed, interest accrued by the txn included
+    BalanceChanged { id: AccountID, amount: Balance },
+    // a rollup update was applied, sqn and header hash as it left the rollup
+    RollupSettled { id: AccountID, sqn: u32, header_hash: Hash },
+}
+
+// Send so an EngineData with observers can move to another thread, e.g. a worker
+pub trait EngineObserver: Send {
+    fn on_event(&mut self, event: &EngineEvent);
+}
+
+impl std::fmt::Debug for dyn EngineObserver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EngineObserver")
+    }
+}
+
+pub(crate) fn notify(observers: &mut Vec<Box<dyn EngineObserver>>, events: &[EngineEvent]) {
+    for e in events {
+        for o in observers.iter_mut() {
+            o.on_event(e);
+        }
+    }
+}
+
+// balance and rollup sqn, None if the account doesn't exist
+type AccountState = Option<(Balance, Option<u32>)>;
+
+fn state(a: &Account) -> (Balance, Option<u32>) {
+    (a.amount, a.rollup.as_ref().map(|ru| ru.sqn))
+}
+
+// the accounts a txn may touch, taken before it runs and before its interest accrues
+pub(crate) struct TxWatch {
+    before: BTreeMap<AccountID, AccountState>,
+}
+
+impl TxWatch {
+    pub(crate) fn new(book: &AccountBook, t: &Transaction) -> TxWatch {
+        let before = book.affected_ids([t]).into_iter().map(|id| (id, book.accounts.get(&id).map(state))).collect();
+        TxWatch { before }
+    }
+
+    // Events of a txn that succeeded with updates, in account id order. Called before the
+    // updates join pending, the leaves earlier txns of the block changed. Withdrawal
+    // recipients aren't known up front, an existing one is told its new balance.
+    pub(crate) fn events(&self, book: &AccountBook, updates: &[(AccountID, Hash)],
+                         pending: &BTreeMap<AccountID, Hash>, out: &mut Vec<EngineEvent>) {
+        let ids: BTreeSet<AccountID> = updates.iter().map(|(id, _)| *id).collect();
+        for id in ids {
+            let Some(a) = book.accounts.get(&id) else {
+                out.push(EngineEvent::AccountClosed { id });
+                continue;
+            };
+            let (amount, sqn) = state(a);
+            let known = self.before.get(&id).copied();
+            let existed = match known {
+                Some(before) => before.is_some(),
+                None => pending.contains_key(&id) || book.proof_tree.get(&id).is_some(),
+            };
+            if !existed {
+                out.push(EngineEvent::AccountCreated { id, owner: a.owner });
+            }
+            // no account, or a recipient we didn't watch, counts as zero before
+            let before = known.flatten();
+            if before.map_or(Balance::ZERO, |(b, _)| b) != amount {
+                out.push(EngineEvent::BalanceChanged { id, amount });
+            }
+            if let (Some(sqn), Some((_, before_sqn))) = (sqn, before) {
+                if before_sqn != Some(sqn) {
+                    let header_hash = a.rollup.as_ref().unwrap().header_hash;
+                    out.push(EngineEvent::RollupSettled { id, sqn, header_hash });
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::ops::Bound;
+use partial_binary_merkle::{MerkleProof, PartialMerkleTrie};
+use serde::{Deserialize, Serialize};
+use crate::common::*;
+
+// Read only queries over an account book, the surface a frontend talks to.
+pub struct Explorer<'a> {
+    book: &'a AccountBook,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookStats {
+    pub root: Hash,
+    pub num_accounts: usize,
+    pub num_rollups: usize,
+    // u128::MAX if the balances sum past it
+    pub total_balance: u128,
+}
+
+impl<'a> Explorer<'a> {
+    pub fn new(book: &'a AccountBook) -> Explorer<'a> {
+        Explorer { book }
+    }
+
+    pub fn balance(&self, aid: &AccountID) -> Option<Balance> {
+        self.book.accounts.get(aid).map(|a| a.amount)
+    }
+
+    pub fn account(&self, aid: &AccountID) -> Option<&'a Account> {
+        self.book.accounts.get(aid)
+    }
+
+    // proof of the account's leaf against the current root
+    pub fn proof(&self, aid: &AccountID) -> Option<MerkleProof> {
+        self.book.proof_tree.get_proof(aid)
+    }
+
+    // one proof for many accounts. Paths of nearby ids share their upper nodes, which
+    // the partial trie holds once, so it is smaller than the individual proofs together.
+    // None if an id has no account
+    pub fn multi_proof(&self, aids: &[AccountID]) -> Option<CompactMultiProof> {
+        if !aids.iter().all(|aid| self.book.accounts.contains_key(aid)) {
+            return None;
+        }
+        let refs = aids.iter().collect();
+        Some(CompactMultiProof { trie: self.book.proof_tree.get_partial(&refs) })
+    }
+
+    pub fn stats(&self) -> BookStats {
+        BookStats {
+            root: *self.book.root(),
+            num_accounts: self.book.accounts.len(),
+            num_rollups: self.book.accounts.values().filter(|a| a.rollup.is_some()).count(),
+            // saturates like the BlockSummary totals
+            total_balance: self.book.accounts.values().fold(0u128, |total, a| total.saturating_add(a.amount.0)),
+        }
+    }
+
+    // up to limit accounts in id order after cursor (from the start if None),
+    // and the cursor of the next page if there is one
+    pub fn accounts_page(&self, cursor: Option<AccountID>, limit: usize) -> (Vec<(&'a AccountID, &'a Account)>, Option<AccountID>) {
+        let start = match cursor {
+            None => Bound::Unbounded,
+            Some(c) => Bound::Excluded(c),
+        };
+        let mut range = self.book.accounts.range((start, Bound::Unbounded));
+        let page: Vec<(&AccountID, &Account)> = range.by_ref().take(limit).collect();
+        let next = match (page.last(), range.next()) {
+            (Some((last, _)), Some(_)) => Some(**last),
+            _ => None,
+        };
+        (page, next)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactMultiProof {
+    trie: PartialMerkleTrie,
+}
+
+// every (id, leaf) is in the proof and the proof hashes up to root
+pub fn verify_multi(proof: &CompactMultiProof, root: &Hash, leaves: &[(AccountID, Hash)]) -> bool {
+    proof.trie.root == *root
+        && proof.trie.verify_partial()
+        && leaves.iter().all(|(aid, leaf)| proof.trie.get(aid) == Some(*leaf))
+}
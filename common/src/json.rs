@@ -0,0 +1,12 @@
+// JSON for explorers and REST APIs, e.g. of a Transaction or a block header. Not a wire
+// format, blocks and receipts go through bincode_config; a tx keeps its id either way.
+use serde::{de::DeserializeOwned, Serialize};
+use crate::common::{EngineError, ResultT};
+
+pub fn to_json<T: Serialize>(value: &T) -> ResultT<String> {
+    serde_json::to_string(value).map_err(|_| EngineError::Serialize)
+}
+
+pub fn from_json<T: DeserializeOwned>(json: &str) -> ResultT<T> {
+    serde_json::from_str(json).map_err(|_| EngineError::Deserialize)
+}
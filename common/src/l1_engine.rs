@@ -1,48 +1,145 @@
+use std::collections::BTreeMap;
 use crate::common::*;
+use crate::engine_util::{apply_updates, atomic, sigs_first};
+use crate::events::{notify, TxWatch};
+
+// Canonical order of txns within an L1 block: rollup creates and everything else first,
+// then deposits, then rollup updates. A deposit always finds the rollup created in the
+// same block, and an update can consume the deposits of its own block.
+pub(crate) fn canonical_rank(t: &Transaction) -> u8 {
+    match t {
+        Transaction::Deposit(_) => 1,
+        Transaction::RollupUpdate(_) | Transaction::RollupBatchUpdate(_) => 2,
+        _ => 0,
+    }
+}
+
+// The rank each txn is run at: a sender's txns stay in sqn order, so one ranks at least as
+// late as the sender's txns before it, e.g. a payment signed after a rollup update goes
+// with the updates
+pub(crate) fn canonical_ranks<'a>(txns: impl IntoIterator<Item = &'a Transaction>) -> Vec<u8> {
+    let mut ranks = Vec::new();
+    let mut by_sender: BTreeMap<AccountID, Vec<(u32, usize)>> = BTreeMap::new();
+    for (i, t) in txns.into_iter().enumerate() {
+        ranks.push(canonical_rank(t));
+        if let Some((sender, sqn)) = t.sender_sqn() {
+            by_sender.entry(sender).or_default().push((sqn, i));
+        }
+    }
+    for mut q in by_sender.into_values() {
+        q.sort_unstable();
+        let mut rank = 0;
+        for (_, i) in q {
+            rank = rank.max(ranks[i]);
+            ranks[i] = rank;
+        }
+    }
+    ranks
+}
+
+// positions of the txns in the order they run, stable within a rank
+pub(crate) fn canonical_order<'a>(txns: impl IntoIterator<Item = &'a Transaction>) -> Vec<usize> {
+    let ranks = canonical_ranks(txns);
+    let mut order: Vec<usize> = (0..ranks.len()).collect();
+    order.sort_by_key(|i| ranks[*i]);
+    order
+}
+
+// txns[k] becomes the txn at order[k]
+fn permute(txns: &mut Vec<Transaction>, order: &[usize]) {
+    let mut slots: Vec<Option<Transaction>> = std::mem::take(txns).into_iter().map(Some).collect();
+    *txns = order.iter().map(|i| slots[*i].take().expect("permutation")).collect();
+}
+
+// undoes permute
+fn unpermute(txns: &mut Vec<Transaction>, order: &[usize]) {
+    let mut slots: Vec<Option<Transaction>> = (0..txns.len()).map(|_| None).collect();
+    for (t, i) in std::mem::take(txns).into_iter().zip(order) {
+        slots[*i] = Some(t);
+    }
+    *txns = slots.into_iter().map(|t| t.expect("permutation")).collect();
+}
 
 pub fn process(input: &mut EngineData,
-               valid_receipt: impl Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>) -> ResultT<BlockHeaderL1> {
-    let txns_hash = tx_set_hash(&input.txns);
-    let mut to_update = std::collections::HashMap::new();
-    let mut deposits = Vec::new();
-    for t in &input.txns {
-        let mut updates = match t {
-            Transaction::Pay(tx) => {
-                input.account_book.process_payment(tx)?
-            }
-            Transaction::Deposit(tx) => {
-                let r = input.account_book.process_deposit_l1(tx)?;
-                deposits.push((*tx).clone());
-                r
-            }
-            Transaction::RollupCreate(tx) => {
-                input.account_book.process_create_rollup_account(tx)?
-            }
-            Transaction::RollupUpdate(tx) => {
-                input.account_book.process_rollup_state_update(tx, &valid_receipt)?
-            }
+               valid_receipt: impl Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>) -> ResultT<(BlockHeaderL1, BlockSummary)> {
+    let (header, summary, _) = atomic(input, |input| sigs_first(input, |input| process_inner(input, valid_receipt, false)))?;
+    Ok((header, summary))
+}
+
+// Invalid txns are dropped from the block instead of failing it, the header covers
+// only the applied ones. For block producers, the guest runs the strict process.
+pub fn process_lenient(input: &mut EngineData,
+                       valid_receipt: impl Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>) -> ResultT<(BlockHeaderL1, BlockSummary, ProcessStats)> {
+    atomic(input, |input| process_inner(input, valid_receipt, true))
+}
 
-            _ => {
-                return Err("tx type");
+// a failed block leaves the txns in the order they came in, as atomic leaves the book
+fn process_inner(input: &mut EngineData,
+                 valid_receipt: impl Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>,
+                 lenient: bool) -> ResultT<(BlockHeaderL1, BlockSummary, ProcessStats)> {
+    let order = canonical_order(&input.txns);
+    permute(&mut input.txns, &order);
+    let r = process_sorted(input, valid_receipt, lenient);
+    if r.is_err() {
+        unpermute(&mut input.txns, &order);
+    }
+    r
+}
+
+fn process_sorted(input: &mut EngineData,
+                  valid_receipt: impl Fn(&Vec<u8>) -> ResultT<BlockHeaderL2>,
+                  lenient: bool) -> ResultT<(BlockHeaderL1, BlockSummary, ProcessStats)> {
+    // by account id, so the change set comes out in the same order on every run
+    let mut to_update = std::collections::BTreeMap::new();
+    let mut ctx = ApplyCtx::new(Some(Layer::L1), input.sqn).with_receipts(&valid_receipt);
+    let mut summary = BlockSummary::default();
+    let mut stats = ProcessStats::default();
+    let mut applied = Vec::with_capacity(input.txns.len());
+    let mut events = Vec::new();
+    for t in &input.txns {
+        let watch = (!input.observers.is_empty()).then(|| TxWatch::new(&input.account_book, t));
+        // a dropped txn must not leave its accrual behind, the strict path fails the block instead
+        let journal = lenient.then(|| input.account_book.journal(t));
+        let accrued = input.account_book.accrue_for(t, input.sqn)?;
+        let r = input.account_book.apply(t, &mut ctx);
+        stats.record(t, &r, ctx.slashed.take());
+        applied.push(r.is_ok());
+        let mut updates = match (r, journal) {
+            (Ok(updates), _) => updates,
+            (Err(_), Some(journal)) => {
+                input.account_book.undo(journal);
+                continue;
             }
+            (Err(e), None) => return Err(e),
         };
+        for (k, v) in accrued {
+            to_update.insert(k, v);
+        }
+        if let Some(watch) = &watch {
+            watch.events(&input.account_book, &updates, &to_update, &mut events);
+        }
         for (k, v) in updates.drain(..) {
             to_update.insert(k, v);
         }
+        summary.add(t);
+        summary.add_fee(input.account_book.fee_of(t))?;
     }
-    let to_update: Vec<(AccountID, Hash)> = to_update.into_iter().collect();
-    input.account_book.update_tree(to_update);
+    apply_updates(&mut input.account_book, to_update);
 
+    let mut applied = applied.into_iter();
+    input.txns.retain(|_| applied.next().unwrap());
+    #[cfg(any(test, feature = "metrics"))]
+    input.metrics.record(&input.account_book, &input.txns, &summary);
     let header = BlockHeaderL1 {
         parent: input.parent,
         state_root: *input.account_book.root(),
         sqn: input.sqn,
-        txns_hash,
-        events: deposits,
+        txns_hash: tx_set_hash(&input.txns),
+        events: ctx.deposits,
     };
 
     input.update(header.hash());
+    notify(&mut input.observers, &events);
 
-    Ok(header)
+    Ok((header, summary, stats))
 }
-
@@ -1,52 +1,81 @@
 use crate::common::*;
+use crate::engine_util::{apply_updates, atomic, sigs_first};
+use crate::events::{notify, TxWatch};
 use sha2::Digest;
 
-pub fn process(input: &mut EngineData) -> ResultT<BlockHeaderL2> {
-    let txns_hash = tx_set_hash(&input.txns);
-    let mut to_update = std::collections::HashMap::new();
-    let mut w_records = Vec::new();
-    let mut l1_l2_msgs = Vec::new();
+pub fn process(input: &mut EngineData) -> ResultT<(BlockHeaderL2, BlockSummary)> {
+    let (header, summary, _) = atomic(input, |input| sigs_first(input, |input| process_inner(input, false)))?;
+    Ok((header, summary))
+}
+
+// drops invalid txns instead of failing the block, see l1_engine::process_lenient
+pub fn process_lenient(input: &mut EngineData) -> ResultT<(BlockHeaderL2, BlockSummary, ProcessStats)> {
+    atomic(input, |input| process_inner(input, true))
+}
+
+fn process_inner(input: &mut EngineData, lenient: bool) -> ResultT<(BlockHeaderL2, BlockSummary, ProcessStats)> {
+    let mut to_update = std::collections::BTreeMap::new();
+    let mut ctx = ApplyCtx::new(Some(Layer::L2), input.sqn);
+    let mut summary = BlockSummary::default();
+    let mut stats = ProcessStats::default();
+    let mut applied = Vec::with_capacity(input.txns.len());
+    let mut events = Vec::new();
     for t in &input.txns {
-        let mut updates = match t {
-            Transaction::Pay(tx) => {
-                input.account_book.process_payment(tx)?
-            }
-            Transaction::DepositL2(tx) => {
-                l1_l2_msgs.push(tx.id());
-                input.account_book.process_deposit_l2(tx)?
-            }
-            Transaction::Withdrawal(tx) => {
-                input.account_book.process_withdrawal(tx, &mut w_records)?
-            }
-            _ => {
-                return Err("tx type");
+        let watch = (!input.observers.is_empty()).then(|| TxWatch::new(&input.account_book, t));
+        // a dropped txn must not leave its accrual behind, the strict path fails the block instead
+        let journal = lenient.then(|| input.account_book.journal(t));
+        let accrued = input.account_book.accrue_for(t, input.sqn)?;
+        let r = input.account_book.apply(t, &mut ctx);
+        stats.record(t, &r, ctx.slashed.take());
+        applied.push(r.is_ok());
+        let mut updates = match (r, journal) {
+            (Ok(updates), _) => updates,
+            (Err(_), Some(journal)) => {
+                input.account_book.undo(journal);
+                continue;
             }
+            (Err(e), None) => return Err(e),
         };
+        for (k, v) in accrued {
+            to_update.insert(k, v);
+        }
+        if let Some(watch) = &watch {
+            watch.events(&input.account_book, &updates, &to_update, &mut events);
+        }
         for (k, v) in updates.drain(..) {
             to_update.insert(k, v);
         }
+        summary.add(t);
+        summary.add_fee(input.account_book.fee_of(t))?;
     }
-    let to_update: Vec<(AccountID, Hash)> = to_update.into_iter().collect();
-    input.account_book.update_tree(to_update);
+    apply_updates(&mut input.account_book, to_update);
 
     let mut hasher = DefaultHasher::new();
-    let num_msgs = l1_l2_msgs.len();
-    for tid in l1_l2_msgs {
+    let num_msgs = ctx.inbox_msgs.len();
+    for tid in ctx.inbox_msgs {
         hasher.update(tid);
     }
     let x: Hash = hasher.finalize().as_slice().try_into().expect("hash");
 
+    let mut applied = applied.into_iter();
+    input.txns.retain(|_| applied.next().unwrap());
+    #[cfg(any(test, feature = "metrics"))]
+    input.metrics.record(&input.account_book, &input.txns, &summary);
     let header = BlockHeaderL2 {
         parent: input.parent,
         state_root: *input.account_book.root(),
         sqn: input.sqn,
-        txns_hash,
+        timestamp: input.timestamp,
+        txns_hash: input.account_book.params().l2_txns_scope.txns_hash(&input.txns),
         inbox_msg_hash: x,
         inbox_msg_count: num_msgs as u32,
-        withdrawals: w_records,
+        withdrawals_root: withdrawals_root(&ctx.w_records),
+        withdrawals: ctx.w_records,
+        total_fees: summary.total_fees,
     };
 
     input.update(header.hash());
+    notify(&mut input.observers, &events);
 
-    Ok(header)
+    Ok((header, summary, stats))
 }
@@ -2,4 +2,19 @@
 pub mod common;
 pub mod l2_engine;
 pub mod l1_engine;
+pub mod bincode_config;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "abi")]
+pub mod abi;
+pub mod explorer;
+pub mod error;
+pub mod mempool;
+pub mod sequencer;
+pub mod events;
+#[cfg(any(test, feature = "metrics"))]
+pub mod metrics;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+mod engine_util;
 mod tests;
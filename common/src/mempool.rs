@@ -0,0 +1,63 @@
+use std::collections::{BTreeMap, BTreeSet};
+use crate::common::*;
+
+// Order a pool of transactions into a block. A sender's txns stay in sqn order, and
+// among the senders' next txns the one with the smallest tx id goes first. There is
+// no tip yet so every txn has the same priority and the id is the whole ordering;
+// a priority, once added, is compared before the id. Either way the same pool gives
+// the same block composition on every node, whatever order the txns arrived in.
+pub fn order_for_block(pool: Vec<Transaction>) -> Vec<Transaction> {
+    let num_txns = pool.len();
+    // per sender queue, sorted by sqn descending so the next txn is at the back.
+    // Unknown txns have no sender and queue alone under their own id
+    let mut queues: BTreeMap<AccountID, Vec<(u32, Hash, Transaction)>> = BTreeMap::new();
+    for t in pool {
+        let id = t.id();
+        let (sender, sqn) = t.sender_sqn().unwrap_or((id, 0));
+        queues.entry(sender).or_default().push((sqn, id, t));
+    }
+    let mut heads: BTreeSet<(Hash, AccountID)> = BTreeSet::new();
+    for (sender, q) in queues.iter_mut() {
+        q.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(b.1.cmp(&a.1)));
+        heads.insert((q.last().expect("queue").1, *sender));
+    }
+
+    let mut block = Vec::with_capacity(num_txns);
+    while let Some((_, sender)) = heads.pop_first() {
+        let q = queues.get_mut(&sender).expect("queue");
+        let (_, _, t) = q.pop().expect("queue");
+        block.push(t);
+        if let Some((_, id, _)) = q.last() {
+            heads.insert((*id, sender));
+        }
+    }
+    block
+}
+
+// The plain sqn fix-up for a sequencer that takes txns in any order: each sender's txns
+// sorted by sqn, then one txn per sender per round, senders in id order. Cheaper than
+// order_for_block as only unknown txns are hashed, but low sender ids always go first
+// in a round, so use order_for_block where the composition of a block must be fair.
+pub fn order_by_sqn(txns: Vec<Transaction>) -> Vec<Transaction> {
+    let num_txns = txns.len();
+    let mut queues: BTreeMap<AccountID, Vec<(u32, Transaction)>> = BTreeMap::new();
+    for t in txns {
+        let (sender, sqn) = t.sender_sqn().unwrap_or_else(|| (t.id(), 0));
+        queues.entry(sender).or_default().push((sqn, t));
+    }
+    // stable, so duplicate sqns keep their arrival order and fail in the engine as usual
+    let mut queues: Vec<_> = queues.into_values().map(|mut q| {
+        q.sort_by_key(|(sqn, _)| *sqn);
+        q.into_iter()
+    }).collect();
+
+    let mut block = Vec::with_capacity(num_txns);
+    while block.len() < num_txns {
+        for q in queues.iter_mut() {
+            if let Some((_, t)) = q.next() {
+                block.push(t);
+            }
+        }
+    }
+    block
+}
@@ -0,0 +1,30 @@
+use crate::common::*;
+
+// Running totals over the blocks an EngineData processed, for a host following a chain.
+// Kept on EngineData with the metrics feature, never serialized, and only written after
+// a block is done, so the guest doesn't have it and the headers don't depend on it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    pub blocks: u64,
+    pub txns: u64,
+    // charged by payments and withdrawals, collected or burned
+    pub fees: u128,
+    pub withdrawn: u128,
+    // most accounts the book held after any of the blocks
+    pub peak_accounts: usize,
+}
+
+impl Metrics {
+    // txns are the applied ones of the block, summary their BlockSummary
+    pub(crate) fn record(&mut self, book: &AccountBook, txns: &[Transaction], summary: &BlockSummary) {
+        self.blocks += 1;
+        self.txns += summary.num_txns as u64;
+        self.fees = txns.iter().fold(self.fees, |fees, t| fees.saturating_add(book.fee_of(t)));
+        self.withdrawn = self.withdrawn.saturating_add(summary.total_withdrawn);
+        self.peak_accounts = self.peak_accounts.max(book.get_num_accounts());
+    }
+
+    pub fn snapshot(&self) -> Metrics {
+        self.clone()
+    }
+}
@@ -0,0 +1,129 @@
+// Packing a block out of a pool of candidate txns, on the sequencer's side.
+use std::collections::BTreeMap;
+use crate::common::*;
+
+// Rough guest cost of a txn, dominated by the ECDSA checks and the account hashing.
+// Only the relative sizes matter for a budget, they are not measured cycle counts.
+pub const SIG_CYCLES: u64 = 500_000;
+pub const ACCOUNT_CYCLES: u64 = 20_000;
+
+pub fn estimated_cycles(t: &Transaction) -> u64 {
+    match t {
+        // the counterparty signs too
+        Transaction::Swap(_) => 2 * SIG_CYCLES + 2 * ACCOUNT_CYCLES,
+        Transaction::BatchPay(tx) => SIG_CYCLES + (1 + tx.payload.outputs.len() as u64) * ACCOUNT_CYCLES,
+        // inbox messages were checked on L1
+        Transaction::DepositL2(_) | Transaction::RollupCreateFundL2(_) => ACCOUNT_CYCLES,
+        Transaction::Unknown { .. } => 0,
+        _ => SIG_CYCLES + 2 * ACCOUNT_CYCLES,
+    }
+}
+
+// Builds the txns of the next block on a layer: the pool in mempool::order_for_block
+// order, each txn tried on a copy of the accounts the pool touches with the ones before
+// it applied. A txn that fails is tried again after the next ones, as long as a pass
+// applies something, e.g. a payment from an account another sender creates. What never
+// applies is left out, e.g. a sender's txn after a gap in its sqns or a spend over what
+// is left after its earlier ones, and so is everything past max_txns or max_cycles.
+// On L1 each of l1_engine's canonical groups is built in turn, so the engine's sort
+// keeps the order. Rollup updates need a receipt validator and are always left out.
+// Inbox messages (DepositL2, RollupCreateFundL2) are not reordered: they must be in the
+// pool in the order L1 enqueued them, the block starts with them in that order, up to
+// the first one that doesn't fit, as the rollup's inbox_msg_hash is taken over it.
+pub struct BlockBuilder<'a> {
+    book: &'a AccountBook,
+    layer: Layer,
+    height: u32,
+    max_txns: usize,
+    max_cycles: u64,
+}
+
+impl<'a> BlockBuilder<'a> {
+    // height is the sqn of the block being built, as the engine will process it
+    pub fn new(book: &'a AccountBook, layer: Layer, height: u32) -> BlockBuilder<'a> {
+        BlockBuilder { book, layer, height, max_txns: usize::MAX, max_cycles: u64::MAX }
+    }
+
+    pub fn max_txns(mut self, max_txns: usize) -> Self {
+        self.max_txns = max_txns;
+        self
+    }
+
+    pub fn max_cycles(mut self, max_cycles: u64) -> Self {
+        self.max_cycles = max_cycles;
+        self
+    }
+
+    pub fn build(&self, pool: Vec<Transaction>) -> Vec<Transaction> {
+        let (inbox, pool): (Vec<Transaction>, Vec<Transaction>) = pool.into_iter()
+            .partition(|t| matches!(t, Transaction::DepositL2(_) | Transaction::RollupCreateFundL2(_)));
+        let pool = crate::mempool::order_for_block(pool);
+        let ids: Vec<AccountID> = self.book.affected_ids(inbox.iter().chain(&pool)).into_iter().collect();
+        let mut scratch = self.book.partial_for(&ids);
+
+        let mut block = Vec::new();
+        let mut cycles = 0u64;
+        // no retries, a later message can't go before an earlier one
+        for t in inbox {
+            if block.len() == self.max_txns || !self.try_apply(&mut scratch, &t, &mut cycles) {
+                break;
+            }
+            block.push(t);
+        }
+
+        let ranks = match self.layer {
+            Layer::L1 => crate::l1_engine::canonical_ranks(&pool),
+            Layer::L2 => vec![0; pool.len()],
+        };
+        let mut groups: BTreeMap<u8, Vec<Transaction>> = BTreeMap::new();
+        for (t, rank) in pool.into_iter().zip(ranks) {
+            groups.entry(rank).or_default().push(t);
+        }
+        for mut pending in groups.into_values() {
+            loop {
+                let before = pending.len();
+                let mut retry = Vec::new();
+                for t in pending {
+                    if block.len() < self.max_txns && self.try_apply(&mut scratch, &t, &mut cycles) {
+                        block.push(t);
+                    } else {
+                        retry.push(t);
+                    }
+                }
+                pending = retry;
+                if pending.len() == before || block.len() == self.max_txns {
+                    break;
+                }
+            }
+        }
+        block
+    }
+
+    // applies t to scratch if it fits the cycle budget and is valid there
+    fn try_apply(&self, scratch: &mut AccountBook, t: &Transaction, cycles: &mut u64) -> bool {
+        let cost = estimated_cycles(t);
+        if cycles.saturating_add(cost) > self.max_cycles {
+            return false;
+        }
+        // tried on its own accounts, so a failed txn leaves scratch as it was
+        let ids: Vec<AccountID> = scratch.affected_ids([t]).into_iter().collect();
+        let mut trial = scratch.partial_for(&ids);
+        let Ok(changes) = trial.apply_transactions(std::slice::from_ref(t), self.layer, self.height) else {
+            return false;
+        };
+        // a closed account leaves its tombstone in place of the account
+        for (id, _) in &changes {
+            match trial.accounts.remove(id) {
+                Some(a) => scratch.accounts.insert(*id, a),
+                None => scratch.accounts.remove(id),
+            };
+            if let Some(sqn) = trial.closed.remove(id) {
+                scratch.closed.insert(*id, sqn);
+            }
+        }
+        scratch.interest_minted = trial.interest_minted;
+        scratch.update_tree(changes);
+        *cycles += cost;
+        true
+    }
+}
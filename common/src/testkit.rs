@@ -0,0 +1,17 @@
+use crate::common::*;
+
+// count payments of amount, senders taken round robin and recipients cycled on their own.
+// Each signer's sqn must be its account's sqn_expect, it is advanced past the txns made,
+// so the load passes sender_check in order and a later call continues where this one stopped.
+// Signed for chain_id, see ChainParams::chain_id
+pub fn generate_payment_load(signers: &mut [TxSigner], recipients: &[VerifyingKey], count: usize, amount: Balance, chain_id: u32) -> Vec<Transaction> {
+    assert!(!signers.is_empty() && !recipients.is_empty());
+    let mut txns = Vec::with_capacity(count);
+    for i in 0..count {
+        let to = recipients[i % recipients.len()];
+        let s = &mut signers[i % signers.len()];
+        txns.push(Transaction::Pay(Tx::new_on_chain(chain_id, s.pk, s.sqn, Payment { to, amount, expected_total: None }, &mut s.sk)));
+        s.sqn += 1;
+    }
+    txns
+}
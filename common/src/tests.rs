@@ -1,9 +1,11 @@
 #[cfg(test)]
 mod tests {
     use crate::common::*;
+    use crate::bincode_config;
     use k256::ecdsa::SigningKey;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
     use rand::rngs::OsRng;
+    use sha2::Digest;
 
     const PAY_AMOUNT: u128 = 10u128;
 
@@ -24,12 +26,26 @@ mod tests {
             for _ in 0..num_alices {
                 alices.push(TxSigner::new(SigningKey::random(&mut csprng)));
             }
-            let l1 = EngineData::new(faucet.pk, GENESIS_AMOUNT);
-            let l2 = EngineData::new(faucet.pk, 0);
+            let l1 = EngineData::new(faucet.pk, GENESIS_AMOUNT, Layer::L1);
+            let l2 = EngineData::new(faucet.pk, 0, Layer::L2);
             Genesis { faucet, rollup, alices, l1, l2 }
         }
     }
 
+    // signed for the chains of the Genesis engines, see Layer::default_chain_id
+    fn l1_tx<T: TxPayload>(sender: VerifyingKey, sqn: u32, payload: T, sk: &mut SigningKey) -> Tx<T> {
+        Tx::new_on_chain(L1_CHAIN_ID, sender, sqn, payload, sk)
+    }
+
+    fn l2_tx<T: TxPayload>(sender: VerifyingKey, sqn: u32, payload: T, sk: &mut SigningKey) -> Tx<T> {
+        Tx::new_on_chain(L2_CHAIN_ID, sender, sqn, payload, sk)
+    }
+
+    // signed by a delegate of the sender, on L1
+    fn delegated<T: TxPayload>(sender: VerifyingKey, sqn: u32, payload: T, delegate_key: &mut SigningKey) -> Tx<T> {
+        Tx::new_delegated(L1_CHAIN_ID, sender, sqn, payload, delegate_key)
+    }
+
     // run the test with the following command, note the manifest-path is relative
     // RUST_BACKTRACE=1 cargo test --lib tests::process_works --manifest-path ./common/Cargo.toml
     #[test]
@@ -45,8 +61,8 @@ mod tests {
         let mut to_update = HashMap::new();
         let alices = &genesis.alices;
         for i in 0..num_alices {
-            let tx = Tx::new(faucet_pk.clone(), i as u32, Payment { to: alices[i].pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
-            let r = book.process_payment(&tx).unwrap();
+            let tx = l1_tx(faucet_pk.clone(), i as u32, Payment { to: alices[i].pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+            let r = book.process_payment(&tx, 0).unwrap();
             for (k, v) in r {
                 to_update.insert(k, v);
             }
@@ -67,8 +83,8 @@ mod tests {
         let mut to_update = HashMap::new();
         let alices = &mut genesis.alices;
         for alice in alices {
-            let tx = Tx::new(alice.pk.clone(), 0u32, Payment { to: faucet_pk.clone(), amount: PAY_AMOUNT }, &mut alice.sk);
-            let r = book.process_payment(&tx).unwrap();
+            let tx = l1_tx(alice.pk.clone(), 0u32, Payment { to: faucet_pk.clone(), amount: Balance(PAY_AMOUNT), expected_total: None }, &mut alice.sk);
+            let r = book.process_payment(&tx, 0).unwrap();
             for (k, v) in r {
                 to_update.insert(k, v);
             }
@@ -87,6 +103,12 @@ mod tests {
         assert!(book.verify_partial_root());
     }
 
+    // L1 plus L2, the rollup's L1 balance backs the L2 balances so it is counted once
+    fn supply(g: &Genesis) -> u128 {
+        let backing = g.l1.account_book.balance_of(&pk_to_hash(&g.rollup.pk)).unwrap_or(0);
+        g.l1.account_book.total_supply().unwrap() - backing + g.l2.account_book.total_supply().unwrap()
+    }
+
     #[test]
     fn deposit_withdrawal_works() {
         let num_alices = 0usize;
@@ -94,9 +116,9 @@ mod tests {
         let faucet_pk = &genesis.faucet.pk;
 
         // L1 deposit
-        let tx = Tx::new(faucet_pk.clone(), 0, CreateRollupAccount { rollup_pk: genesis.rollup.pk.clone() }, &mut genesis.faucet.sk);
+        let tx = l1_tx(faucet_pk.clone(), 0, CreateRollupAccount { rollup_pk: genesis.rollup.pk.clone(), bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
         genesis.l1.txns.push(Transaction::RollupCreate(tx));
-        let tx = Tx::new(faucet_pk.clone(), 1, L1ToL2Deposit { rollup_pk: genesis.rollup.pk.clone(), amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+        let tx = l1_tx(faucet_pk.clone(), 1, L1ToL2Deposit { rollup_pk: genesis.rollup.pk.clone(), amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
         let deposit_tx_id = tx.id();
         genesis.l1.txns.push(Transaction::Deposit(tx.clone()));
         let bh1 = crate::l1_engine::process(&mut genesis.l1, |_| Ok(BlockHeaderL2::default()));
@@ -105,7 +127,8 @@ mod tests {
 
         assert!(genesis.l1.account_book.account_hash_verify(&faucet_pk, |a| a.sqn_expect == 2u32 && a.amount == GENESIS_AMOUNT - PAY_AMOUNT && a.owner == *faucet_pk));
         assert!(genesis.l1.account_book.account_hash_verify(&genesis.rollup.pk, |a| a.sqn_expect == 0u32 && a.amount == PAY_AMOUNT && a.owner == genesis.rollup.pk &&
-            a.rollup.as_ref().is_some_and(|ru| ru.header_hash == Hash::default() && !ru.inbox.is_empty() && ru.inbox[0] == deposit_tx_id && ru.sqn == 0)));
+            a.rollup.as_ref().is_some_and(|ru| ru.header_hash == Hash::default() && !ru.inbox.is_empty() && ru.inbox[0].0 == deposit_tx_id && ru.sqn == 0)));
+        assert_eq!(genesis.l1.account_book.assert_conservation(GENESIS_AMOUNT), Ok(()));
 
         // L2 deposit
         genesis.l2.txns.push(Transaction::DepositL2(tx));
@@ -113,39 +136,3634 @@ mod tests {
         assert!(bh2.is_ok());
         assert!(genesis.l2.txns.is_empty());
         assert!(genesis.l2.account_book.account_hash_verify(&faucet_pk, |a| a.sqn_expect == 0u32 && a.amount == PAY_AMOUNT && a.owner == *faucet_pk));
+        assert_eq!(supply(&genesis), GENESIS_AMOUNT);
 
         // update L2 state to L1 (no zk proof)
-        let bh2 = bh2.unwrap();
-        let data = bincode::serialize(&bh2).unwrap();
-        let tx = Tx::new(genesis.rollup.pk.clone(), 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        let (bh2, _) = bh2.unwrap();
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(genesis.rollup.pk.clone(), 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
         genesis.l1.txns.push(Transaction::RollupUpdate(tx));
         let bh1 = crate::l1_engine::process(&mut genesis.l1, |data| {
-            let header: BlockHeaderL2 = bincode::deserialize(data).unwrap();
+            let header: BlockHeaderL2 = bincode_config::deserialize(data).unwrap();
             Ok(header)
         });
         assert!(bh1.is_ok());
         assert!(genesis.l1.account_book.account_hash_verify(&genesis.rollup.pk, |a| a.sqn_expect == 1u32 && a.amount == PAY_AMOUNT && a.owner == genesis.rollup.pk &&
             a.rollup.as_ref().is_some_and(|ru| ru.header_hash == bh2.hash() && ru.inbox.is_empty() && ru.sqn == 1)));
+        assert_eq!(supply(&genesis), GENESIS_AMOUNT);
 
         // withdrawal
-        let tx = Tx::new(faucet_pk.clone(), 0, L2ToL1Withdrawal { amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+        let tx = l2_tx(faucet_pk.clone(), 0, L2ToL1Withdrawal { amount: Balance(PAY_AMOUNT), to: None }, &mut genesis.faucet.sk);
         genesis.l2.txns.push(Transaction::Withdrawal(tx));
         let bh2 = crate::l2_engine::process(&mut genesis.l2);
         assert!(bh2.is_ok());
         assert!(genesis.l2.account_book.account_hash_verify(&faucet_pk, |a| a.sqn_expect == 1u32 && a.amount == 0 && a.owner == *faucet_pk));
+        // in flight to L1 until the update below
+        assert_eq!(genesis.l1.account_book.assert_conservation(GENESIS_AMOUNT), Ok(()));
 
         // update L2 state to L1 (no zk proof), to see withdrawal effect
-        let bh2 = bh2.unwrap();
+        let (bh2, _) = bh2.unwrap();
         assert!(!bh2.withdrawals.is_empty() && bh2.withdrawals[0].to == *faucet_pk && bh2.withdrawals[0].amount == PAY_AMOUNT && bh2.sqn == 1 && bh2.inbox_msg_count == 0);
-        let data = bincode::serialize(&bh2).unwrap();
-        let tx = Tx::new(genesis.rollup.pk.clone(), 1, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(genesis.rollup.pk.clone(), 1, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
         genesis.l1.txns.push(Transaction::RollupUpdate(tx));
         let bh1 = crate::l1_engine::process(&mut genesis.l1, |data| {
-            let header: BlockHeaderL2 = bincode::deserialize(data).unwrap();
+            let header: BlockHeaderL2 = bincode_config::deserialize(data).unwrap();
             Ok(header)
         });
         assert!(bh1.is_ok());
         assert!(genesis.l1.account_book.account_hash_verify(&genesis.rollup.pk, |a| a.sqn_expect == 2u32 && a.amount == 0 && a.owner == genesis.rollup.pk &&
             a.rollup.as_ref().is_some_and(|ru| ru.header_hash == bh2.hash() && ru.inbox.is_empty() && ru.sqn == 2)));
+        assert_eq!(supply(&genesis), GENESIS_AMOUNT);
+        assert_eq!(genesis.l1.account_book.assert_conservation(GENESIS_AMOUNT), Ok(()));
+        assert_eq!(genesis.l1.account_book.assert_conservation(GENESIS_AMOUNT + 1), Err(EngineError::SupplyMismatch));
+    }
+
+    #[test]
+    fn create_rollup_collision_rejected() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let book = &mut genesis.l1.account_book;
+
+        // rollup key pointing at the sender's own account
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk: faucet_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_create_rollup_account(&tx, 0).unwrap_err(), "rollup_self");
+
+        // rollup key colliding with a plain account
+        let tx = l1_tx(faucet_pk, 0, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        book.process_payment(&tx, 0).unwrap();
+        let tx = l1_tx(faucet_pk, 1, CreateRollupAccount { rollup_pk: alice_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_create_rollup_account(&tx, 0).unwrap_err(), "account_exist");
+
+        // rollup key colliding with an existing rollup
+        let tx = l1_tx(faucet_pk, 1, CreateRollupAccount { rollup_pk: genesis.rollup.pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        assert!(book.process_create_rollup_account(&tx, 0).is_ok());
+        let tx = l1_tx(faucet_pk, 2, CreateRollupAccount { rollup_pk: genesis.rollup.pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_create_rollup_account(&tx, 0).unwrap_err(), "exist");
+    }
+
+    #[test]
+    fn block_summary_matches_host() {
+        let mut genesis = Genesis::new(3);
+        let faucet_pk = genesis.faucet.pk;
+        genesis.l2.account_book.set_params(ChainParams { fee_policy: FeePolicy::Flat(1), ..ChainParams::for_layer(Layer::L2) });
+
+        // fund the l2 faucet, then pay and withdraw
+        let tx = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk: genesis.rollup.pk, amount: PAY_AMOUNT * 10 }, &mut genesis.faucet.sk);
+        genesis.l2.txns.push(Transaction::DepositL2(tx));
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let tx = l2_tx(faucet_pk, i as u32, Payment { to: alice.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+            genesis.l2.txns.push(Transaction::Pay(tx));
+        }
+        let tx = l2_tx(faucet_pk, 3, L2ToL1Withdrawal { amount: Balance(PAY_AMOUNT), to: None }, &mut genesis.faucet.sk);
+        genesis.l2.txns.push(Transaction::Withdrawal(tx));
+
+        let host_summary = BlockSummary::new(&genesis.l2.txns, &genesis.l2.account_book).unwrap();
+        let (header, summary) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        assert_eq!(summary, host_summary);
+        assert_eq!(summary, BlockSummary { num_txns: 5, total_paid: PAY_AMOUNT * 3, total_deposited: PAY_AMOUNT * 10, total_withdrawn: PAY_AMOUNT, total_fees: 4 });
+        assert_eq!(summary.total_fees, header.total_fees);
+    }
+
+    #[test]
+    fn tx_valid_until_works() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let book = &mut genesis.l1.account_book;
+
+        // expired: included after its last valid block
+        let tx = Tx::new_signed(faucet_pk, 0, L1_CHAIN_ID, Some(4), None, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        assert!(tx.sig_verify());
+        assert_eq!(book.process_payment(&tx, 5).unwrap_err(), "expired");
+
+        // within its window, including the last valid block
+        assert!(book.process_payment(&tx, 4).is_ok());
+        let tx = Tx::new_signed(faucet_pk, 1, L1_CHAIN_ID, Some(4), None, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        assert!(book.process_payment(&tx, 2).is_ok());
+
+        // the bound is signed over
+        let mut tx = Tx::new_signed(faucet_pk, 2, L1_CHAIN_ID, Some(4), None, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        tx.valid_until = Some(100);
+        assert_eq!(book.process_payment(&tx, 5).unwrap_err(), "sig");
+    }
+
+    #[test]
+    fn block_header_l2_encoding_is_pinned() {
+        let header = BlockHeaderL2 {
+            parent: [1u8; 32],
+            state_root: [2u8; 32],
+            sqn: 3,
+            timestamp: 7,
+            txns_hash: [4u8; 32],
+            inbox_msg_hash: [5u8; 32],
+            inbox_msg_count: 6,
+            withdrawals: vec![],
+            total_fees: 8,
+            withdrawals_root: [9u8; 32],
+        };
+        let mut golden = Vec::new();
+        golden.extend([1u8; 32]);
+        golden.extend([2u8; 32]);
+        golden.extend([3u8, 0, 0, 0]);
+        golden.extend([7u8, 0, 0, 0, 0, 0, 0, 0]);
+        golden.extend([4u8; 32]);
+        golden.extend([5u8; 32]);
+        golden.extend([6u8, 0, 0, 0]);
+        golden.extend([0u8; 8]);
+        golden.extend([8u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        golden.extend([9u8; 32]);
+
+        let data = bincode_config::serialize(&header).unwrap();
+        assert_eq!(data, golden);
+        assert_eq!(data, bincode::serialize(&header).unwrap());
+        let decoded: BlockHeaderL2 = bincode_config::deserialize(&data).unwrap();
+        assert_eq!(decoded.hash(), header.hash());
+    }
+
+    #[test]
+    fn create_and_fund_rollup_works() {
+        // two steps: create, then deposit
+        let mut separate = Genesis::new(0);
+        let faucet_pk = separate.faucet.pk;
+        let rollup_pk = separate.rollup.pk;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut separate.faucet.sk);
+        separate.l1.txns.push(Transaction::RollupCreate(tx));
+        let tx = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut separate.faucet.sk);
+        separate.l1.txns.push(Transaction::Deposit(tx));
+        assert!(crate::l1_engine::process(&mut separate.l1, |_| Ok(BlockHeaderL2::default())).is_ok());
+
+        // one step, same keys
+        let mut combined = Genesis::new(0);
+        combined.faucet = separate.faucet.clone();
+        combined.rollup = separate.rollup.clone();
+        combined.l1 = EngineData::new(faucet_pk, GENESIS_AMOUNT, Layer::L1);
+        combined.l2 = EngineData::new(faucet_pk, 0, Layer::L2);
+        let tx = l1_tx(faucet_pk, 0, CreateAndFundRollup { rollup_pk, amount: PAY_AMOUNT, bond: 0 }, &mut combined.faucet.sk);
+        let tx_id = tx.id();
+        combined.l1.txns.push(Transaction::RollupCreateFund(tx.clone()));
+        assert!(crate::l1_engine::process(&mut combined.l1, |_| Ok(BlockHeaderL2::default())).is_ok());
+
+        let s_rollup = separate.l1.account_book.get_account(&pk_to_hash(&rollup_pk)).unwrap().clone();
+        let c_rollup = combined.l1.account_book.get_account(&pk_to_hash(&rollup_pk)).unwrap().clone();
+        assert_eq!(s_rollup.amount, c_rollup.amount);
+        let (s_ru, c_ru) = (s_rollup.rollup.unwrap(), c_rollup.rollup.unwrap());
+        assert_eq!(s_ru.inbox.len(), c_ru.inbox.len());
+        assert_eq!(c_ru.inbox[0].0, tx_id);
+        let s_faucet = separate.l1.account_book.get_account(&pk_to_hash(&faucet_pk)).unwrap().amount;
+        assert!(combined.l1.account_book.account_hash_verify(&faucet_pk, |a| a.amount == s_faucet && a.sqn_expect == 1));
+
+        // relayed to L2 it is consumed like a deposit
+        combined.l2.txns.push(Transaction::RollupCreateFundL2(tx));
+        let (bh2, _) = crate::l2_engine::process(&mut combined.l2).unwrap();
+        assert!(combined.l2.account_book.account_hash_verify(&faucet_pk, |a| a.amount == PAY_AMOUNT));
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut combined.rollup.sk);
+        combined.l1.txns.push(Transaction::RollupUpdate(tx));
+        assert!(crate::l1_engine::process(&mut combined.l1, |data| bincode_config::deserialize(data)).is_ok());
+        assert!(combined.l1.account_book.account_hash_verify(&rollup_pk, |a| a.rollup.as_ref().is_some_and(|ru| ru.inbox.is_empty() && ru.sqn == 1)));
+
+        // all or nothing: the rollup exists, or the sender can't pay
+        let book = &mut combined.l1.account_book;
+        let root = *book.root();
+        let tx = l1_tx(faucet_pk, 1, CreateAndFundRollup { rollup_pk, amount: PAY_AMOUNT, bond: 0 }, &mut combined.faucet.sk);
+        assert_eq!(book.process_create_and_fund_rollup(&tx, 0).unwrap_err(), "exist");
+        let other = TxSigner::new(SigningKey::random(&mut OsRng));
+        let tx = l1_tx(faucet_pk, 1, CreateAndFundRollup { rollup_pk: other.pk, amount: GENESIS_AMOUNT, bond: 0 }, &mut combined.faucet.sk);
+        assert_eq!(book.process_create_and_fund_rollup(&tx, 0).unwrap_err(), "sender");
+        assert!(book.get_account(&pk_to_hash(&other.pk)).is_none());
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.sqn_expect == 1));
+        assert_eq!(*book.root(), root);
+    }
+
+    #[test]
+    fn verify_partial_root_empty_book() {
+        // empty partial of a nonempty book can't show the root is right
+        let genesis = Genesis::new(2);
+        let partial = genesis.l1.account_book.get_partial(&vec![]);
+        assert_eq!(partial.get_num_accounts(), 0);
+        assert!(!partial.verify_partial_root());
+
+        // empty partial of the empty book
+        let empty = AccountBook::new_batch(vec![], 0);
+        let partial = empty.get_partial(&vec![]);
+        assert_eq!(partial.get_num_accounts(), 0);
+        assert!(partial.verify_partial_root());
+    }
+
+    #[test]
+    fn engines_agree_on_payments() {
+        let mut genesis = Genesis::new(4);
+        let faucet_pk = genesis.faucet.pk;
+        genesis.l2 = EngineData::new(faucet_pk, GENESIS_AMOUNT, Layer::L2);
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let pay = Payment { to: alice.pk, amount: Balance(PAY_AMOUNT), expected_total: None };
+            genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, i as u32, pay.clone(), &mut genesis.faucet.sk)));
+            genesis.l2.txns.push(Transaction::Pay(l2_tx(faucet_pk, i as u32, pay, &mut genesis.faucet.sk)));
+        }
+        let (bh1, _) = crate::l1_engine::process(&mut genesis.l1, |_| Ok(BlockHeaderL2::default())).unwrap();
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        assert_eq!(bh1.state_root, bh2.state_root);
+        assert_eq!(genesis.l1.account_book.root(), genesis.l2.account_book.root());
+    }
+
+    #[test]
+    fn swap_works() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let book = &mut genesis.l1.account_book;
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let tx = l1_tx(faucet_pk, i as u32, Payment { to: alice.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+            book.process_payment(&tx, 0).unwrap();
+        }
+        let (alice, bob) = (genesis.alices[0].clone(), &mut genesis.alices[1]);
+
+        // alice gives 3 and gets 7 from bob
+        let swap = Swap::new(&alice.pk, 0, L1_CHAIN_ID, None, 3, 7, &mut bob.sk);
+        let mut alice_sk = alice.sk.clone();
+        let tx = l1_tx(alice.pk, 0, swap, &mut alice_sk);
+        let r = book.process_swap(&tx, 0).unwrap();
+        book.update_tree(r);
+        assert!(book.account_hash_verify(&alice.pk, |a| a.amount == PAY_AMOUNT + 4 && a.sqn_expect == 1));
+        assert!(book.account_hash_verify(&bob.pk, |a| a.amount == PAY_AMOUNT - 4 && a.sqn_expect == 0));
+
+        // bob can't give what he doesn't have
+        let swap = Swap::new(&alice.pk, 1, L1_CHAIN_ID, None, 1, PAY_AMOUNT, &mut bob.sk);
+        let tx = l1_tx(alice.pk, 1, swap, &mut alice_sk);
+        assert_eq!(book.process_swap(&tx, 0).unwrap_err(), "counterparty");
+
+        // terms signed by someone else than the counterparty
+        let mut swap = Swap::new(&alice.pk, 1, L1_CHAIN_ID, None, 1, 2, &mut genesis.faucet.sk);
+        swap.counterparty = bob.pk;
+        let tx = l1_tx(alice.pk, 1, swap, &mut alice_sk);
+        assert_eq!(book.process_swap(&tx, 0).unwrap_err(), "counter_sig");
+
+        // counterparty signature bound to the sender's sqn
+        let swap = Swap::new(&alice.pk, 0, L1_CHAIN_ID, None, 1, 2, &mut bob.sk);
+        let tx = l1_tx(alice.pk, 1, swap, &mut alice_sk);
+        assert_eq!(book.process_swap(&tx, 0).unwrap_err(), "counter_sig");
+        // and to the chain and expiry of the sender's tx
+        let swap = Swap::new(&alice.pk, 1, L2_CHAIN_ID, None, 1, 2, &mut bob.sk);
+        let tx = l1_tx(alice.pk, 1, swap, &mut alice_sk);
+        assert_eq!(book.process_swap(&tx, 0).unwrap_err(), "counter_sig");
+        let swap = Swap::new(&alice.pk, 1, L1_CHAIN_ID, None, 1, 2, &mut bob.sk);
+        let tx: Tx<Swap> = Tx::new_signed(alice.pk, 1, L1_CHAIN_ID, Some(10), None, swap, &mut alice_sk);
+        assert_eq!(book.process_swap(&tx, 0).unwrap_err(), "counter_sig");
+        assert!(book.account_hash_verify(&alice.pk, |a| a.amount == PAY_AMOUNT + 4 && a.sqn_expect == 1));
+    }
+
+    #[test]
+    fn partial_for_matches_get_partial() {
+        let mut genesis = Genesis::new(3);
+        let book = &mut genesis.l2.account_book;
+        for alice in &genesis.alices {
+            let tx = l1_tx(alice.pk, 0, L1ToL2Deposit { rollup_pk: genesis.rollup.pk, amount: PAY_AMOUNT }, &mut alice.sk.clone());
+            let r = book.process_deposit_l2(&tx).unwrap();
+            book.update_tree(r);
+        }
+        let mut txns = vec![];
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let to = genesis.alices[(i + 1) % genesis.alices.len()].pk;
+            txns.push(Transaction::Pay(l2_tx(alice.pk, 0, Payment { to, amount: Balance(1), expected_total: None }, &mut alice.sk.clone())));
+        }
+
+        let ids = book.get_affected_account_ids(&txns);
+        let by_ids = book.partial_for(&ids);
+        let by_txns = book.get_partial(&txns);
+        assert_eq!(by_ids.get_num_accounts(), genesis.alices.len());
+        assert_eq!(bincode_config::serialize(&by_ids).unwrap(), bincode_config::serialize(&by_txns).unwrap());
+        assert!(by_ids.verify_partial_root());
+    }
+
+    #[test]
+    fn unknown_transaction_variant_decodes() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let tx = l1_tx(faucet_pk, 0, Payment { to: genesis.alices[0].pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        let txns = vec![Transaction::Pay(tx.clone())];
+        let known = bincode_config::serialize(&txns).unwrap();
+        let decoded: Vec<Transaction> = bincode_config::deserialize(&known).unwrap();
+        assert!(matches!(&decoded[0], Transaction::Pay(t) if t.id() == tx.id()));
+
+        // a block from a newer node: the payment, then a variant this node has never heard of
+        let future: Vec<Vec<u8>> = vec![Transaction::Pay(tx.clone()).encode().unwrap(), vec![TX_ENCODING_VERSION, 200, 1, 2, 3]];
+        let data = bincode_config::serialize(&future).unwrap();
+        // the serde form of a txn is its encode bytes
+        assert_eq!(bincode_config::serialize(&future[..1].to_vec()).unwrap(), known);
+        let decoded: Vec<Transaction> = bincode_config::deserialize(&data).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert!(matches!(&decoded[1], Transaction::Unknown { tag: 200, bytes } if *bytes == vec![1, 2, 3]));
+        assert_eq!(bincode_config::serialize(&decoded).unwrap(), data);
+        // the block's txns hash covers the unknown txn by its id, like any other
+        let mut hasher = DefaultHasher::new();
+        decoded.iter().for_each(|t| hasher.update(t.id()));
+        assert_eq!(tx_set_hash(&decoded), hasher.finish());
+
+        genesis.l1.txns = decoded.clone();
+        assert_eq!(crate::l1_engine::process(&mut genesis.l1, |_| Ok(BlockHeaderL2::default())).unwrap_err(), "unknown tx");
+        // the l2 faucet has nothing to pay with, feed only the unknown one
+        genesis.l2.txns = decoded[1..].to_vec();
+        assert_eq!(crate::l2_engine::process(&mut genesis.l2).unwrap_err(), "unknown tx");
+    }
+
+    #[test]
+    fn invalid_rollup_update_slashes_bond() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let bond = 1_000u128;
+        genesis.l1.account_book.set_params(ChainParams { rollup_slash_bps: 1_000, ..ChainParams::for_layer(Layer::L1) });
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Ok(BlockHeaderL2::default())).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&faucet_pk, |a| a.amount == GENESIS_AMOUNT - bond));
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.amount == 0 && a.rollup.as_ref().is_some_and(|ru| ru.bond == bond)));
+
+        // authenticated, but the header doesn't extend the rollup: 10% of the bond burned, slot used
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        let mut forged = bh2.clone();
+        forged.parent = [7u8; 32];
+        let data = bincode_config::serialize(&forged).unwrap();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        genesis.l1.txns.push(Transaction::RollupUpdate(tx));
+        let (_, _, stats) = crate::l1_engine::process_lenient(&mut genesis.l1, |data| bincode_config::deserialize(data)).unwrap();
+        assert_eq!(stats.receipts[0].outcome, TxOutcome::Slashed(EngineError::BadParent));
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.sqn_expect == 1 &&
+            a.rollup.as_ref().is_some_and(|ru| ru.bond == bond - 100 && ru.sqn == 0 && ru.header_hash == Hash::default())));
+
+        // a valid one costs nothing
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(rollup_pk, 1, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        genesis.l1.txns.push(Transaction::RollupUpdate(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.sqn_expect == 2 &&
+            a.rollup.as_ref().is_some_and(|ru| ru.bond == bond - 100 && ru.sqn == 1 && ru.header_hash == bh2.hash())));
+
+        // without slashing the invalid update is just rejected
+        genesis.l1.account_book.set_params(ChainParams::for_layer(Layer::L1));
+        let data = bincode_config::serialize(&forged).unwrap();
+        let tx = l1_tx(rollup_pk, 2, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        assert_eq!(genesis.l1.account_book.process_rollup_state_update(&tx, 0, |data| bincode_config::deserialize(data)).unwrap_err(), "parent");
+    }
+
+    #[test]
+    fn rollups_are_created_with_the_min_bond() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let book = &mut genesis.l1.account_book;
+        book.set_params(ChainParams { min_rollup_bond: 100, ..ChainParams::for_layer(Layer::L1) });
+        let sk = &mut genesis.faucet.sk;
+        let create = |bond, sk: &mut SigningKey| l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond, genesis_inbox: vec![], genesis_amount: 0 }, sk);
+        let fund = |bond, sk: &mut SigningKey| l1_tx(faucet_pk, 0, CreateAndFundRollup { rollup_pk, amount: PAY_AMOUNT, bond }, sk);
+        assert_eq!(book.process_create_rollup_account(&create(99, sk), 0).unwrap_err(), EngineError::BondTooLow);
+        assert_eq!(book.process_create_and_fund_rollup(&fund(99, sk), 0).unwrap_err(), EngineError::BondTooLow);
+
+        // the bond is paid on top of the deposit, and kept in the rollup state
+        let r = book.process_create_and_fund_rollup(&fund(100, sk), 0).unwrap();
+        book.update_tree(r);
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.amount == GENESIS_AMOUNT - PAY_AMOUNT - 100));
+        assert!(book.account_hash_verify(&rollup_pk, |a| a.amount == PAY_AMOUNT && a.rollup.as_ref().is_some_and(|ru| ru.bond == 100)));
+    }
+
+    #[test]
+    fn partial_keeps_rollup_inbox() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        let mut deposits = vec![];
+        for i in 1..4 {
+            let tx = l1_tx(faucet_pk, i, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+            deposits.push(tx.id());
+            genesis.l2.txns.push(Transaction::DepositL2(tx.clone()));
+            genesis.l1.txns.push(Transaction::Deposit(tx));
+        }
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Ok(BlockHeaderL2::default())).is_ok());
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+
+        // the block settling the rollup, as handed to a prover
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        genesis.l1.txns.push(Transaction::RollupUpdate(tx));
+        let mut partial = genesis.l1.get_partial();
+        assert!(partial.account_book.verify_partial_root());
+        let ru = partial.account_book.get_account(&pk_to_hash(&rollup_pk)).unwrap().rollup.clone().unwrap();
+        assert_eq!(ru.inbox.iter().map(|m| m.0).collect::<Vec<_>>(), deposits);
+
+        // and the partial settles it exactly like the full book
+        let (p_bh1, _) = crate::l1_engine::process(&mut partial, |data| bincode_config::deserialize(data)).unwrap();
+        let (bh1, _) = crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).unwrap();
+        assert_eq!(p_bh1.state_root, bh1.state_root);
+    }
+
+    #[test]
+    fn explorer_queries() {
+        let mut genesis = Genesis::new(7);
+        let faucet_pk = genesis.faucet.pk;
+        let book = &mut genesis.l1.account_book;
+        let mut to_update = vec![];
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let tx = l1_tx(faucet_pk, i as u32, Payment { to: alice.pk, amount: Balance(PAY_AMOUNT * (i as u128 + 1)), expected_total: None }, &mut genesis.faucet.sk);
+            to_update.extend(book.process_payment(&tx, 0).unwrap());
+        }
+        let tx = l1_tx(faucet_pk, 7, CreateRollupAccount { rollup_pk: genesis.rollup.pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        to_update.extend(book.process_create_rollup_account(&tx, 0).unwrap());
+        book.update_tree(to_update);
+
+        let explorer = crate::explorer::Explorer::new(book);
+        let aid = pk_to_hash(&genesis.alices[2].pk);
+        assert_eq!(explorer.balance(&aid), Some(Balance(PAY_AMOUNT * 3)));
+        assert_eq!(explorer.account(&aid).unwrap().owner, genesis.alices[2].pk);
+        assert!(explorer.balance(&[0u8; 32]).is_none());
+        assert!(explorer.proof(&aid).unwrap().verify(book.root()));
+
+        let stats = explorer.stats();
+        assert_eq!(stats.root, *book.root());
+        assert_eq!(stats.num_accounts, 9);
+        assert_eq!(stats.num_rollups, 1);
+        assert_eq!(stats.total_balance, GENESIS_AMOUNT);
+        let keys = random_keys(2);
+        let overflowing = AccountBookBuilder::new().with_account(keys[0], u128::MAX, None).with_account(keys[1], 1, None).build();
+        assert_eq!(crate::explorer::Explorer::new(&overflowing).stats().total_balance, u128::MAX);
+
+        // pages of 4 cover all 9 accounts once, in id order
+        let mut seen = vec![];
+        let mut cursor = None;
+        loop {
+            let (page, next) = explorer.accounts_page(cursor, 4);
+            assert!(page.len() <= 4);
+            seen.extend(page.into_iter().map(|(id, _)| *id));
+            if next.is_none() {
+                break;
+            }
+            cursor = next;
+        }
+        let mut sorted = seen.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(seen, sorted);
+        assert_eq!(seen.len(), 9);
+    }
+
+    #[test]
+    fn rollup_state_hash_is_pinned() {
+        let ru = RollupState {
+            inbox: vec![([1u8; 32], 6), ([2u8; 32], 7)].into(),
+            header_hash: [3u8; 32],
+            sqn: 4,
+            bond: 5,
+            pending_deposits: 13,
+            last_timestamp: 14,
+        };
+        let mut hasher = DefaultHasher::new();
+        ru.hash(&mut hasher);
+        let x: Hash = hasher.finalize().as_slice().try_into().unwrap();
+        assert_eq!(x, [
+            0x97, 0x48, 0x6a, 0xc8, 0x74, 0xc6, 0x40, 0xc1, 0x64, 0x07, 0xb6, 0xa3, 0xc9, 0xb8, 0x47, 0xdc,
+            0x25, 0x21, 0xfc, 0xd2, 0xe2, 0x37, 0x1b, 0x01, 0x8e, 0xeb, 0xf6, 0x69, 0xda, 0x70, 0x06, 0x6d,
+        ]);
+    }
+
+    #[test]
+    fn fee_policies_work() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let (alice, collector) = (genesis.alices[0].clone(), genesis.alices[1].pk);
+        let book = &mut genesis.l2.account_book;
+        let tx = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk: genesis.rollup.pk, amount: 100_000 }, &mut genesis.faucet.sk);
+        book.process_deposit_l2(&tx).unwrap();
+
+        // flat
+        book.set_params(ChainParams { fee_policy: FeePolicy::Flat(3), fee_collector: Some(collector), ..ChainParams::for_layer(Layer::L2) });
+        let tx = l2_tx(faucet_pk, 0, Payment { to: alice.pk, amount: Balance(10_000), expected_total: None }, &mut genesis.faucet.sk);
+        let r = book.process_payment(&tx, 0).unwrap();
+        book.update_tree(r);
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.amount == 100_000 - 10_003));
+        assert!(book.account_hash_verify(&alice.pk, |a| a.amount == 10_000));
+        assert!(book.account_hash_verify(&collector, |a| a.amount == 3));
+
+        // 30 bps, exact and rounded up
+        book.set_params(ChainParams { fee_policy: FeePolicy::BasisPoints(30), fee_collector: Some(collector), ..ChainParams::for_layer(Layer::L2) });
+        let tx = l2_tx(faucet_pk, 1, Payment { to: alice.pk, amount: Balance(10_000), expected_total: None }, &mut genesis.faucet.sk);
+        let r = book.process_payment(&tx, 0).unwrap();
+        book.update_tree(r);
+        assert!(book.account_hash_verify(&collector, |a| a.amount == 3 + 30));
+        let tx = l2_tx(faucet_pk, 2, Payment { to: alice.pk, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk);
+        let r = book.process_payment(&tx, 0).unwrap();
+        book.update_tree(r);
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.amount == 100_000 - 10_003 - 10_030 - 2));
+        assert!(book.account_hash_verify(&collector, |a| a.amount == 3 + 30 + 1));
+
+        // withdrawals pay it too, the record carries the amount only
+        let mut w_records = vec![];
+        let tx = l2_tx(alice.pk, 0, L2ToL1Withdrawal { amount: Balance(10_000), to: None }, &mut alice.sk.clone());
+        let r = book.process_withdrawal(&tx, 0, &mut w_records).unwrap();
+        book.update_tree(r);
+        assert!(book.account_hash_verify(&alice.pk, |a| a.amount == 20_001 - 10_030));
+        assert!(book.account_hash_verify(&collector, |a| a.amount == 3 + 30 + 1 + 30));
+        assert_eq!(w_records[0].amount, 10_000);
+
+        // the fee counts against the balance
+        let tx = l2_tx(alice.pk, 1, L2ToL1Withdrawal { amount: Balance(9_972), to: None }, &mut alice.sk.clone());
+        assert_eq!(book.process_withdrawal(&tx, 0, &mut w_records).unwrap_err(), "sender");
+        let tx = l2_tx(alice.pk, 1, L2ToL1Withdrawal { amount: Balance(9_971), to: None }, &mut alice.sk.clone());
+        assert_eq!(book.process_withdrawal(&tx, 0, &mut w_records).unwrap_err(), "balance");
+    }
+
+    #[test]
+    fn partial_root_matches_full_root() {
+        let mut genesis = Genesis::new(20);
+        let faucet_pk = genesis.faucet.pk;
+        genesis.l2 = EngineData::new_batch(genesis.alices.iter().map(|a| a.pk).collect(), PAY_AMOUNT, Layer::L2);
+        for i in 0..5 {
+            let (from, to) = (&genesis.alices[i], genesis.alices[i + 10].pk);
+            let tx = l2_tx(from.pk, 0, Payment { to, amount: Balance(1), expected_total: None }, &mut from.sk.clone());
+            genesis.l2.txns.push(Transaction::Pay(tx));
+        }
+        let tx = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk: genesis.rollup.pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+        genesis.l2.txns.push(Transaction::DepositL2(tx));
+
+        let mut partial = genesis.l2.get_partial();
+        assert_eq!(partial.account_book.root(), genesis.l2.account_book.root());
+        assert!(partial.account_book.verify_partial_root());
+        assert_eq!(partial.account_book.get_num_accounts(), 10);
+
+        // and it stays in step with the full book through the block
+        let (p_bh2, _) = crate::l2_engine::process(&mut partial).unwrap();
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        assert_eq!(p_bh2.hash(), bh2.hash());
+    }
+
+    #[test]
+    fn l2_commitments_decode() {
+        let mut genesis = Genesis::new(2);
+        for alice in &genesis.alices {
+            let tx = l1_tx(alice.pk, 0, L1ToL2Deposit { rollup_pk: genesis.rollup.pk, amount: PAY_AMOUNT }, &mut alice.sk.clone());
+            genesis.l2.txns.push(Transaction::DepositL2(tx));
+            let tx = l2_tx(alice.pk, 0, L2ToL1Withdrawal { amount: Balance(PAY_AMOUNT / 2), to: None }, &mut alice.sk.clone());
+            genesis.l2.txns.push(Transaction::Withdrawal(tx));
+        }
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+
+        // what the guest commits, read back on the host
+        let bytes = bh2.commitments().to_bytes();
+        assert_eq!(bytes.len(), L2Commitments::LEN);
+        let c = L2Commitments::from_bytes(&bytes).unwrap();
+        assert_eq!(c.header_hash, bh2.hash());
+        assert_eq!(c.inbox_msg_hash, bh2.inbox_msg_hash);
+        assert_eq!(c.inbox_msg_count, 2);
+        assert_eq!(c.withdrawals_root, bh2.withdrawals_root);
+        // a withdrawal is claimed against the committed root
+        for (i, w) in bh2.withdrawals.iter().enumerate() {
+            assert!(bh2.withdrawal_proof(i).unwrap().verify(w, &c.withdrawals_root));
+        }
+        let mut other = bh2.clone();
+        other.withdrawals.pop();
+        assert_ne!(c.withdrawals_root, withdrawals_root(&other.withdrawals));
+
+        assert!(L2Commitments::from_bytes(&bytes[1..]).is_err());
+    }
+
+    #[test]
+    fn fee_exempt_sender_pays_no_fee() {
+        let mut genesis = Genesis::new(2);
+        let (alice, bob) = (genesis.alices[0].clone(), genesis.alices[1].clone());
+        let collector = genesis.rollup.clone();
+        let book = &mut genesis.l1.account_book;
+        let mut r = vec![];
+        for (i, pk) in [alice.pk, collector.pk].iter().enumerate() {
+            let tx = l1_tx(genesis.faucet.pk, i as u32, Payment { to: *pk, amount: Balance(1_000), expected_total: None }, &mut genesis.faucet.sk);
+            r.extend(book.process_payment(&tx, 0).unwrap());
+        }
+        book.update_tree(r);
+        let mut params = ChainParams { fee_policy: FeePolicy::Flat(5), fee_collector: Some(collector.pk), ..ChainParams::for_layer(Layer::L1) };
+        params.fee_exempt.insert(pk_to_hash(&collector.pk));
+        book.set_params(params);
+
+        // same payment, one from a normal account, one from the exempt collector
+        let tx = l1_tx(alice.pk, 0, Payment { to: bob.pk, amount: Balance(100), expected_total: None }, &mut alice.sk.clone());
+        let r = book.process_payment(&tx, 0).unwrap();
+        book.update_tree(r);
+        let tx = l1_tx(collector.pk, 0, Payment { to: bob.pk, amount: Balance(100), expected_total: None }, &mut collector.sk.clone());
+        let r = book.process_payment(&tx, 0).unwrap();
+        book.update_tree(r);
+
+        assert!(book.account_hash_verify(&alice.pk, |a| a.amount == 1_000 - 105));
+        assert!(book.account_hash_verify(&collector.pk, |a| a.amount == 1_000 + 5 - 100));
+        assert!(book.account_hash_verify(&bob.pk, |a| a.amount == 200));
+    }
+
+    #[test]
+    fn engine_error_converts_to_io_error() {
+        fn run_block(engine: &mut EngineData) -> Result<BlockHeaderL2, crate::error::Error> {
+            let mut bytes: &[u8] = &[];
+            std::io::Read::read_to_end(&mut bytes, &mut vec![])?;
+            let (header, _) = crate::l2_engine::process(engine)?;
+            Ok(header)
+        }
+        fn run_block_io(engine: &mut EngineData) -> std::io::Result<BlockHeaderL2> {
+            Ok(run_block(engine)?)
+        }
+
+        let mut genesis = Genesis::new(1);
+        let tx = l2_tx(genesis.faucet.pk, 5, Payment { to: genesis.alices[0].pk, amount: Balance(0), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l2.txns.push(Transaction::Pay(tx));
+        let e = run_block_io(&mut genesis.l2).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "engine: sqn");
+
+        let e: std::io::Error = crate::error::Error::from(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)).into();
+        assert_eq!(e.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // and straight from the engine, without the host error in between
+        fn run_block_direct(engine: &mut EngineData) -> std::io::Result<BlockHeaderL2> {
+            let (header, _) = crate::l2_engine::process(engine)?;
+            Ok(header)
+        }
+        let tx = l2_tx(genesis.faucet.pk, 5, Payment { to: genesis.alices[0].pk, amount: Balance(0), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l2.txns = vec![Transaction::Pay(tx)];
+        let e = run_block_direct(&mut genesis.l2).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "sqn");
+    }
+
+    #[test]
+    fn apply_transactions_matches_engines() {
+        let mut genesis = Genesis::new(3);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let mut l1_txns = vec![];
+        let mut l2_txns = vec![];
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        l1_txns.push(Transaction::RollupCreate(tx));
+        // signed in the canonical L1 order, deposits after payments
+        let deposit = l1_tx(faucet_pk, 4, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT * 10 }, &mut genesis.faucet.sk);
+        l2_txns.push(Transaction::DepositL2(deposit.clone()));
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let tx = l1_tx(faucet_pk, i as u32 + 1, Payment { to: alice.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+            l1_txns.push(Transaction::Pay(tx));
+            let tx = l2_tx(faucet_pk, i as u32, Payment { to: alice.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+            l2_txns.push(Transaction::Pay(tx));
+        }
+        l1_txns.push(Transaction::Deposit(deposit));
+        let tx = l2_tx(faucet_pk, 3, L2ToL1Withdrawal { amount: Balance(1), to: None }, &mut genesis.faucet.sk);
+        l2_txns.push(Transaction::Withdrawal(tx));
+
+        let mut l1_book = EngineData::new(faucet_pk, GENESIS_AMOUNT, Layer::L1).account_book;
+        let mut l2_book = EngineData::new(faucet_pk, 0, Layer::L2).account_book;
+        let l1_changes = l1_book.apply_transactions(&l1_txns, Layer::L1, 0).unwrap();
+        let l2_changes = l2_book.apply_transactions(&l2_txns, Layer::L2, 0).unwrap();
+        // faucet, rollup and the alices, once each
+        assert_eq!(l1_changes.len(), 5);
+        assert_eq!(l2_changes.len(), 4);
+
+        genesis.l1.txns = l1_txns;
+        genesis.l2.txns = l2_txns.clone();
+        let (bh1, _) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        assert_eq!(*l1_book.root(), bh1.state_root);
+        assert_eq!(*l2_book.root(), bh2.state_root);
+
+        // layers are not interchangeable
+        assert_eq!(l1_book.apply_transactions(&l2_txns[..1], Layer::L1, 0).unwrap_err(), l2_txns[0].kind().wrong_layer());
+    }
+
+    #[test]
+    fn mempool_order_ties_broken_by_id() {
+        let mut genesis = Genesis::new(4);
+        let mut pool = vec![];
+        for alice in genesis.alices.iter_mut() {
+            for sqn in 0..2 {
+                let tx = Tx::new(alice.pk, sqn, Payment { to: genesis.faucet.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut alice.sk);
+                pool.push(Transaction::Pay(tx));
+            }
+        }
+
+        let block = crate::mempool::order_for_block(pool.clone());
+        assert_eq!(block.len(), pool.len());
+        let ids: Vec<Hash> = block.iter().map(|t| t.id()).collect();
+        // the arrival order must not matter
+        for _ in 0..3 {
+            pool.rotate_left(3);
+            pool.reverse();
+            let again: Vec<Hash> = crate::mempool::order_for_block(pool.clone()).iter().map(|t| t.id()).collect();
+            assert_eq!(again, ids);
+        }
+        // every sender's txns are still in sqn order
+        let mut next_sqn = std::collections::HashMap::new();
+        for t in &block {
+            let (sender, sqn) = t.sender_sqn().unwrap();
+            let expect = next_sqn.entry(sender).or_insert(0u32);
+            assert_eq!(sqn, *expect);
+            *expect += 1;
+        }
+        // the block opens with the smallest id among the senders' first txns
+        let first = pool.iter().filter(|t| t.sender_sqn().unwrap().1 == 0).map(|t| t.id()).min().unwrap();
+        assert_eq!(ids[0], first);
+    }
+
+    #[test]
+    fn rollup_created_with_genesis_inbox() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let genesis_inbox = vec![([1u8; 32], PAY_AMOUNT), ([2u8; 32], 1), ([3u8; 32], PAY_AMOUNT * 2)];
+        let genesis_amount = PAY_AMOUNT * 3 + 1;
+
+        // messages without the funds behind them, and funds without messages
+        let book = &mut genesis.l1.account_book;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: genesis_inbox.clone(), genesis_amount: 0 }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_create_rollup_account(&tx, 0).unwrap_err(), "genesis_inbox");
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: genesis_inbox.clone(), genesis_amount: genesis_amount - 1 }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_create_rollup_account(&tx, 0).unwrap_err(), "genesis_inbox");
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_create_rollup_account(&tx, 0).unwrap_err(), "genesis_inbox");
+
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: genesis_inbox.clone(), genesis_amount }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Ok(BlockHeaderL2::default())).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&faucet_pk, |a| a.amount == GENESIS_AMOUNT - genesis_amount));
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.amount == genesis_amount &&
+            a.rollup.as_ref().is_some_and(|ru| ru.inbox.iter().cloned().eq(genesis_inbox.iter().cloned()) && ru.pending_deposits == genesis_amount)));
+
+        // the first update consumes the seeded messages like any deposits
+        let mut hasher = DefaultHasher::new();
+        for (msg, _) in &genesis_inbox {
+            hasher.update(msg);
+        }
+        let header = BlockHeaderL2 {
+            inbox_msg_hash: hasher.finalize().as_slice().try_into().unwrap(),
+            inbox_msg_count: genesis_inbox.len() as u32,
+            ..Default::default()
+        };
+        let data = bincode_config::serialize(&header).unwrap();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        genesis.l1.txns.push(Transaction::RollupUpdate(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.amount == genesis_amount &&
+            a.rollup.as_ref().is_some_and(|ru| ru.inbox.is_empty() && ru.pending_deposits == 0 && ru.sqn == 1)));
+    }
+
+    #[test]
+    fn balance_checked_ops_and_wire_format() {
+        assert_eq!(Balance(u128::MAX).checked_add(Balance(1)), None);
+        assert_eq!(Balance(0).checked_sub(Balance(1)), None);
+        assert_eq!(Balance(7).checked_add(Balance(3)), Some(Balance(10)));
+        assert_eq!(Balance(7).checked_sub(Balance(7)), Some(Balance::ZERO));
+
+        // encodes exactly like the bare u128 it replaced
+        for x in [0u128, 1, PAY_AMOUNT, GENESIS_AMOUNT, u128::MAX] {
+            let bytes = bincode_config::serialize(&Balance(x)).unwrap();
+            assert_eq!(bytes, bincode_config::serialize(&x).unwrap());
+            assert_eq!(bincode_config::deserialize::<Balance>(&bytes).unwrap(), Balance(x));
+        }
+        let mut genesis = Genesis::new(1);
+        let tx = l2_tx(genesis.faucet.pk, 0, L2ToL1Withdrawal { amount: Balance(PAY_AMOUNT), to: None }, &mut genesis.faucet.sk);
+        assert_eq!(bincode_config::serialize(&tx.payload).unwrap(), bincode_config::serialize(&(PAY_AMOUNT, None::<VerifyingKey>)).unwrap());
+
+        assert_eq!(Balance(1_234_567).to_string(), "1.234567");
+        assert_eq!(Balance(5).to_string(), "0.000005");
+        assert_eq!(Balance(GENESIS_AMOUNT).to_string(), "1000.000000");
+    }
+
+    #[test]
+    fn l1_create_and_deposit_canonical_order() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let create = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        let deposit = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+
+        // executed as given, the deposit would miss the rollup
+        genesis.l1.txns = vec![Transaction::Deposit(deposit.clone()), Transaction::RollupCreate(create.clone())];
+        let (bh1, _) = crate::l1_engine::process(&mut genesis.l1, |_| Ok(BlockHeaderL2::default())).unwrap();
+        assert!(genesis.l1.account_book.account_hash_verify(&faucet_pk, |a| a.amount == GENESIS_AMOUNT - PAY_AMOUNT && a.sqn_expect == 2));
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.amount == PAY_AMOUNT &&
+            a.rollup.as_ref().is_some_and(|ru| ru.inbox.len() == 1)));
+        // the header commits to the order the txns were executed in
+        let canonical = vec![Transaction::RollupCreate(create), Transaction::Deposit(deposit)];
+        assert_eq!(bh1.txns_hash, tx_set_hash(&canonical));
+    }
+
+    #[test]
+    fn l1_keeps_a_senders_sqn_order_across_ranks() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let create = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns = vec![Transaction::RollupCreate(create)];
+        let valid = |data: &Vec<u8>| bincode_config::deserialize(data);
+        crate::l1_engine::process(&mut genesis.l1, valid).unwrap();
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+
+        // the update at sqn 0 runs before the rollup's sqn 1, and the faucet's payment still
+        // goes first
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let update = Transaction::RollupUpdate(l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk));
+        let delegate = Transaction::SetDelegate(l1_tx(rollup_pk, 1, SetDelegate { delegate: alice_pk, allowance: 1 }, &mut genesis.rollup.sk));
+        let pay = Transaction::Pay(l1_tx(faucet_pk, 1, Payment { to: alice_pk, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk));
+        assert_eq!(crate::l1_engine::canonical_order(&[update.clone(), delegate.clone(), pay.clone()]), vec![2, 0, 1]);
+
+        // a failed block hands the txns back in the order they came in
+        let bad = Transaction::Pay(l1_tx(faucet_pk, 9, Payment { to: alice_pk, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk));
+        let given = vec![update.clone(), delegate.clone(), bad];
+        genesis.l1.txns = given.clone();
+        assert_eq!(crate::l1_engine::process(&mut genesis.l1, valid).unwrap_err(), "sqn");
+        assert_eq!(genesis.l1.txns.iter().map(|t| t.id()).collect::<Vec<_>>(), given.iter().map(|t| t.id()).collect::<Vec<_>>());
+
+        genesis.l1.txns = vec![update, delegate, pay];
+        crate::l1_engine::process(&mut genesis.l1, valid).unwrap();
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.sqn_expect == 2 &&
+            a.rollup.as_ref().is_some_and(|ru| ru.header_hash == bh2.hash())));
+    }
+
+    #[test]
+    fn partial_post_state_has_new_accounts() {
+        let mut genesis = Genesis::new(4);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        // brand new recipients, only absence proofs in the pre-state partial
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let tx = l1_tx(faucet_pk, i as u32, Payment { to: alice.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+            genesis.l1.txns.push(Transaction::Pay(tx));
+        }
+        let mut partial = genesis.l1.get_partial();
+        assert!(partial.account_book.verify_partial_root());
+        let (p_bh1, _) = crate::l1_engine::process(&mut partial, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let (bh1, _) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(p_bh1.state_root, bh1.state_root);
+        let new_ids: Vec<AccountID> = genesis.alices.iter().map(|a| pk_to_hash(&a.pk)).collect();
+        assert!(partial.account_book.verify_leaves(&new_ids));
+        assert!(partial.account_book.verify_partial_root());
+        assert!(!partial.account_book.verify_leaves(&[[9u8; 32]]));
+
+        // a withdrawal recipient new to L1 is created by the rollup update, leaf included
+        let n = genesis.alices.len() as u32;
+        let tx = l1_tx(faucet_pk, n, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        let tx = l1_tx(faucet_pk, n + 1, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Deposit(tx.clone()));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+
+        let mut csprng = OsRng;
+        let mut bob = TxSigner::new(SigningKey::random(&mut csprng));
+        genesis.l2.txns.push(Transaction::DepositL2(tx));
+        let tx = l2_tx(faucet_pk, 0, Payment { to: bob.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l2.txns.push(Transaction::Pay(tx));
+        let tx = l2_tx(bob.pk, 0, L2ToL1Withdrawal { amount: Balance(PAY_AMOUNT), to: None }, &mut bob.sk);
+        genesis.l2.txns.push(Transaction::Withdrawal(tx));
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        genesis.l1.txns.push(Transaction::RollupUpdate(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&bob.pk, |a| a.amount == PAY_AMOUNT));
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.amount == 0));
+    }
+
+    #[test]
+    fn signer_batch_funded() {
+        let mut genesis = Genesis::new(0);
+        let mut csprng = OsRng;
+        let signers = TxSigner::batch(50, &mut csprng);
+        assert_eq!(signers.len(), 50);
+
+        // the faucet spent a sqn already, fund_all picks up from the book
+        let tx = l1_tx(genesis.faucet.pk, 0, Payment { to: genesis.rollup.pk, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Pay(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+
+        genesis.l1.txns = TxSigner::fund_all(&genesis.l1.account_book, &mut genesis.faucet, &signers, Balance(PAY_AMOUNT));
+        assert_eq!(genesis.faucet.sqn, 51);
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+        let book = &mut genesis.l1.account_book;
+        for s in &signers {
+            assert!(book.account_hash_verify(&s.pk, |a| a.amount == PAY_AMOUNT && a.sqn_expect == 0));
+        }
+        assert!(book.account_hash_verify(&genesis.faucet.pk, |a| a.sqn_expect == 51 && a.amount == GENESIS_AMOUNT - 1 - 50 * PAY_AMOUNT));
+    }
+
+    fn random_keys(n: usize) -> Vec<VerifyingKey> {
+        let mut csprng = OsRng;
+        (0..n).map(|_| *SigningKey::random(&mut csprng).verifying_key()).collect()
+    }
+
+    // which layers take a variant at all, exhaustive so a new variant has to be placed here
+    fn replay_layers(t: &Transaction) -> (bool, bool) {
+        match t {
+            Transaction::Pay(_) | Transaction::Swap(_) | Transaction::ConditionalPay(_) | Transaction::SetDelegate(_)
+            | Transaction::BatchPay(_) | Transaction::CreateAccount(_) | Transaction::CloseAccount(_) => (true, true),
+            Transaction::Deposit(_) | Transaction::RollupCreate(_) | Transaction::RollupUpdate(_)
+            | Transaction::RollupBatchUpdate(_) | Transaction::RollupCreateFund(_) => (true, false),
+            Transaction::DepositL2(_) | Transaction::Withdrawal(_) | Transaction::RollupCreateFundL2(_) => (false, true),
+            Transaction::Unknown { .. } => (false, false),
+        }
+    }
+
+    // a txn of every kind and an Unknown, each signed at its sender's first sqn
+    fn one_of_each(genesis: &mut Genesis) -> Vec<Transaction> {
+        one_of_each_signed(genesis, L1_CHAIN_ID, L2_CHAIN_ID, None)
+    }
+
+    // one_of_each with the L1 txns signed for l1_chain, the L2 ones for l2_chain, all
+    // expiring at valid_until. Inbox messages are the L1 deposits, signed for l1_chain
+    fn one_of_each_signed(genesis: &mut Genesis, l1_chain: u32, l2_chain: u32, valid_until: Option<u32>) -> Vec<Transaction> {
+        fn signed<T: TxPayload>(chain_id: u32, valid_until: Option<u32>, sender: VerifyingKey, payload: T, sk: &mut SigningKey) -> Tx<T> {
+            Tx::new_signed(sender, 0, chain_id, valid_until, None, payload, sk)
+        }
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let sk = &mut genesis.faucet.sk;
+        let mut cp_sk = genesis.alices[0].sk.clone();
+        let (l1, l2) = (l1_chain, l2_chain);
+        let deposit = signed(l1, valid_until, faucet_pk, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, sk);
+        let fund = signed(l1, valid_until, faucet_pk, CreateAndFundRollup { rollup_pk, amount: PAY_AMOUNT, bond: 0 }, sk);
+        let swap = Swap::new(&faucet_pk, 0, l1, valid_until, 1, 1, &mut cp_sk);
+        vec![
+            Transaction::Pay(signed(l1, valid_until, faucet_pk, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, sk)),
+            Transaction::Deposit(deposit.clone()),
+            Transaction::RollupCreate(signed(l1, valid_until, faucet_pk, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, sk)),
+            Transaction::RollupUpdate(signed(l1, valid_until, rollup_pk, RollupStateUpdate { proof_receipt: vec![] }, &mut genesis.rollup.sk)),
+            Transaction::RollupBatchUpdate(signed(l1, valid_until, rollup_pk, RollupBatchUpdate { proof_receipts: vec![] }, &mut genesis.rollup.sk)),
+            Transaction::DepositL2(deposit),
+            Transaction::Withdrawal(signed(l2, valid_until, faucet_pk, L2ToL1Withdrawal { amount: Balance(1), to: None }, sk)),
+            Transaction::RollupCreateFund(fund.clone()),
+            Transaction::RollupCreateFundL2(fund),
+            Transaction::Swap(signed(l1, valid_until, faucet_pk, swap, sk)),
+            Transaction::ConditionalPay(signed(l1, valid_until, faucet_pk, ConditionalPayment { to: alice_pk, amount: Balance(1), max_recipient_balance: 0 }, sk)),
+            Transaction::SetDelegate(signed(l1, valid_until, faucet_pk, SetDelegate { delegate: alice_pk, allowance: 1 }, sk)),
+            Transaction::BatchPay(signed(l1, valid_until, faucet_pk, BatchPayment { outputs: vec![(alice_pk, 1)] }, sk)),
+            Transaction::CreateAccount(signed(l1, valid_until, faucet_pk, CreateAccount { pubkey: alice_pk }, sk)),
+            Transaction::CloseAccount(signed(l1, valid_until, faucet_pk, CloseAccount {}, sk)),
+            Transaction::Unknown { tag: 99, bytes: vec![] },
+        ]
+    }
+
+    // t alone in a block at height, on the layer one_of_each_signed signs it for. None for
+    // the inbox messages, L1 checked them, and for Unknown
+    fn run_on_signed_layer(genesis: &mut Genesis, t: &Transaction, height: u32) -> Option<ResultT<()>> {
+        let input = match t {
+            Transaction::Withdrawal(_) => &mut genesis.l2,
+            Transaction::DepositL2(_) | Transaction::RollupCreateFundL2(_) | Transaction::Unknown { .. } => return None,
+            _ => &mut genesis.l1,
+        };
+        input.sqn = height;
+        input.txns = vec![t.clone()];
+        Some(match input.layer {
+            Layer::L1 => crate::l1_engine::process(input, |_| Err(EngineError::InvalidReceipt)).map(|_| ()),
+            Layer::L2 => crate::l2_engine::process(input).map(|_| ()),
+        })
+    }
+
+    #[test]
+    fn replay_protection_matrix() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let samples = one_of_each(&mut genesis);
+
+        // wrong layer: rejected before anything else is looked at
+        for t in &samples {
+            let (l1, l2) = replay_layers(t);
+            let expect = match t {
+                Transaction::Unknown { .. } => "unknown tx",
+                _ => t.kind().wrong_layer(),
+            };
+            if !l1 {
+                genesis.l1.txns = vec![t.clone()];
+                assert_eq!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap_err(), expect, "{:?}", t);
+            }
+            if !l2 {
+                genesis.l2.txns = vec![t.clone()];
+                assert_eq!(crate::l2_engine::process(&mut genesis.l2).unwrap_err(), expect, "{:?}", t);
+            }
+        }
+        // signed for the other layer's chain. Pay and Swap are taken by both layers, only
+        // the chain id keeps them apart
+        for t in &one_of_each_signed(&mut genesis, L2_CHAIN_ID, L1_CHAIN_ID, None) {
+            if let Some(r) = run_on_signed_layer(&mut genesis, t, 0) {
+                assert_eq!(r.unwrap_err(), "chain", "{:?}", t);
+            }
+        }
+
+        // same fields, other payload type: the domain byte breaks the signature
+        let sk = &mut genesis.faucet.sk;
+        let pay = l1_tx(faucet_pk, 0, Payment { to: rollup_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, sk);
+        let as_deposit: Tx<L1ToL2Deposit> = bincode_config::deserialize(&bincode_config::serialize(&pay).unwrap()).unwrap();
+        assert!(pay.sig_verify() && !as_deposit.sig_verify());
+        let book = &mut genesis.l1.account_book;
+        assert_eq!(book.process_deposit_l1(&as_deposit, 0).unwrap_err(), "sig");
+        let deposit = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk: alice_pk, amount: PAY_AMOUNT }, sk);
+        // Payment has expected_total on top, the bytes may not even decode as one
+        let r = bincode_config::deserialize::<Tx<Payment>>(&bincode_config::serialize(&deposit).unwrap())
+            .and_then(|as_pay| book.process_payment(&as_pay, 0));
+        assert!(matches!(r.unwrap_err(), EngineError::Deserialize | EngineError::BadSig));
+        let create = l1_tx(faucet_pk, 0, CreateAndFundRollup { rollup_pk, amount: PAY_AMOUNT, bond: 0 }, sk);
+        let as_deposit: Tx<L1ToL2Deposit> = bincode_config::deserialize(&bincode_config::serialize(&create).unwrap()).unwrap();
+        assert_eq!(book.process_deposit_l1(&as_deposit, 0).unwrap_err(), "sig");
+
+        // expired vs current, on either layer. At its last block a txn may still fail,
+        // e.g. after the faucet's payment took sqn 0, but not for expiring
+        let expiring = one_of_each_signed(&mut genesis, L1_CHAIN_ID, L2_CHAIN_ID, Some(4));
+        for t in &expiring {
+            if let Some(r) = run_on_signed_layer(&mut genesis, t, 5) {
+                assert_eq!(r.unwrap_err(), "expired", "{:?}", t);
+            }
+        }
+        for t in &expiring {
+            if let Some(Err(e)) = run_on_signed_layer(&mut genesis, t, 4) {
+                assert_ne!(e, "expired", "{:?}", t);
+            }
+        }
+    }
+
+    #[test]
+    fn pending_deposits_track_inbox() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let tx = l1_tx(faucet_pk, 0, CreateAndFundRollup { rollup_pk, amount: 5, bond: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreateFund(tx.clone()));
+        genesis.l2.txns.push(Transaction::RollupCreateFundL2(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.rollup.as_ref().is_some_and(|ru| ru.pending_deposits == 5)));
+
+        for (sqn, amount) in [(1, PAY_AMOUNT), (2, 7)] {
+            let tx = l1_tx(faucet_pk, sqn, L1ToL2Deposit { rollup_pk, amount }, &mut genesis.faucet.sk);
+            genesis.l1.txns.push(Transaction::Deposit(tx.clone()));
+            genesis.l2.txns.push(Transaction::DepositL2(tx));
+        }
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.amount == 5 + PAY_AMOUNT + 7 &&
+            a.rollup.as_ref().is_some_and(|ru| ru.pending_deposits == 5 + PAY_AMOUNT + 7)));
+
+        // L2 consumes the first two messages in one block, the last in the next
+        let last = genesis.l2.txns.pop().unwrap();
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        genesis.l1.txns.push(Transaction::RollupUpdate(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).is_ok());
+        // the balance stays, only the part owed to L2 shrinks
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.amount == 5 + PAY_AMOUNT + 7 &&
+            a.rollup.as_ref().is_some_and(|ru| ru.pending_deposits == 7 && ru.inbox.len() == 1)));
+
+        genesis.l2.txns.push(last);
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(rollup_pk, 1, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        genesis.l1.txns.push(Transaction::RollupUpdate(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.rollup.as_ref().is_some_and(|ru| ru.pending_deposits == 0 && ru.inbox.is_empty())));
+
+        // a header claiming more messages than the inbox holds is rejected, not a panic
+        let header = BlockHeaderL2 { parent: bh2.hash(), sqn: 2, inbox_msg_count: 1, ..Default::default() };
+        let data = bincode_config::serialize(&header).unwrap();
+        let tx = l1_tx(rollup_pk, 2, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        assert_eq!(genesis.l1.account_book.process_rollup_state_update(&tx, 0, |data| bincode_config::deserialize(data)).unwrap_err(), "inbox_count");
+    }
+
+    #[test]
+    fn l2_txns_scope_hashes() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let deposit = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+        let pay = l2_tx(faucet_pk, 0, Payment { to: genesis.alices[0].pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        let create = l1_tx(faucet_pk, 1, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        let l2_only = vec![Transaction::DepositL2(deposit.clone()), Transaction::Pay(pay.clone())];
+        let mixed = vec![Transaction::DepositL2(deposit), Transaction::RollupCreate(create), Transaction::Pay(pay)];
+
+        let all = L2TxnsScope::All;
+        let l2 = L2TxnsScope::L2Only;
+        assert_ne!(all.txns_hash(&mixed), l2.txns_hash(&mixed));
+        assert_eq!(l2.txns_hash(&mixed), l2.txns_hash(&l2_only));
+        assert_ne!(all.txns_hash(&mixed), all.txns_hash(&l2_only));
+        // the scope is committed even when it selects the same txns
+        assert_ne!(all.txns_hash(&l2_only), l2.txns_hash(&l2_only));
+
+        // the engine hashes under the configured scope
+        genesis.l2.txns = l2_only.clone();
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        assert_eq!(bh2.txns_hash, all.txns_hash(&l2_only));
+        let mut other = Genesis::new(0);
+        other.l2.account_book.set_params(ChainParams { l2_txns_scope: l2, ..ChainParams::for_layer(Layer::L2) });
+        let faucet_pk = other.faucet.pk;
+        let deposit = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut other.faucet.sk);
+        other.l2.txns = vec![Transaction::DepositL2(deposit)];
+        let txns = other.l2.txns.clone();
+        let (bh2, _) = crate::l2_engine::process(&mut other.l2).unwrap();
+        assert_eq!(bh2.txns_hash, l2.txns_hash(&txns));
+    }
+
+    #[test]
+    fn rollup_update_must_be_signed_by_rollup() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        let tx = l1_tx(faucet_pk, 1, Payment { to: genesis.alices[0].pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Pay(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let book = &mut genesis.l1.account_book;
+        let valid = |data: &Vec<u8>| bincode_config::deserialize(data);
+
+        // a relayer holding a valid receipt can't submit it in the rollup's name
+        let mut relayer = genesis.alices[0].clone();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data.clone() }, &mut relayer.sk);
+        assert_eq!(book.process_rollup_state_update(&tx, 0, valid).unwrap_err(), "sig");
+        // nor under its own account
+        let tx = l1_tx(relayer.pk, 0, RollupStateUpdate { proof_receipt: data.clone() }, &mut relayer.sk);
+        assert_eq!(book.process_rollup_state_update(&tx, 0, valid).unwrap_err(), "account_rollup");
+        // the receipt is signed over, it can't be swapped under the rollup's signature
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data.clone() }, &mut genesis.rollup.sk);
+        let mut forged = bh2.clone();
+        forged.sqn = 7;
+        let mut swapped = tx.clone();
+        swapped.payload.proof_receipt = bincode_config::serialize(&forged).unwrap();
+        assert_eq!(book.process_rollup_state_update(&swapped, 0, valid).unwrap_err(), "sig");
+
+        assert!(book.process_rollup_state_update(&tx, 0, valid).is_ok());
+    }
+
+    #[test]
+    fn conditional_payment_tops_up() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let (low, funded) = (genesis.alices[0].pk, genesis.alices[1].pk);
+        let tx = l1_tx(faucet_pk, 0, Payment { to: funded, amount: Balance(PAY_AMOUNT * 5), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Pay(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+
+        // a new account holds nothing, the top-up goes through
+        let top_up = |to, sqn, sk: &mut SigningKey| l1_tx(faucet_pk, sqn, ConditionalPayment { to, amount: Balance(PAY_AMOUNT), max_recipient_balance: PAY_AMOUNT * 2 }, sk);
+        genesis.l1.txns.push(Transaction::ConditionalPay(top_up(low, 1, &mut genesis.faucet.sk)));
+        let (_, summary) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(summary.total_paid, PAY_AMOUNT);
+        // the limit is inclusive
+        genesis.l1.txns.push(Transaction::ConditionalPay(top_up(low, 2, &mut genesis.faucet.sk)));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&low, |a| a.amount == PAY_AMOUNT * 2));
+
+        // already funded: rejected, nothing moves and the sqn is not used
+        let book = &mut genesis.l1.account_book;
+        assert_eq!(book.process_conditional_payment(&top_up(funded, 3, &mut genesis.faucet.sk), 0).unwrap_err(), "condition");
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.sqn_expect == 3 && a.amount == GENESIS_AMOUNT - PAY_AMOUNT * 7));
+        assert!(book.account_hash_verify(&funded, |a| a.amount == PAY_AMOUNT * 5));
+        // same for a sender that can't cover it, the condition holding or not
+        let tx = l1_tx(faucet_pk, 3, ConditionalPayment { to: low, amount: Balance(GENESIS_AMOUNT), max_recipient_balance: u128::MAX }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_conditional_payment(&tx, 0).unwrap_err(), "sender");
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.sqn_expect == 3));
+        // so sqn 3 is still free
+        let tx = l1_tx(faucet_pk, 3, Payment { to: low, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk);
+        assert!(book.process_payment(&tx, 0).is_ok());
+
+        // round trips as its own variant
+        let t = Transaction::ConditionalPay(top_up(funded, 3, &mut genesis.faucet.sk));
+        let decoded: Transaction = bincode_config::deserialize(&bincode_config::serialize(&t).unwrap()).unwrap();
+        assert!(matches!(decoded, Transaction::ConditionalPay(_)));
+        assert_eq!(decoded.id(), t.id());
+    }
+
+    #[test]
+    fn affected_ids_sorted_and_stable() {
+        let mut genesis = Genesis::new(6);
+        let faucet_pk = genesis.faucet.pk;
+        let mut txns = TxSigner::fund_all(&genesis.l1.account_book, &mut genesis.faucet, &genesis.alices, Balance(PAY_AMOUNT));
+        let tx = l1_tx(faucet_pk, 6, L1ToL2Deposit { rollup_pk: genesis.rollup.pk, amount: 1 }, &mut genesis.faucet.sk);
+        txns.push(Transaction::Deposit(tx));
+
+        let ids = genesis.l1.account_book.get_affected_account_ids(&txns);
+        // faucet, rollup and the alices, each once
+        assert_eq!(ids.len(), 8);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        for _ in 0..3 {
+            txns.reverse();
+            assert_eq!(genesis.l1.account_book.get_affected_account_ids(&txns), ids);
+        }
+    }
+
+    #[test]
+    fn engine_data_with_book_continues() {
+        let mut genesis = Genesis::new(2);
+        genesis.l1.txns = TxSigner::fund_all(&genesis.l1.account_book, &mut genesis.faucet, &genesis.alices, Balance(PAY_AMOUNT));
+        let (bh1, _) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+
+        // a node restarting from its stored book
+        let stored = bincode_config::serialize(&genesis.l1.account_book).unwrap();
+        let book: AccountBook = bincode_config::deserialize(&stored).unwrap();
+        let mut restored = EngineData::with_book(book, bh1.hash(), genesis.l1.sqn, Layer::L1);
+        assert_eq!(restored.sqn, 1);
+
+        let (alice, bob) = (genesis.alices[0].clone(), genesis.alices[1].pk);
+        let tx = l1_tx(alice.pk, 0, Payment { to: bob, amount: Balance(1), expected_total: None }, &mut alice.sk.clone());
+        genesis.l1.txns.push(Transaction::Pay(tx.clone()));
+        restored.txns.push(Transaction::Pay(tx));
+        let (next, _) = crate::l1_engine::process(&mut restored, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let (expected, _) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(next.parent, bh1.hash());
+        assert_eq!(next.sqn, 1);
+        assert_eq!(next.hash(), expected.hash());
+        assert_eq!((restored.sqn, restored.parent), (2, next.hash()));
+    }
+
+    #[test]
+    fn lenient_process_stats() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let alice = genesis.alices[0].pk;
+        let sk = &mut genesis.faucet.sk;
+        let deposit = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, sk);
+        let good = vec![
+            Transaction::DepositL2(deposit),
+            Transaction::Pay(l2_tx(faucet_pk, 0, Payment { to: alice, amount: Balance(1), expected_total: None }, sk)),
+            Transaction::Pay(l2_tx(faucet_pk, 1, Payment { to: alice, amount: Balance(1), expected_total: None }, sk)),
+        ];
+        let bad = vec![
+            // sqn already used
+            Transaction::Pay(l2_tx(faucet_pk, 0, Payment { to: alice, amount: Balance(2), expected_total: None }, sk)),
+            Transaction::Withdrawal(l2_tx(faucet_pk, 2, L2ToL1Withdrawal { amount: Balance(GENESIS_AMOUNT + 1), to: None }, sk)),
+            Transaction::RollupCreate(l1_tx(faucet_pk, 2, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, sk)),
+            Transaction::Unknown { tag: 42, bytes: vec![] },
+        ];
+        let mut txns = good.clone();
+        txns.extend(bad.iter().cloned());
+        genesis.l2.txns = txns.clone();
+        let (bh2, summary, stats) = crate::l2_engine::process_lenient(&mut genesis.l2).unwrap();
+
+        let expected: BTreeMap<TxKind, (usize, usize)> = [
+            (TxKind::DepositL2, (1, 0)),
+            (TxKind::Pay, (2, 1)),
+            (TxKind::Withdrawal, (0, 1)),
+            (TxKind::RollupCreate, (0, 1)),
+            (TxKind::Unknown, (0, 1)),
+        ].into_iter().collect();
+        assert_eq!(stats.per_kind, expected);
+        assert_eq!(summary.num_txns, 3);
+        // the header covers what was applied, the same as a strict run of the good txns
+        assert_eq!(bh2.txns_hash, L2TxnsScope::All.txns_hash(&good));
+        let mut strict = EngineData::new(faucet_pk, 0, Layer::L2);
+        strict.txns = good;
+        let (bh2_strict, _) = crate::l2_engine::process(&mut strict).unwrap();
+        assert_eq!(bh2.hash(), bh2_strict.hash());
+
+        // the strict engine fails the same block
+        let mut strict = EngineData::new(faucet_pk, 0, Layer::L2);
+        strict.txns = txns;
+        assert_eq!(crate::l2_engine::process(&mut strict).unwrap_err(), "sqn");
+
+        // l1 counts its kinds the same way
+        let tx = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+        genesis.l1.txns = vec![Transaction::Deposit(tx), bad[3].clone()];
+        let (bh1, _, stats) = crate::l1_engine::process_lenient(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(stats.per_kind.get(&TxKind::Deposit), Some(&(0, 1)));
+        assert_eq!(stats.per_kind.get(&TxKind::Unknown), Some(&(0, 1)));
+        assert!(bh1.events.is_empty());
+        assert_eq!(bh1.txns_hash, tx_set_hash(&vec![]));
+    }
+
+    #[test]
+    fn multi_proof_matches_individual_proofs() {
+        let keys = random_keys(16);
+        let data = EngineData::new_batch(keys.clone(), 1000, Layer::L1);
+        let book = &data.account_book;
+        let explorer = crate::explorer::Explorer::new(book);
+        let root = *book.root();
+        let cluster: Vec<AccountID> = keys[..6].iter().map(pk_to_hash).collect();
+        let leaves: Vec<(AccountID, Hash)> = cluster.iter()
+            .map(|aid| (*aid, book.accounts[aid].hash()))
+            .collect();
+
+        let proof = explorer.multi_proof(&cluster).unwrap();
+        let individual = cluster.iter().all(|aid| explorer.proof(aid).unwrap().verify(&root));
+        assert!(individual);
+        assert_eq!(crate::explorer::verify_multi(&proof, &root, &leaves), individual);
+
+        // tampered leaf, wrong root
+        let mut bad = leaves.clone();
+        bad[3].1[0] ^= 1;
+        assert!(!crate::explorer::verify_multi(&proof, &root, &bad));
+        assert!(!crate::explorer::verify_multi(&proof, &[0u8; 32], &leaves));
+
+        let decoded: crate::explorer::CompactMultiProof =
+            bincode_config::deserialize(&bincode_config::serialize(&proof).unwrap()).unwrap();
+        assert!(crate::explorer::verify_multi(&decoded, &root, &leaves));
+        assert!(explorer.multi_proof(&[[0u8; 32]]).is_none());
+    }
+
+    #[test]
+    fn rollup_batch_update_settles_chain() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        let mut deposits = vec![];
+        for (sqn, amount) in [(1, PAY_AMOUNT), (2, 7)] {
+            let tx = l1_tx(faucet_pk, sqn, L1ToL2Deposit { rollup_pk, amount }, &mut genesis.faucet.sk);
+            genesis.l1.txns.push(Transaction::Deposit(tx.clone()));
+            deposits.push(Transaction::DepositL2(tx));
+        }
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+
+        // three L2 blocks: a deposit, a withdrawal, the other deposit
+        let withdraw = l2_tx(faucet_pk, 0, L2ToL1Withdrawal { amount: Balance(3), to: None }, &mut genesis.faucet.sk);
+        let mut headers = vec![];
+        for txns in [vec![deposits[0].clone()], vec![Transaction::Withdrawal(withdraw)], vec![deposits[1].clone()]] {
+            genesis.l2.txns = txns;
+            headers.push(crate::l2_engine::process(&mut genesis.l2).unwrap().0);
+        }
+        let receipts: Vec<Vec<u8>> = headers.iter().map(|h| bincode_config::serialize(h).unwrap()).collect();
+
+        // out of order is rejected
+        let mut swapped = receipts.clone();
+        swapped.swap(1, 2);
+        let tx = l1_tx(rollup_pk, 0, RollupBatchUpdate { proof_receipts: swapped }, &mut genesis.rollup.sk);
+        assert_eq!(genesis.l1.account_book.process_rollup_batch_update(&tx, 0, |data| bincode_config::deserialize(data)).unwrap_err(), "parent");
+        let tx = l1_tx(rollup_pk, 0, RollupBatchUpdate { proof_receipts: vec![] }, &mut genesis.rollup.sk);
+        assert_eq!(genesis.l1.account_book.process_rollup_batch_update(&tx, 0, |data| bincode_config::deserialize(data)).unwrap_err(), "receipts");
+
+        let tx = l1_tx(rollup_pk, 0, RollupBatchUpdate { proof_receipts: receipts }, &mut genesis.rollup.sk);
+        genesis.l1.txns.push(Transaction::RollupBatchUpdate(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).is_ok());
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.sqn_expect == 1 && a.amount == PAY_AMOUNT + 7 - 3 &&
+            a.rollup.as_ref().is_some_and(|ru| ru.sqn == 3 && ru.header_hash == headers[2].hash() && ru.inbox.is_empty() && ru.pending_deposits == 0)));
+        assert!(genesis.l1.account_book.account_hash_verify(&faucet_pk, |a| a.amount == GENESIS_AMOUNT - PAY_AMOUNT - 7 + 3));
+    }
+
+    #[test]
+    fn lenient_block_equals_strict_block_of_its_retained_txns() {
+        let mut genesis = Genesis::new(3);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let bob_pk = genesis.alices[1].pk;
+        genesis.l1.account_book.set_params(ChainParams { interest_ppb: 1_000_000, ..ChainParams::for_layer(Layer::L1) });
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, 0, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk)));
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, 1, Payment { to: bob_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk)));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        for _ in 0..5 {
+            crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        }
+
+        // alice and bob would accrue for the overdraft, and only for it
+        genesis.l1.txns = vec![
+            Transaction::Pay(l1_tx(faucet_pk, 2, Payment { to: genesis.alices[2].pk, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk)),
+            Transaction::Pay(l1_tx(alice_pk, 0, Payment { to: bob_pk, amount: Balance(PAY_AMOUNT * 2), expected_total: None }, &mut genesis.alices[0].sk)),
+        ];
+        let bytes = bincode_config::serialize(&genesis.l1).unwrap();
+        let mut strict: EngineData = bincode_config::deserialize(&bytes).unwrap();
+        let (lenient_header, _, stats) = crate::l1_engine::process_lenient(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert!(matches!(stats.receipts[1].outcome, TxOutcome::Failed(_)));
+
+        strict.txns.truncate(1);
+        let (strict_header, _) = crate::l1_engine::process(&mut strict, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(lenient_header.hash(), strict_header.hash());
+        assert_eq!(genesis.l1.account_book.interest_minted, strict.account_book.interest_minted);
+        assert!(genesis.l1.account_book.account_hash_verify(&alice_pk, |a| a.amount == PAY_AMOUNT && a.last_accrued == 0));
+        assert!(genesis.l1.account_book.account_hash_verify(&bob_pk, |a| a.amount == PAY_AMOUNT && a.last_accrued == 0));
+    }
+
+    #[test]
+    fn interest_accrues_on_touch() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let bob_pk = genesis.alices[1].pk;
+        // 0.1% per block
+        genesis.l1.account_book.set_params(ChainParams { interest_ppb: 1_000_000, ..ChainParams::for_layer(Layer::L1) });
+
+        // alice is created in block 0, bob in block 3
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, 0, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk)));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        for _ in 0..2 {
+            crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        }
+        let faucet_before = genesis.l1.account_book.accounts[&pk_to_hash(&faucet_pk)].amount.0;
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, 1, Payment { to: bob_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk)));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        // the faucet accrued 3 blocks before paying, alice was not touched
+        let faucet_interest = faucet_before * 3_000_000 / INTEREST_DENOMINATOR;
+        assert!(genesis.l1.account_book.account_hash_verify(&faucet_pk, |a| a.amount == faucet_before + faucet_interest - PAY_AMOUNT && a.last_accrued == 3));
+        assert!(genesis.l1.account_book.account_hash_verify(&alice_pk, |a| a.amount == PAY_AMOUNT && a.last_accrued == 0));
+        assert!(genesis.l1.account_book.account_hash_verify(&bob_pk, |a| a.amount == PAY_AMOUNT && a.last_accrued == 3));
+
+        // alice pays bob in block 10: alice accrues 10 blocks, bob 7, rounded down
+        for _ in 4..10 {
+            crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        }
+        assert_eq!(genesis.l1.sqn, 10);
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(alice_pk, 0, Payment { to: bob_pk, amount: Balance(1), expected_total: None }, &mut genesis.alices[0].sk)));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let alice_interest = PAY_AMOUNT * 10_000_000 / INTEREST_DENOMINATOR;
+        let bob_interest = PAY_AMOUNT * 7_000_000 / INTEREST_DENOMINATOR;
+        assert!(genesis.l1.account_book.account_hash_verify(&alice_pk, |a| a.amount == PAY_AMOUNT + alice_interest - 1 && a.last_accrued == 10));
+        assert!(genesis.l1.account_book.account_hash_verify(&bob_pk, |a| a.amount == PAY_AMOUNT + bob_interest + 1 && a.last_accrued == 10));
+        assert_eq!(genesis.l1.account_book.interest_minted, faucet_interest + alice_interest + bob_interest);
+    }
+
+    #[derive(Clone, Default)]
+    struct Capture(std::sync::Arc<std::sync::Mutex<Vec<crate::events::EngineEvent>>>);
+
+    impl crate::events::EngineObserver for Capture {
+        fn on_event(&mut self, event: &crate::events::EngineEvent) {
+            self.0.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn observers_see_block_events() {
+        use crate::events::EngineEvent::*;
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let (faucet, rollup, alice) = (pk_to_hash(&faucet_pk), pk_to_hash(&rollup_pk), pk_to_hash(&alice_pk));
+        let capture = Capture::default();
+        genesis.l1.observers.push(Box::new(capture.clone()));
+
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, 0, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk)));
+        genesis.l1.txns.push(Transaction::RollupCreate(l1_tx(faucet_pk, 1, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk)));
+        let deposit = l1_tx(faucet_pk, 2, L1ToL2Deposit { rollup_pk, amount: 7 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Deposit(deposit.clone()));
+        // observers don't change the block, the guest runs without them
+        let partial = &mut genesis.l1.get_partial();
+        let (guest, _) = crate::l1_engine::process(partial, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let (header, _) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(header.hash(), guest.hash());
+
+        // txn by txn, ids sorted within a txn
+        let by_id = |mut per_id: Vec<(AccountID, Vec<crate::events::EngineEvent>)>| {
+            per_id.sort_by_key(|(id, _)| *id);
+            per_id.into_iter().flat_map(|(_, e)| e)
+        };
+        let after_pay = GENESIS_AMOUNT - PAY_AMOUNT;
+        let mut expected: Vec<_> = by_id(vec![
+            (alice, vec![AccountCreated { id: alice, owner: alice_pk }, BalanceChanged { id: alice, amount: Balance(PAY_AMOUNT) }]),
+            (faucet, vec![BalanceChanged { id: faucet, amount: Balance(after_pay) }]),
+        ]).collect();
+        // creating the rollup with no bond only bumps the faucet's sqn
+        expected.push(AccountCreated { id: rollup, owner: rollup_pk });
+        expected.extend(by_id(vec![
+            (faucet, vec![BalanceChanged { id: faucet, amount: Balance(after_pay - 7) }]),
+            (rollup, vec![BalanceChanged { id: rollup, amount: Balance(7) }]),
+        ]));
+        assert_eq!(*capture.0.lock().unwrap(), expected);
+
+        // settling an L2 block
+        capture.0.lock().unwrap().clear();
+        genesis.l2.txns.push(Transaction::DepositL2(deposit));
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        let data = bincode_config::serialize(&bh2).unwrap();
+        genesis.l1.txns.push(Transaction::RollupUpdate(l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk)));
+        crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).unwrap();
+        assert_eq!(*capture.0.lock().unwrap(), vec![RollupSettled { id: rollup, sqn: 1, header_hash: bh2.hash() }]);
+    }
+
+    #[test]
+    fn checkpoints_every_interval() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        genesis.l1.account_book.set_checkpoint_interval(3);
+        assert_eq!(genesis.l1.account_book.root_at_height(0), None);
+
+        // every block changes the root
+        let mut roots = vec![];
+        for sqn in 0..8 {
+            let tx = l1_tx(faucet_pk, sqn, Payment { to: alice_pk, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk);
+            genesis.l1.txns.push(Transaction::Pay(tx));
+            crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+            roots.push(*genesis.l1.account_book.root());
+        }
+        // blocks 0, 3 and 6 are kept, queries in between get the one before
+        for (sqn, kept) in [(0, 0), (1, 0), (2, 0), (3, 3), (5, 3), (6, 6), (7, 6), (100, 6)] {
+            assert_eq!(genesis.l1.account_book.root_at_height(sqn), Some(roots[kept]), "{}", sqn);
+        }
+
+        // not carried into the guest input
+        assert_eq!(genesis.l1.get_partial().account_book.root_at_height(7), None);
+    }
+
+    #[test]
+    fn tx_id_is_deterministic() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let sk = &mut genesis.faucet.sk;
+        let pay = |sqn, sk: &mut SigningKey| Tx::new(faucet_pk, sqn, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, sk);
+        let a = pay(0, sk);
+        let b = pay(0, sk);
+        assert_eq!(a.id(), b.id());
+        assert_eq!(bincode_config::serialize(&a).unwrap(), bincode_config::serialize(&b).unwrap());
+        assert_ne!(a.id(), pay(1, sk).id());
+
+        let withdraw = Tx::new_valid_until(faucet_pk, 0, Some(9), L2ToL1Withdrawal { amount: Balance(1), to: None }, sk);
+        assert_eq!(withdraw.id(), Tx::new_valid_until(faucet_pk, 0, Some(9), L2ToL1Withdrawal { amount: Balance(1), to: None }, sk).id());
+    }
+
+    #[test]
+    fn deposit_below_minimum_rejected() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        genesis.l1.account_book.set_params(ChainParams { min_deposit: 100, ..ChainParams::for_layer(Layer::L1) });
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+
+        let book = &mut genesis.l1.account_book;
+        let dust = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: 99 }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_deposit_l1(&dust, 1).unwrap_err(), "min_deposit");
+        let rollup = &book.accounts[&pk_to_hash(&rollup_pk)];
+        assert!(rollup.amount == 0 && rollup.rollup.as_ref().is_some_and(|ru| ru.inbox.is_empty() && ru.pending_deposits == 0));
+        assert_eq!(book.accounts[&pk_to_hash(&faucet_pk)].sqn_expect, 1);
+
+        let deposit = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: 100 }, &mut genesis.faucet.sk);
+        book.process_deposit_l1(&deposit, 1).unwrap();
+        let rollup = &book.accounts[&pk_to_hash(&rollup_pk)];
+        assert!(rollup.amount == 100 && rollup.rollup.as_ref().is_some_and(|ru| ru.inbox.len() == 1 && ru.inbox[0].0 == deposit.id()));
+    }
+
+    #[test]
+    fn partial_root_errors_are_distinct() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let tx = l1_tx(faucet_pk, 0, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Pay(tx));
+        let partial = genesis.l1.get_partial();
+        assert_eq!(partial.account_book.verify_partial_root_detailed(), Ok(()));
+
+        // an account that doesn't match its leaf
+        let mut book = genesis.l1.get_partial().account_book;
+        let faucet = pk_to_hash(&faucet_pk);
+        book.accounts.get_mut(&faucet).unwrap().amount = Balance(1);
+        assert_eq!(book.verify_partial_root_detailed(), Err(PartialRootError::LeafMismatch(faucet)));
+        assert!(!book.verify_partial_root());
+
+        let mut book = genesis.l1.get_partial().account_book;
+        let stranger = random_keys(1)[0];
+        book.accounts.insert(pk_to_hash(&stranger), Account::new(stranger, Balance::ZERO, None));
+        assert_eq!(book.verify_partial_root_detailed(), Err(PartialRootError::MissingLeaf(pk_to_hash(&stranger))));
+
+        // accounts all fine, the trie doesn't hash to its root, so neither does the faucet's path
+        let mut book = genesis.l1.get_partial().account_book;
+        book.proof_tree.root = [9u8; 32];
+        assert_eq!(book.verify_partial_root_detailed(), Err(PartialRootError::BadPath { id: faucet, root: [9u8; 32] }));
+
+        let mut book = genesis.l1.get_partial().account_book;
+        book.accounts.clear();
+        assert_eq!(book.verify_partial_root_detailed(), Err(PartialRootError::NotEmpty));
+    }
+
+    #[test]
+    fn partial_root_names_the_path_of_a_corrupted_sibling() {
+        let mut genesis = Genesis::new(1);
+        let faucet = pk_to_hash(&genesis.faucet.pk);
+        let alice = pk_to_hash(&genesis.alices[0].pk);
+        let tx = l1_tx(genesis.faucet.pk, 0, Payment { to: genesis.alices[0].pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Pay(tx));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let book = &mut genesis.l1.account_book;
+        let before = book.partial_for(&[faucet]);
+        assert_eq!(before.verify_partial_root_detailed(), Ok(()));
+
+        // the faucet's path again after alice changed: only the sibling hash covering
+        // alice and the root differ. Put the old root back over the new sibling
+        let a = book.accounts.get_mut(&alice).unwrap();
+        a.amount = Balance(1);
+        let leaf = a.hash_with::<DefaultHasher>();
+        book.update_tree(vec![(alice, leaf)]);
+        let mut corrupted = book.partial_for(&[faucet]);
+        let (old_root, new_root) = (*before.root(), *corrupted.root());
+        let mut bytes = bincode_config::serialize(&corrupted.proof_tree).unwrap();
+        let at = bytes.windows(32).position(|w| w == new_root).unwrap();
+        bytes[at..at + 32].copy_from_slice(&old_root);
+        assert_ne!(bytes, bincode_config::serialize(&before.proof_tree).unwrap());
+        corrupted.proof_tree = bincode_config::deserialize(&bytes).unwrap();
+
+        assert_eq!(*corrupted.root(), old_root);
+        assert!(corrupted.verify_leaves([&faucet]));
+        assert_eq!(corrupted.verify_partial_root_detailed(), Err(PartialRootError::BadPath { id: faucet, root: old_root }));
+    }
+
+    #[test]
+    fn rollup_update_rejects_fork_and_stale_time() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+
+        genesis.l2.timestamp = 1_000;
+        let (bh0, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        let data = bincode_config::serialize(&bh0).unwrap();
+        genesis.l1.txns.push(Transaction::RollupUpdate(l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk)));
+        crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).unwrap();
+        assert_eq!(genesis.l2.timestamp, 1_001);
+
+        let settle = |header: &BlockHeaderL2, genesis: &mut Genesis| {
+            let data = bincode_config::serialize(header).unwrap();
+            let tx = l1_tx(rollup_pk, 1, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+            genesis.l1.account_book.process_rollup_state_update(&tx, 1, |data| bincode_config::deserialize(data))
+        };
+        let (bh1, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        // right sqn, but from a divergent history
+        let fork = BlockHeaderL2 { parent: [7u8; 32], ..bh1.clone() };
+        assert_eq!(settle(&fork, &mut genesis).unwrap_err(), "parent");
+        // extends the settled header, but not later than it
+        let stale = BlockHeaderL2 { timestamp: bh0.timestamp, ..bh1.clone() };
+        assert_eq!(settle(&stale, &mut genesis).unwrap_err(), "timestamp");
+
+        let updates = settle(&bh1, &mut genesis).unwrap();
+        genesis.l1.account_book.update_tree(updates);
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.rollup.as_ref().is_some_and(|ru| ru.sqn == 2 && ru.last_timestamp == 1_001)));
+    }
+
+    #[test]
+    fn genesis_manifest_matches_across_nodes() {
+        let keys = random_keys(5);
+        let params = ChainParams { min_deposit: 10, fee_policy: FeePolicy::Flat(1), ..Default::default() };
+        let build = |params: &ChainParams| {
+            let mut book = AccountBook::new_batch(keys.clone(), PAY_AMOUNT);
+            book.set_params(params.clone());
+            book
+        };
+        let canonical = build(&params).genesis_manifest().unwrap();
+        assert_eq!(canonical.account_count, 5);
+        assert_eq!(canonical.total_supply, PAY_AMOUNT * 5);
+
+        let joining = build(&params);
+        assert_eq!(joining.genesis_manifest(), Ok(canonical.clone()));
+        assert!(joining.verify_genesis(&canonical));
+
+        // same accounts, other rules
+        let other = build(&ChainParams { min_deposit: 11, ..params.clone() });
+        assert_eq!(*other.root(), canonical.root);
+        assert!(!other.verify_genesis(&canonical));
+        // same rules, other balances
+        let mut poorer = AccountBook::new_batch(keys.clone(), PAY_AMOUNT - 1);
+        poorer.set_params(params);
+        assert!(!poorer.verify_genesis(&canonical));
+
+        // balances that don't sum in a u128 have no manifest
+        let overflowing = AccountBookBuilder::new().with_account(keys[0], u128::MAX, None).with_account(keys[1], 1, None).build();
+        assert_eq!(overflowing.genesis_manifest(), Err(EngineError::Overflow));
+        assert!(!overflowing.verify_genesis(&canonical));
+    }
+
+    // signing and verifying 10k txns takes about a minute in debug builds, run with
+    // cargo test --release --lib tests::payment_load_processes -- --ignored
+    #[test]
+    #[ignore]
+    fn payment_load_processes() {
+        let mut signers = TxSigner::batch(16, &mut OsRng);
+        let recipients = random_keys(50);
+        let keys = signers.iter().map(|s| s.pk).collect();
+        let mut l2 = EngineData::new_batch(keys, PAY_AMOUNT * 1_000, Layer::L2);
+
+        let txns = crate::testkit::generate_payment_load(&mut signers, &recipients, 10_000, Balance(1), L2_CHAIN_ID);
+        assert_eq!(txns.len(), 10_000);
+        assert!(signers.iter().all(|s| s.sqn == 625));
+        let data = bincode_config::serialize(&txns).unwrap();
+        l2.txns = bincode_config::deserialize(&data).unwrap();
+        let (_, summary) = crate::l2_engine::process(&mut l2).unwrap();
+        assert_eq!(summary.num_txns, 10_000);
+        assert_eq!(summary.total_paid, 10_000);
+        assert_eq!(l2.account_book.get_num_accounts(), 66);
+    }
+
+    #[test]
+    fn fee_collector_aliasing() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let (alice, bob) = (genesis.alices[0].clone(), genesis.alices[1].pk);
+        let book = &mut genesis.l1.account_book;
+        let fee_to = |collector| ChainParams { fee_policy: FeePolicy::Flat(3), fee_collector: Some(collector), ..ChainParams::for_layer(Layer::L1) };
+        let mut pay = |book: &mut AccountBook, sqn, to| {
+            let tx = l1_tx(faucet_pk, sqn, Payment { to, amount: Balance(100), expected_total: None }, &mut genesis.faucet.sk);
+            let r = book.process_payment(&tx, 0)?;
+            // every account once
+            let ids: BTreeSet<AccountID> = r.iter().map(|(id, _)| *id).collect();
+            assert_eq!(ids.len(), r.len());
+            book.update_tree(r);
+            Ok::<(), EngineError>(())
+        };
+        let balance = |book: &AccountBook, pk: &VerifyingKey| book.accounts[&pk_to_hash(pk)].amount.0;
+        let start = balance(book, &faucet_pk);
+
+        // recipient is the collector: it gets amount and fee in one credit
+        book.set_params(fee_to(alice.pk));
+        pay(book, 0, alice.pk).unwrap();
+        assert!(book.account_hash_verify(&alice.pk, |a| a.amount == 103));
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.amount == start - 103));
+
+        // sender is the collector: the fee comes straight back
+        book.set_params(fee_to(faucet_pk));
+        pay(book, 1, bob).unwrap();
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.amount == start - 203 && a.sqn_expect == 2));
+        assert!(book.account_hash_verify(&bob, |a| a.amount == 100));
+
+        // all three: only the sqn moves, but the full total must be covered first
+        pay(book, 2, faucet_pk).unwrap();
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.amount == start - 203 && a.sqn_expect == 3));
+
+        // a combined credit that overflows is an error, not a panic
+        book.set_params(fee_to(alice.pk));
+        book.accounts.get_mut(&pk_to_hash(&alice.pk)).unwrap().amount = Balance(u128::MAX - 101);
+        assert_eq!(pay(book, 3, alice.pk).unwrap_err(), "overflow");
+        assert_eq!(balance(book, &faucet_pk), start - 203);
+    }
+
+    #[test]
+    fn engine_data_ids_only_resolves() {
+        let mut genesis = Genesis::new(3);
+        let faucet_pk = genesis.faucet.pk;
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let tx = l1_tx(faucet_pk, i as u32, Payment { to: alice.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+            genesis.l1.txns.push(Transaction::Pay(tx));
+        }
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let tx = l1_tx(genesis.alices[0].pk, 0, Payment { to: genesis.alices[1].pk, amount: Balance(1), expected_total: None }, &mut genesis.alices[0].sk);
+        genesis.l1.txns.push(Transaction::Pay(tx));
+
+        let full = genesis.l1.get_partial();
+        let ids = genesis.l1.get_partial().ids_only();
+        let data = bincode_config::serialize(&ids).unwrap();
+        assert!(data.len() < bincode_config::serialize(&full).unwrap().len());
+
+        // the receiver fills the accounts in from its own copy of the pre-state
+        let ids: EngineDataIds = bincode_config::deserialize(&data).unwrap();
+        let mut resolved = ids.resolve(&genesis.l1.account_book).unwrap();
+        assert!(resolved.account_book.verify_partial_root());
+        assert_eq!(bincode_config::serialize(&resolved).unwrap(), bincode_config::serialize(&full).unwrap());
+        let mut full = full;
+        let (a, _) = crate::l1_engine::process(&mut resolved, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let (b, _) = crate::l1_engine::process(&mut full, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(a.hash(), b.hash());
+
+        // a local copy at another version is caught
+        let stale = genesis.l1.get_partial().ids_only();
+        genesis.l1.account_book.accounts.get_mut(&pk_to_hash(&genesis.alices[1].pk)).unwrap().amount = Balance(7);
+        assert_eq!(stale.resolve(&genesis.l1.account_book).unwrap_err(), "stale");
+    }
+
+    #[test]
+    fn l2_rejects_l1_txns_by_kind() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let deposit = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+        let update = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: vec![] }, &mut genesis.rollup.sk);
+
+        genesis.l2.txns = vec![Transaction::DepositL2(deposit.clone()), Transaction::RollupUpdate(update.clone())];
+        assert_eq!(crate::l2_engine::process(&mut genesis.l2).unwrap_err(), "l1 only: RollupUpdate");
+
+        // lenient: the misrouted txns are dropped, the rest of the block goes through
+        genesis.l2 = EngineData::new(faucet_pk, 0, Layer::L2);
+        genesis.l2.txns = vec![Transaction::Deposit(deposit.clone()), Transaction::DepositL2(deposit), Transaction::RollupUpdate(update)];
+        let (header, summary, stats) = crate::l2_engine::process_lenient(&mut genesis.l2).unwrap();
+        assert_eq!(header.inbox_msg_count, 1);
+        assert_eq!(summary.num_txns, 1);
+        assert_eq!(stats.per_kind[&TxKind::Deposit], (0, 1));
+        assert_eq!(stats.per_kind[&TxKind::RollupUpdate], (0, 1));
+    }
+
+    #[test]
+    fn partial_roots_to_source_for_any_ids() {
+        let keys = random_keys(8);
+        let book = AccountBook::new_batch(keys.clone(), PAY_AMOUNT);
+        let known = pk_to_hash(&keys[3]);
+        // ids only pick which proofs are kept: unknown, repeated, none at all
+        for ids in [vec![[0xabu8; 32]], vec![known, known, [1u8; 32]], vec![]] {
+            let partial = book.partial_for(&ids);
+            assert_eq!(partial.root(), book.root());
+            assert!(partial.accounts.len() <= 1);
+            assert!(partial.proof_tree.verify_partial());
+        }
+    }
+
+    #[test]
+    fn overspend_in_block_is_an_error() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let (alice, bob) = (genesis.alices[0].clone(), genesis.alices[1].pk);
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, 0, Payment { to: alice.pk, amount: Balance(100), expected_total: None }, &mut genesis.faucet.sk)));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+
+        // with a fee, the second payment passes sender_qualify (amount <= balance) but
+        // not the debit of amount + fee, after the first one spent in the same block
+        genesis.l1.account_book.set_params(ChainParams { fee_policy: FeePolicy::Flat(1), ..ChainParams::for_layer(Layer::L1) });
+        let mut sk = alice.sk.clone();
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(alice.pk, 0, Payment { to: bob, amount: Balance(50), expected_total: None }, &mut sk)));
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(alice.pk, 1, Payment { to: bob, amount: Balance(49), expected_total: None }, &mut sk)));
+        assert_eq!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap_err(), "balance");
+
+        // a deposit bigger than the balance leaves the rollup alone
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let book = &mut genesis.l1.account_book;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        book.process_create_rollup_account(&tx, 0).unwrap();
+        book.accounts.get_mut(&pk_to_hash(&faucet_pk)).unwrap().amount = Balance(5);
+        let tx = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: 6 }, &mut genesis.faucet.sk);
+        assert!(book.process_deposit_l1(&tx, 0).is_err());
+        assert!(book.accounts[&pk_to_hash(&rollup_pk)].rollup.as_ref().is_some_and(|ru| ru.inbox.is_empty()));
+    }
+
+    #[test]
+    fn delegate_spends_within_allowance() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let (mut bot, bob) = (genesis.alices[0].clone(), genesis.alices[1].pk);
+        let fid = pk_to_hash(&faucet_pk);
+        let tx = l1_tx(faucet_pk, 0, SetDelegate { delegate: bot.pk, allowance: 10 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::SetDelegate(tx));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert!(genesis.l1.account_book.account_hash_verify(&faucet_pk, |a| a.delegates[&pk_to_hash(&bot.pk)] == 10));
+
+        // signed by the delegate, paid from the faucet, the allowance shrinks
+        let before = genesis.l1.account_book.accounts[&fid].amount;
+        let tx = delegated(faucet_pk, 1, Payment { to: bob, amount: Balance(6), expected_total: None }, &mut bot.sk);
+        assert!(tx.sig_verify());
+        genesis.l1.txns.push(Transaction::Pay(tx));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let book = &mut genesis.l1.account_book;
+        assert_eq!(book.accounts[&fid].amount, before.0 - 6);
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.sqn_expect == 2 && a.delegates[&pk_to_hash(&bot.pk)] == 4));
+
+        // beyond the allowance, or signed by a key that is no delegate
+        let book = &mut genesis.l1.account_book;
+        let tx = delegated(faucet_pk, 2, Payment { to: bob, amount: Balance(5), expected_total: None }, &mut bot.sk);
+        assert_eq!(book.process_payment(&tx, 0).unwrap_err(), "allowance");
+        let mut stranger = genesis.alices[1].sk.clone();
+        let tx = delegated(faucet_pk, 2, Payment { to: bob, amount: Balance(1), expected_total: None }, &mut stranger);
+        assert_eq!(book.process_payment(&tx, 0).unwrap_err(), "allowance");
+        // delegates only move funds, they can't hand out allowances themselves
+        let tx = delegated(faucet_pk, 2, SetDelegate { delegate: bob, allowance: 1 }, &mut bot.sk);
+        assert_eq!(book.process_set_delegate(&tx, 0).unwrap_err(), "delegate");
+
+        // spending the rest removes the delegate
+        let tx = delegated(faucet_pk, 2, Payment { to: bob, amount: Balance(4), expected_total: None }, &mut bot.sk);
+        let updates = book.process_payment(&tx, 0).unwrap();
+        book.update_tree(updates);
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.delegates.is_empty()));
+    }
+
+    #[test]
+    fn delegated_payment_on_l2() {
+        let mut genesis = Genesis::new(3);
+        let (mut owner, mut bot, bob) = (genesis.alices[0].clone(), genesis.alices[1].clone(), genesis.alices[2].pk);
+        let mut l2 = EngineData::new_batch(genesis.alices.iter().map(|a| a.pk).collect(), PAY_AMOUNT, Layer::L2);
+        let tx = l2_tx(owner.pk, 0, SetDelegate { delegate: bot.pk, allowance: 5 }, &mut owner.sk);
+        l2.txns.push(Transaction::SetDelegate(tx));
+        crate::l2_engine::process(&mut l2).unwrap();
+
+        // signed for L1, the chain id keeps it off L2
+        let tx = Tx::new_delegated(L1_CHAIN_ID, owner.pk, 1, Payment { to: bob, amount: Balance(3), expected_total: None }, &mut bot.sk);
+        l2.txns = vec![Transaction::Pay(tx)];
+        assert_eq!(crate::l2_engine::process(&mut l2).unwrap_err(), EngineError::WrongChain);
+
+        let tx = Tx::new_delegated(L2_CHAIN_ID, owner.pk, 1, Payment { to: bob, amount: Balance(3), expected_total: None }, &mut bot.sk);
+        l2.txns = vec![Transaction::Pay(tx)];
+        crate::l2_engine::process(&mut l2).unwrap();
+        let book = &l2.account_book;
+        assert!(book.account_hash_verify(&owner.pk, |a| a.amount == PAY_AMOUNT - 3 && a.sqn_expect == 2 && a.delegates[&pk_to_hash(&bot.pk)] == 2));
+        assert!(book.account_hash_verify(&bob, |a| a.amount == PAY_AMOUNT + 3));
+        assert!(book.account_hash_verify(&bot.pk, |a| a.amount == PAY_AMOUNT && a.sqn_expect == 0));
+    }
+
+    #[test]
+    fn credit_overflow_rejected() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let bob = genesis.alices[0].pk;
+        let (fid, bid) = (pk_to_hash(&faucet_pk), pk_to_hash(&bob));
+        let book = &mut genesis.l1.account_book;
+        book.set_params(ChainParams { rollup_slash_bps: 5_000, ..ChainParams::for_layer(Layer::L1) });
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 100, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        book.process_create_rollup_account(&tx, 0).unwrap();
+        book.accounts.get_mut(&pk_to_hash(&rollup_pk)).unwrap().amount = Balance(10);
+        book.get_account_or_new(bob).amount = Balance(u128::MAX - 1);
+
+        // payment
+        let before = book.accounts[&fid].clone();
+        let tx = l1_tx(faucet_pk, 1, Payment { to: bob, amount: Balance(2), expected_total: None }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_payment(&tx, 0).unwrap_err(), "overflow");
+        assert_eq!(book.accounts[&fid].hash(), before.hash());
+        assert_eq!(book.accounts[&bid].amount, u128::MAX - 1);
+
+        // withdrawal into bob, rejected without slashing the rollup
+        let w = |amount| WithdrawalRecord { to: bob, amount: Balance(amount) };
+        let header = BlockHeaderL2 {
+            parent: Hash::default(),
+            state_root: Hash::default(),
+            sqn: 0,
+            timestamp: 1,
+            txns_hash: Hash::default(),
+            inbox_msg_hash: DefaultHasher::new().finalize().as_slice().try_into().unwrap(),
+            inbox_msg_count: 0,
+            withdrawals: vec![w(1), w(1)],
+            total_fees: 0,
+            withdrawals_root: Hash::default(),
+        };
+        let data = bincode_config::serialize(&header).unwrap();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        assert_eq!(book.process_rollup_state_update(&tx, 0, |d| bincode_config::deserialize(d)).unwrap_err(), "overflow");
+        assert!(book.accounts[&pk_to_hash(&rollup_pk)].rollup.as_ref().is_some_and(|ru| ru.bond == 100 && ru.sqn == 0));
+        assert_eq!(book.accounts[&pk_to_hash(&rollup_pk)].amount, 10);
+
+        // L2 deposit into an account that is full
+        let mut l2 = genesis.l2.account_book;
+        l2.get_account_or_new(bob).amount = Balance(u128::MAX);
+        let tx = l1_tx(bob, 0, L1ToL2Deposit { rollup_pk, amount: 1 }, &mut genesis.alices[0].sk);
+        assert_eq!(l2.process_deposit_l2(&tx).unwrap_err(), "overflow");
+        assert_eq!(l2.accounts[&bid].amount, u128::MAX);
+    }
+
+    #[test]
+    fn metrics_sum_block_summaries() {
+        let mut genesis = Genesis::new(3);
+        let faucet_pk = genesis.faucet.pk;
+        genesis.l1.account_book.set_params(ChainParams { fee_policy: FeePolicy::Flat(2), ..ChainParams::for_layer(Layer::L1) });
+        let mut expect = BlockSummary::default();
+        for (block, n) in [1usize, 3, 2].into_iter().enumerate() {
+            for i in 0..n {
+                let to = genesis.alices[i].pk;
+                let tx = l1_tx(faucet_pk, genesis.faucet.sqn, Payment { to, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+                genesis.faucet.sqn += 1;
+                genesis.l1.txns.push(Transaction::Pay(tx));
+            }
+            let (_, summary) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+            expect.num_txns += summary.num_txns;
+            expect.total_withdrawn += summary.total_withdrawn;
+            assert_eq!(genesis.l1.metrics.blocks, block as u64 + 1);
+        }
+        let m = genesis.l1.metrics.snapshot();
+        assert_eq!(m.txns, expect.num_txns as u64);
+        assert_eq!(m.withdrawn, expect.total_withdrawn);
+        assert_eq!(m.fees, 2 * 6);
+        assert_eq!(m.peak_accounts, 4);
+    }
+
+    #[test]
+    fn self_payment_keeps_leaf_in_sync() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice = genesis.alices[0].pk;
+        // burned fee, so the self-payment still changes the balance
+        genesis.l1.account_book.set_params(ChainParams { fee_policy: FeePolicy::Flat(1), ..ChainParams::for_layer(Layer::L1) });
+        let sk = &mut genesis.faucet.sk;
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, 0, Payment { to: faucet_pk, amount: Balance(50), expected_total: None }, sk)));
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, 1, Payment { to: alice, amount: Balance(10), expected_total: None }, sk)));
+        genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, 2, Payment { to: faucet_pk, amount: Balance(GENESIS_AMOUNT - 13), expected_total: None }, sk)));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let book = &mut genesis.l1.account_book;
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.amount == GENESIS_AMOUNT - 13 && a.sqn_expect == 3));
+        let leaf = book.proof_tree.get(&pk_to_hash(&faucet_pk));
+        assert_eq!(leaf, Some(book.accounts[&pk_to_hash(&faucet_pk)].hash()));
+    }
+
+    #[test]
+    fn payment_expected_total() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice = genesis.alices[0].pk;
+        let book = &mut genesis.l1.account_book;
+        book.set_params(ChainParams { fee_policy: FeePolicy::BasisPoints(100), ..ChainParams::for_layer(Layer::L1) });
+        let pay = |sqn, expected_total, sk: &mut SigningKey| {
+            l1_tx(faucet_pk, sqn, Payment { to: alice, amount: Balance(1_000), expected_total }, sk)
+        };
+        // the sender assumed no fee
+        let tx = pay(0, Some(1_000), &mut genesis.faucet.sk);
+        assert_eq!(book.process_payment(&tx, 0).unwrap_err(), "total_mismatch");
+        // 1% of 1_000 on top
+        let tx = pay(0, Some(1_010), &mut genesis.faucet.sk);
+        book.process_payment(&tx, 0).unwrap();
+        assert_eq!(book.accounts[&pk_to_hash(&alice)].amount, 1_000);
+        // the field is signed over
+        let a = pay(1, None, &mut genesis.faucet.sk);
+        let b = pay(1, Some(1_010), &mut genesis.faucet.sk);
+        assert_ne!(a.id(), b.id());
+    }
+
+    #[test]
+    fn last_sqn_rejected() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice = genesis.alices[0].pk;
+        let book = &mut genesis.l1.account_book;
+        book.accounts.get_mut(&pk_to_hash(&faucet_pk)).unwrap().sqn_expect = u32::MAX;
+        let before = book.accounts[&pk_to_hash(&faucet_pk)].hash();
+        let tx = l1_tx(faucet_pk, u32::MAX, Payment { to: alice, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_payment(&tx, 0).unwrap_err(), "sqn_overflow");
+        let tx = l1_tx(faucet_pk, u32::MAX, L1ToL2Deposit { rollup_pk: alice, amount: 1 }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_deposit_l1(&tx, 0).unwrap_err(), "sqn_overflow");
+        assert_eq!(book.accounts[&pk_to_hash(&faucet_pk)].hash(), before);
+    }
+
+    #[test]
+    fn partial_for_hand_picked_ids() {
+        let keys = random_keys(6);
+        let book = AccountBook::new_batch(keys.clone(), PAY_AMOUNT);
+        let ids: Vec<AccountID> = [0, 2, 5].iter().map(|i| pk_to_hash(&keys[*i])).collect();
+        let partial = book.partial_for(&ids);
+        assert_eq!(partial.root(), book.root());
+        assert_eq!(partial.get_num_accounts(), 3);
+        assert!(partial.verify_partial_root());
+        for id in &ids {
+            assert_eq!(partial.proof_tree.get(id), Some(book.accounts[id].hash()));
+        }
+        assert!(!partial.accounts.contains_key(&pk_to_hash(&keys[1])));
+    }
+
+    #[test]
+    fn balance_of_matches_get_account() {
+        let mut genesis = Genesis::new(3);
+        let faucet_pk = genesis.faucet.pk;
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let amount = Balance(10 * (i as u128 + 1));
+            genesis.l1.txns.push(Transaction::Pay(l1_tx(faucet_pk, i as u32, Payment { to: alice.pk, amount, expected_total: None }, &mut genesis.faucet.sk)));
+        }
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let book = &mut genesis.l1.account_book;
+        let ids: Vec<AccountID> = genesis.alices.iter().map(|a| a.pk).chain([faucet_pk]).map(|pk| pk_to_hash(&pk)).collect();
+        for id in &ids {
+            let seen = book.get_account(id).map(|a| (a.amount.0, a.hash()));
+            assert_eq!(book.balance_of(id), seen.map(|s| s.0));
+            assert_eq!(book.account_ref(id).map(|a| a.hash()), seen.map(|s| s.1));
+        }
+        // two readers at once
+        let (a, b) = (book.balance_of(&ids[0]), book.account_ref(&ids[3]));
+        assert_eq!(a, Some(10));
+        assert_eq!(b.unwrap().amount, GENESIS_AMOUNT - 60);
+        assert_eq!(book.balance_of(&[7u8; 32]), None);
+    }
+
+    #[test]
+    fn iter_accounts_sums_batch() {
+        let keys = random_keys(5);
+        let book = AccountBook::new_batch(keys.clone(), PAY_AMOUNT);
+        let total = book.iter_accounts().try_fold(0u128, |t, (_, a)| t.checked_add(a.amount.0)).unwrap();
+        assert_eq!(total, keys.len() as u128 * PAY_AMOUNT);
+        let ids: Vec<AccountID> = book.iter_accounts().map(|(id, _)| *id).collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert!(book.iter_accounts().all(|(id, a)| *id == a.id()));
+    }
+
+    #[test]
+    fn batch_payment_to_ten() {
+        let mut genesis = Genesis::new(10);
+        let faucet_pk = genesis.faucet.pk;
+        let outputs: Vec<(VerifyingKey, u128)> = genesis.alices.iter().enumerate().map(|(i, a)| (a.pk, i as u128 + 1)).collect();
+        let tx = l1_tx(faucet_pk, 0, BatchPayment { outputs: outputs.clone() }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::BatchPay(tx));
+        let (_, summary) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(summary.total_paid, 55);
+        let book = &mut genesis.l1.account_book;
+        for (pk, amount) in &outputs {
+            assert!(book.account_hash_verify(pk, |a| a.amount == *amount && a.sqn_expect == 0));
+        }
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.amount == GENESIS_AMOUNT - 55 && a.sqn_expect == 1));
+
+        // all or nothing
+        let alice = genesis.alices[0].pk;
+        let tx = l1_tx(faucet_pk, 1, BatchPayment { outputs: vec![(alice, 1), (alice, u128::MAX)] }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_batch_payment(&tx, 0).unwrap_err(), "sender");
+        let tx = l1_tx(faucet_pk, 1, BatchPayment { outputs: vec![] }, &mut genesis.faucet.sk);
+        assert_eq!(book.process_batch_payment(&tx, 0).unwrap_err(), "outputs");
+        assert_eq!(book.balance_of(&pk_to_hash(&alice)), Some(1));
+    }
+
+    #[test]
+    fn l2_header_totals_fees() {
+        let mut genesis = Genesis::new(3);
+        let (collector, bob) = (genesis.alices[1].pk, genesis.alices[2].pk);
+        let mut alice = genesis.alices[0].clone();
+        let l2 = &mut genesis.l2;
+        let tx = l1_tx(alice.pk, 0, L1ToL2Deposit { rollup_pk: genesis.rollup.pk, amount: 1_000 }, &mut alice.sk);
+        l2.account_book.process_deposit_l2(&tx).map(|r| l2.account_book.update_tree(r)).unwrap();
+        l2.account_book.set_params(ChainParams { fee_policy: FeePolicy::BasisPoints(500), fee_collector: Some(collector), ..ChainParams::for_layer(Layer::L2) });
+
+        // 5% of each, rounded up: 5 + 3 + 1
+        for (sqn, amount) in [(0, 100), (1, 50), (2, 1)] {
+            l2.txns.push(Transaction::Pay(l2_tx(alice.pk, sqn, Payment { to: bob, amount: Balance(amount), expected_total: None }, &mut alice.sk)));
+        }
+        let (header, summary) = crate::l2_engine::process(l2).unwrap();
+        assert_eq!(header.total_fees, 9);
+        assert_eq!(summary.total_fees, 9);
+        let book = &mut l2.account_book;
+        assert!(book.account_hash_verify(&alice.pk, |a| a.amount == 1_000 - 151 - 9));
+        assert!(book.account_hash_verify(&bob, |a| a.amount == 151));
+        assert!(book.account_hash_verify(&collector, |a| a.amount == 9));
+
+        // a block without fees
+        let (header, _) = crate::l2_engine::process(l2).unwrap();
+        assert_eq!(header.total_fees, 0);
+    }
+
+    #[test]
+    fn restore_undoes_a_failed_block() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let (alice, bob) = (genesis.alices[0].pk, genesis.alices[1].pk);
+        let book = &mut genesis.l1.account_book;
+        book.set_params(ChainParams { interest_ppb: 1_000, ..ChainParams::for_layer(Layer::L1) });
+        let before = bincode_config::serialize(&*book).unwrap();
+        let snap = book.snapshot();
+        assert_eq!(snap.root(), book.root());
+
+        let sk = &mut genesis.faucet.sk;
+        let txns = vec![
+            Transaction::Pay(l1_tx(faucet_pk, 0, Payment { to: alice, amount: Balance(PAY_AMOUNT), expected_total: None }, sk)),
+            Transaction::Pay(l1_tx(faucet_pk, 1, Payment { to: bob, amount: Balance(PAY_AMOUNT), expected_total: None }, sk)),
+            // sqn reused
+            Transaction::Pay(l1_tx(faucet_pk, 1, Payment { to: bob, amount: Balance(PAY_AMOUNT), expected_total: None }, sk)),
+        ];
+        assert_eq!(book.apply_transactions(&txns, Layer::L1, 5).unwrap_err(), "sqn");
+        assert_ne!(bincode_config::serialize(&*book).unwrap(), before);
+
+        book.restore(snap);
+        assert_eq!(bincode_config::serialize(&*book).unwrap(), before);
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.sqn_expect == 0 && a.amount == GENESIS_AMOUNT));
+    }
+
+    #[test]
+    fn failed_l2_block_changes_nothing() {
+        let mut genesis = Genesis::new(1);
+        let mut alice = genesis.alices[0].clone();
+        let bob = TxSigner::new(SigningKey::random(&mut OsRng)).pk;
+        let tx = l1_tx(alice.pk, 0, L1ToL2Deposit { rollup_pk: genesis.rollup.pk, amount: PAY_AMOUNT }, &mut alice.sk);
+        genesis.l2.txns.push(Transaction::DepositL2(tx));
+        crate::l2_engine::process(&mut genesis.l2).unwrap();
+        let before = bincode_config::serialize(&genesis.l2.account_book).unwrap();
+        let root = *genesis.l2.account_book.root();
+
+        let good = l2_tx(alice.pk, 0, Payment { to: bob, amount: Balance(1), expected_total: None }, &mut alice.sk);
+        // signed by someone else
+        let bad = l2_tx(alice.pk, 1, Payment { to: bob, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l2.txns = vec![Transaction::Pay(good), Transaction::Pay(bad)];
+        assert_eq!(crate::l2_engine::process(&mut genesis.l2).unwrap_err(), "sig");
+        assert_eq!(*genesis.l2.account_book.root(), root);
+        assert_eq!(bincode_config::serialize(&genesis.l2.account_book).unwrap(), before);
+        assert!(genesis.l2.account_book.account_ref(&pk_to_hash(&bob)).is_none());
+    }
+
+    #[test]
+    fn engine_errors_are_typed() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let alice = genesis.alices[0].pk;
+        let book = &mut genesis.l1.account_book;
+        let tx = l1_tx(faucet_pk, 1, Payment { to: alice, amount: Balance(1), expected_total: None }, &mut genesis.faucet.sk);
+        assert!(matches!(book.process_payment(&tx, 0), Err(EngineError::BadSqn)));
+        let tx = l1_tx(faucet_pk, 0, Payment { to: alice, amount: Balance(GENESIS_AMOUNT + 1), expected_total: None }, &mut genesis.faucet.sk);
+        assert!(matches!(book.process_payment(&tx, 0), Err(EngineError::SenderUnqualified)));
+        let tx = l1_tx(faucet_pk, 0, BatchPayment { outputs: vec![] }, &mut genesis.faucet.sk);
+        assert!(matches!(book.process_batch_payment(&tx, 0), Err(EngineError::NoOutputs)));
+
+        let tx = l2_tx(faucet_pk, 0, L2ToL1Withdrawal { amount: Balance(1), to: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns = vec![Transaction::Withdrawal(tx)];
+        let e = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap_err();
+        assert_eq!(e, EngineError::WrongLayer(TxKind::Withdrawal));
+        assert_eq!(e.to_string(), TxKind::Withdrawal.wrong_layer());
+
+        let host: crate::error::Error = EngineError::BadSig.into();
+        assert_eq!(host.to_string(), "engine: sig");
+        assert!(matches!(host, crate::error::Error::Engine(EngineError::BadSig)));
+    }
+
+    #[test]
+    fn validate_matches_process() {
+        let [mut faucet, mut alice, bob, fresh] = [(); 4].map(|_| TxSigner::new(SigningKey::random(&mut OsRng)));
+        let (bob, fresh) = (bob.pk, fresh.pk);
+        let mut l1_book = AccountBook::new_batch(vec![faucet.pk, alice.pk, bob], GENESIS_AMOUNT);
+        let mut l2_book = AccountBook::new_batch(vec![alice.pk], GENESIS_AMOUNT);
+        let pay = |to, amount| Payment { to, amount: Balance(amount), expected_total: None };
+        let l1 = vec![
+            Transaction::Pay(Tx::new(faucet.pk, 0, pay(alice.pk, 5), &mut faucet.sk)),
+            Transaction::Pay(Tx::new(faucet.pk, 1, pay(alice.pk, 5), &mut faucet.sk)),
+            Transaction::Pay(Tx::new(faucet.pk, 0, pay(alice.pk, 5), &mut alice.sk)),
+            Transaction::Pay(Tx::new(alice.pk, 0, pay(bob, GENESIS_AMOUNT + 1), &mut alice.sk)),
+            Transaction::ConditionalPay(Tx::new(faucet.pk, 0, ConditionalPayment { to: alice.pk, amount: Balance(1), max_recipient_balance: 0 }, &mut faucet.sk)),
+            Transaction::Swap(Tx::new(alice.pk, 0, Swap::new(&alice.pk, 0, 0, None, 1, 1, &mut alice.sk.clone()), &mut alice.sk)),
+            Transaction::SetDelegate(Tx::new(alice.pk, 0, SetDelegate { delegate: alice.pk, allowance: 1 }, &mut alice.sk)),
+            Transaction::BatchPay(Tx::new(alice.pk, 0, BatchPayment { outputs: vec![] }, &mut alice.sk)),
+            Transaction::Deposit(Tx::new(faucet.pk, 0, L1ToL2Deposit { rollup_pk: fresh, amount: 1 }, &mut faucet.sk)),
+            Transaction::RollupCreate(Tx::new(faucet.pk, 0, CreateRollupAccount { rollup_pk: alice.pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut faucet.sk)),
+            Transaction::RollupCreateFund(Tx::new(faucet.pk, 0, CreateAndFundRollup { rollup_pk: fresh, amount: 1, bond: 0 }, &mut faucet.sk)),
+            Transaction::Unknown { tag: 99, bytes: vec![] },
+        ];
+        let l2 = vec![
+            Transaction::Withdrawal(Tx::new(alice.pk, 0, L2ToL1Withdrawal { amount: Balance(GENESIS_AMOUNT + 1), to: None }, &mut alice.sk)),
+            Transaction::Withdrawal(Tx::new(alice.pk, 0, L2ToL1Withdrawal { amount: Balance(1), to: None }, &mut alice.sk)),
+        ];
+        let mut errors = BTreeSet::new();
+        for (book, txns, layer) in [(&mut l1_book, l1, Layer::L1), (&mut l2_book, l2, Layer::L2)] {
+            for t in txns {
+                let root = *book.root();
+                let validated = book.validate(&t, layer, 1);
+                assert_eq!(*book.root(), root);
+                let snap = book.snapshot();
+                let processed = book.apply_transactions(std::slice::from_ref(&t), layer, 1).map(|_| ());
+                book.restore(snap);
+                assert_eq!(validated, processed, "{:?}", t.kind());
+                if let Err(e) = validated {
+                    errors.insert(e.as_str());
+                }
+            }
+        }
+        // the cases above really reach different checks
+        assert!(errors.len() >= 10, "{:?}", errors);
+    }
+
+    #[test]
+    fn shuffled_payments_apply_after_order_by_sqn() {
+        let mut genesis = Genesis::new(1);
+        let faucet = &mut genesis.faucet;
+        let mut txns = vec![];
+        for sqn in [2, 0, 1] {
+            let tx = l1_tx(faucet.pk, sqn, Payment { to: genesis.alices[0].pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut faucet.sk);
+            txns.push(Transaction::Pay(tx));
+        }
+        // in arrival order the block fails, and being atomic changes nothing
+        genesis.l1.txns = txns.clone();
+        let root = *genesis.l1.account_book.root();
+        assert_eq!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap_err(), EngineError::BadSqn);
+        assert_eq!(*genesis.l1.account_book.root(), root);
+
+        let ordered = crate::mempool::order_by_sqn(txns);
+        let sqns: Vec<u32> = ordered.iter().map(|t| t.sender_sqn().unwrap().1).collect();
+        assert_eq!(sqns, vec![0, 1, 2]);
+        genesis.l1.txns = ordered;
+        let (_, summary) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(summary.num_txns, 3);
+        assert_eq!(summary.total_paid, 3 * PAY_AMOUNT);
+    }
+
+    fn scheme_round_trip<S: SignatureScheme>(sk: &mut S::SigningKey, other: &S::VerifyingKey) {
+        let pk = S::verifying_key(sk);
+        let payload = Payment { to: TxSigner::new(SigningKey::random(&mut OsRng)).pk, amount: Balance(7), expected_total: None };
+        let tx = Tx::<Payment, S>::new_signed(pk.clone(), 3, 0, Some(9), None, payload, sk);
+        assert!(tx.sig_verify());
+        let decoded: Tx<Payment, S> = bincode_config::deserialize(&bincode_config::serialize(&tx).unwrap()).unwrap();
+        assert!(decoded.sig_verify());
+        assert_eq!(decoded.id(), tx.id());
+        // the signature covers the sender and every field
+        let mut forged = tx.clone();
+        forged.sender = other.clone();
+        assert!(!forged.sig_verify());
+        let mut forged = tx.clone();
+        forged.payload.amount = Balance(8);
+        assert!(!forged.sig_verify());
+        let mut forged = tx;
+        forged.valid_until = None;
+        assert!(!forged.sig_verify());
+    }
+
+    #[test]
+    fn secp256k1_scheme_round_trip() {
+        let mut sk = SigningKey::random(&mut OsRng);
+        let other = *SigningKey::random(&mut OsRng).verifying_key();
+        scheme_round_trip::<Secp256k1>(&mut sk, &other);
+        // the default scheme, and the constructors for it sign the same way
+        let pk = *sk.verifying_key();
+        let tx = Tx::new(pk, 0, SetDelegate { delegate: other, allowance: 1 }, &mut sk);
+        let same = Tx::<SetDelegate, Secp256k1>::new_signed(pk, 0, 0, None, None, SetDelegate { delegate: other, allowance: 1 }, &mut sk);
+        assert_eq!(tx.id(), same.id());
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn ed25519_scheme_round_trip() {
+        let mut seed = [0u8; 32];
+        rand::RngCore::fill_bytes(&mut OsRng, &mut seed);
+        let mut sk = ed25519_dalek::SigningKey::from_bytes(&seed);
+        let other = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]).verifying_key();
+        scheme_round_trip::<Ed25519>(&mut sk, &other);
+    }
+
+    #[test]
+    fn verify_all_sigs_finds_the_forged_txn() {
+        let mut signers = TxSigner::batch(4, &mut OsRng);
+        let recipients = random_keys(10);
+        let keys: Vec<VerifyingKey> = signers.iter().map(|s| s.pk).collect();
+        let txns = crate::testkit::generate_payment_load(&mut signers, &recipients, 100, Balance(1), L2_CHAIN_ID);
+        assert_eq!(verify_all_sigs(&txns), Ok(()));
+
+        let mut l2 = EngineData::new_batch(keys.clone(), PAY_AMOUNT * 1_000, Layer::L2);
+        let root = *l2.account_book.root();
+        let accounts = l2.account_book.accounts.clone();
+        // first, somewhere in the middle, last: the block fails before any txn is applied
+        for forged in [0, 57, 99] {
+            let mut block = txns.clone();
+            if let Transaction::Pay(tx) = &mut block[forged] {
+                tx.payload.amount = Balance(2);
+            }
+            assert_eq!(verify_all_sigs(&block), Err(EngineError::BadSigAt(forged as u32)));
+            l2.txns = block.clone();
+            l2.verify_sigs_first = false;
+            assert_eq!(crate::l2_engine::process(&mut l2).unwrap_err(), EngineError::BadSig);
+            l2.txns = block;
+            l2.verify_sigs_first = true;
+            assert_eq!(crate::l2_engine::process(&mut l2).unwrap_err(), EngineError::BadSigAt(forged as u32));
+            assert_eq!(*l2.account_book.root(), root);
+            assert!(l2.account_book.accounts.iter().all(|(id, a)| a.hash() == accounts[id].hash()));
+            assert_eq!(l2.account_book.accounts.len(), accounts.len());
+        }
+
+        // with no forged txn both ways give the same block
+        l2.txns = txns.clone();
+        let (header, _) = crate::l2_engine::process(&mut l2).unwrap();
+        assert!(!l2.account_book.sigs_verified);
+        let mut per_tx = EngineData::new_batch(keys, PAY_AMOUNT * 1_000, Layer::L2);
+        per_tx.txns = txns;
+        assert_eq!(crate::l2_engine::process(&mut per_tx).unwrap().0.hash(), header.hash());
+    }
+
+    #[test]
+    fn cached_id_matches_and_is_not_serialized() {
+        let mut genesis = Genesis::new(1);
+        let tx = Tx::new(genesis.faucet.pk, 0, Payment { to: genesis.alices[0].pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        let before = bincode_config::serialize(&tx).unwrap();
+        assert_eq!(tx.id_cached(), tx.id());
+        assert_eq!(tx.id_cached(), tx.id());
+        // the cache adds nothing to the encoding, and a decoded tx hashes to the same id
+        assert_eq!(bincode_config::serialize(&tx).unwrap(), before);
+        let decoded: Tx<Payment> = bincode_config::deserialize(&before).unwrap();
+        assert_eq!(decoded.id_cached(), tx.id());
+        let t = Transaction::Pay(tx);
+        assert_eq!(t.id(), decoded.id());
+        assert_eq!(tx_set_hash(&vec![t]), tx_set_hash(&vec![Transaction::Pay(decoded)]));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn update_tree_rehashes_in_parallel() {
+        let mut signers = TxSigner::batch(8, &mut OsRng);
+        let recipients = random_keys(40);
+        let keys: Vec<VerifyingKey> = signers.iter().map(|s| s.pk).collect();
+        let txns = crate::testkit::generate_payment_load(&mut signers, &recipients, 400, Balance(1), 0);
+
+        let mut book = AccountBook::new_batch(keys, PAY_AMOUNT * 1_000);
+        let root = *book.root();
+        let Transaction::Pay(pay) = &txns[0] else { unreachable!() };
+        let r = book.process_payment(pay, 0).unwrap();
+        // the hashing is left to update_tree
+        assert!(r.iter().all(|(_, h)| *h == UNHASHED));
+        assert_eq!(*book.root(), root);
+        let leaves = book.update_tree(r);
+        assert!(leaves.iter().all(|(id, h)| *h == book.accounts[id].hash()));
+
+        let changes = book.apply_transactions(&txns[1..], Layer::L2, 0).unwrap();
+        assert!(changes.iter().all(|(id, h)| *h == book.accounts[id].hash()));
+        assert_eq!(*book.root(), book.recompute_root());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trip() {
+        use crate::json::{from_json, to_json};
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let tx = Tx::new(faucet_pk, 0, Payment { to: genesis.alices[0].pk, amount: Balance(PAY_AMOUNT), expected_total: Some(PAY_AMOUNT) }, &mut genesis.faucet.sk);
+        let t = Transaction::Pay(tx);
+        let json = to_json(&t).unwrap();
+        assert!(json.starts_with("{\"Pay\":"), "{}", json);
+        // the sig is a hex string, not an array of numbers
+        assert!(json.contains("\"sig\":\""), "{}", json);
+        let back: Transaction = from_json(&json).unwrap();
+        assert_eq!(back.id(), t.id());
+        assert!(back.sig_verify());
+        let unknown: Transaction = from_json(&to_json(&Transaction::Unknown { tag: 99, bytes: vec![1, 2] }).unwrap()).unwrap();
+        assert!(matches!(unknown, Transaction::Unknown { tag: 99, .. }));
+
+        let tx = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk: genesis.rollup.pk, amount: 5 }, &mut genesis.faucet.sk);
+        let h1 = BlockHeaderL1 { sqn: 3, events: vec![tx], ..Default::default() };
+        assert_eq!(from_json::<BlockHeaderL1>(&to_json(&h1).unwrap()).unwrap().hash(), h1.hash());
+        let h2 = BlockHeaderL2 { sqn: 4, withdrawals: vec![WithdrawalRecord { to: faucet_pk, amount: Balance(7) }], total_fees: u128::MAX, ..Default::default() };
+        assert_eq!(from_json::<BlockHeaderL2>(&to_json(&h2).unwrap()).unwrap().hash(), h2.hash());
+        assert_eq!(from_json::<Transaction>("{\"Pay\":1}").unwrap_err(), EngineError::Deserialize);
+    }
+
+    #[cfg(feature = "abi")]
+    #[test]
+    fn l2_header_abi_round_trip() {
+        use alloy_sol_types::SolValue;
+        use crate::abi::SolBlockHeaderL2;
+        let keys = random_keys(2);
+        let header = BlockHeaderL2 {
+            parent: [1; 32],
+            state_root: [2; 32],
+            sqn: 7,
+            timestamp: 8,
+            txns_hash: [3; 32],
+            inbox_msg_hash: [4; 32],
+            inbox_msg_count: 9,
+            withdrawals: keys.iter().enumerate().map(|(i, k)| WithdrawalRecord { to: *k, amount: Balance(i as u128 + 10) }).collect(),
+            total_fees: u128::MAX,
+            withdrawals_root: [5; 32],
+        };
+        let bytes = header.abi_encode();
+        assert_eq!(bytes, header.abi_encode());
+        let decoded = SolBlockHeaderL2::abi_decode(&bytes, true).unwrap();
+        assert_eq!(decoded.parent.0, header.parent);
+        assert_eq!(decoded.state_root.0, header.state_root);
+        assert_eq!((decoded.sqn, decoded.timestamp, decoded.inbox_msg_count), (7, 8, 9));
+        assert_eq!(decoded.txns_hash.0, header.txns_hash);
+        assert_eq!(decoded.inbox_msg_hash.0, header.inbox_msg_hash);
+        assert_eq!(decoded.total_fees, u128::MAX);
+        assert_eq!(decoded.withdrawals_root.0, header.withdrawals_root);
+        assert_eq!(decoded.withdrawals.len(), 2);
+        for (w, k) in decoded.withdrawals.iter().zip(&keys) {
+            assert_eq!(VerifyingKey::from_sec1_bytes(&w.to).unwrap(), *k);
+        }
+        assert_eq!(decoded.withdrawals[1].amount, 11);
+    }
+
+    #[test]
+    fn withdrawal_proofs_verify_against_header() {
+        let mut signers = TxSigner::batch(5, &mut OsRng);
+        let mut l2 = EngineData::new_batch(signers.iter().map(|s| s.pk).collect(), PAY_AMOUNT, Layer::L2);
+        for (i, s) in signers.iter_mut().enumerate() {
+            let tx = l2_tx(s.pk, 0, L2ToL1Withdrawal { amount: Balance(i as u128 + 1), to: None }, &mut s.sk);
+            l2.txns.push(Transaction::Withdrawal(tx));
+        }
+        let (header, _) = crate::l2_engine::process(&mut l2).unwrap();
+        assert_eq!(header.withdrawals.len(), 5);
+        assert_eq!(header.withdrawals_root, withdrawals_root(&header.withdrawals));
+        for (i, w) in header.withdrawals.iter().enumerate() {
+            let proof = header.withdrawal_proof(i).unwrap();
+            assert!(proof.verify(w, &header.withdrawals_root));
+            // not for another withdrawal, or another amount
+            let other = &header.withdrawals[(i + 1) % 5];
+            assert!(!proof.verify(other, &header.withdrawals_root));
+            assert!(!proof.verify(&WithdrawalRecord { to: w.to, amount: Balance(100) }, &header.withdrawals_root));
+            // the same siblings claiming another position, or another block size
+            for index in (0..5).filter(|j| *j != i as u32) {
+                assert!(!WithdrawalProof { index, ..proof.clone() }.verify(w, &header.withdrawals_root));
+            }
+            assert!(!WithdrawalProof { leaves: 4, ..proof.clone() }.verify(w, &header.withdrawals_root));
+        }
+        assert!(header.withdrawal_proof(5).is_none());
+
+        // one withdrawal is its own root, none is all zeros
+        let one = &header.withdrawals[..1];
+        assert_eq!(withdrawals_root(one), one[0].leaf_hash(0));
+        assert!(WithdrawalProof::new(one, 0).unwrap().siblings.is_empty());
+        assert_eq!(withdrawals_root(&[]), Hash::default());
+    }
+
+    #[test]
+    fn account_proof_verifies_without_the_book() {
+        let mut genesis = Genesis::new(2);
+        let alice = genesis.alices[0].pk;
+        let tx = l1_tx(genesis.faucet.pk, 0, Payment { to: alice, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Pay(tx));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let book = &genesis.l1.account_book;
+        let root = *book.root();
+
+        // what a light client receives
+        let data = bincode_config::serialize(&book.account_proof(&pk_to_hash(&alice)).unwrap()).unwrap();
+        let proof: AccountProof = bincode_config::deserialize(&data).unwrap();
+        assert!(proof.verify(&root));
+        assert_eq!(proof.account.amount, PAY_AMOUNT);
+
+        // a balance the book doesn't hold doesn't verify, even rehashed
+        let mut forged = proof.clone();
+        forged.account.amount = Balance(PAY_AMOUNT + 1);
+        assert!(!forged.verify(&root));
+        forged.hash = forged.account.hash();
+        assert!(!forged.verify(&root));
+        assert!(!proof.verify(&[0u8; 32]));
+        assert!(book.account_proof(&pk_to_hash(&genesis.alices[1].pk)).is_none());
+    }
+
+    #[test]
+    fn absence_proof_for_new_ids_only() {
+        let mut genesis = Genesis::new(1);
+        let alice = pk_to_hash(&genesis.alices[0].pk);
+        let faucet = pk_to_hash(&genesis.faucet.pk);
+        let book = &genesis.l1.account_book;
+        let root = *book.root();
+        let random: AccountID = random_keys(1).iter().map(pk_to_hash).next().unwrap();
+        assert!(book.absence_proof(&random).verify(&root));
+        let proof = book.absence_proof(&alice);
+        assert!(proof.verify(&root));
+        assert!(!book.absence_proof(&faucet).verify(&root));
+        // a proof can't be moved to another id
+        assert!(!AbsenceProof { id: faucet, closed: None, proof: proof.proof.clone() }.verify(&root));
+
+        // once alice has an account the old proof is against a stale root
+        let tx = l1_tx(genesis.faucet.pk, 0, Payment { to: genesis.alices[0].pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Pay(tx));
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let root = *genesis.l1.account_book.root();
+        assert!(!proof.verify(&root));
+        assert!(!genesis.l1.account_book.absence_proof(&alice).verify(&root));
+    }
+
+    #[test]
+    fn block_builder_packs_an_applyable_block() {
+        use crate::sequencer::{estimated_cycles, BlockBuilder};
+        let mut genesis = Genesis::new(2);
+        let mut faucet = genesis.faucet.clone();
+        let mut alice = genesis.alices[0].clone();
+        let bob = genesis.alices[1].pk;
+        let pay = |to, amount| Payment { to, amount: Balance(amount), expected_total: None };
+        let mut pool = vec![];
+        // out of order, and a second sqn 1 that conflicts with the first
+        for (sqn, amount) in [(2, 3), (0, 1), (1, 2), (1, 9)] {
+            pool.push(Transaction::Pay(l1_tx(faucet.pk, sqn, pay(alice.pk, amount), &mut faucet.sk)));
+        }
+        // over what is left after the ones before, and the sqn after it
+        pool.push(Transaction::Pay(l1_tx(faucet.pk, 3, pay(bob, GENESIS_AMOUNT), &mut faucet.sk)));
+        pool.push(Transaction::Pay(l1_tx(faucet.pk, 4, pay(bob, 1), &mut faucet.sk)));
+        // alice only has an account once the faucet paid her in this block
+        pool.push(Transaction::Pay(l1_tx(alice.pk, 0, pay(bob, 1), &mut alice.sk)));
+        pool.push(Transaction::Pay(l1_tx(alice.pk, 1, pay(bob, 1), &mut alice.sk)));
+        // a forgery and an L2 only txn
+        pool.push(Transaction::Pay(l1_tx(faucet.pk, 3, pay(bob, 1), &mut alice.sk)));
+        pool.push(Transaction::Withdrawal(l2_tx(alice.pk, 2, L2ToL1Withdrawal { amount: Balance(1), to: None }, &mut alice.sk)));
+
+        let book = &genesis.l1.account_book;
+        let block = BlockBuilder::new(book, Layer::L1, genesis.l1.sqn).build(pool.clone());
+        let sqns: Vec<(bool, u32)> = block.iter().map(|t| t.sender_sqn().map(|(s, n)| (s == pk_to_hash(&faucet.pk), n)).unwrap()).collect();
+        assert_eq!(sqns.len(), 5);
+        assert!(sqns.contains(&(false, 0)) && sqns.contains(&(false, 1)));
+        // one of the two sqn 1 txns, whichever order_for_block puts first
+        let amounts: Vec<u128> = block.iter().map(|t| match t { Transaction::Pay(tx) => tx.payload.amount.0, _ => 0 }).collect();
+        assert_eq!(amounts.iter().filter(|a| **a == 2 || **a == 9).count(), 1);
+        assert!(!amounts.contains(&GENESIS_AMOUNT));
+
+        // the budgets cut the block short
+        let first_two = BlockBuilder::new(book, Layer::L1, genesis.l1.sqn).max_txns(2).build(pool.clone());
+        assert_eq!(first_two.len(), 2);
+        let budget = estimated_cycles(&block[0]) * 3;
+        assert_eq!(BlockBuilder::new(book, Layer::L1, genesis.l1.sqn).max_cycles(budget).build(pool).len(), 3);
+
+        genesis.l1.txns = block;
+        let (_, summary) = crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        assert_eq!(summary.num_txns, 5);
+        assert_eq!(summary.total_paid, amounts.iter().sum::<u128>());
+    }
+
+    #[test]
+    fn payments_do_not_replay_across_layers() {
+        let mut genesis = Genesis::new(1);
+        // a funded L2 on its default chain, no params set on either side
+        let mut l2 = EngineData::new(genesis.faucet.pk, GENESIS_AMOUNT, Layer::L2);
+        assert_eq!((genesis.l1.chain_id(), l2.chain_id()), (L1_CHAIN_ID, L2_CHAIN_ID));
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let payment = || Payment { to: alice_pk, amount: Balance(1), expected_total: None };
+        let on_l1 = l1_tx(faucet_pk, 0, payment(), &mut genesis.faucet.sk);
+        let on_l2 = l2_tx(faucet_pk, 0, payment(), &mut genesis.faucet.sk);
+
+        // the faucet has funds on both layers, only the chain id is wrong
+        assert_eq!(genesis.l1.account_book.process_payment(&on_l2, 1).unwrap_err(), EngineError::WrongChain);
+        assert_eq!(l2.account_book.process_payment(&on_l1, 1).unwrap_err(), EngineError::WrongChain);
+        // the chain id is signed, it can't be swapped
+        let mut relabeled = on_l2.clone();
+        relabeled.chain_id = L1_CHAIN_ID;
+        assert!(!relabeled.sig_verify());
+        // a txn signed for no chain in particular is taken by neither
+        let unbound = Tx::new(faucet_pk, 0, payment(), &mut genesis.faucet.sk);
+        assert_eq!(genesis.l1.account_book.process_payment(&unbound, 1).unwrap_err(), EngineError::WrongChain);
+        assert_eq!(l2.account_book.process_payment(&unbound, 1).unwrap_err(), EngineError::WrongChain);
+
+        assert!(genesis.l1.account_book.process_payment(&on_l1, 1).is_ok());
+        assert!(l2.account_book.process_payment(&on_l2, 1).is_ok());
+    }
+
+    #[test]
+    fn l1_block_takes_a_txn_up_to_its_valid_until() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let sqn = genesis.l1.sqn;
+        let pay = |sqn: u32, valid_until: u32, sk: &mut SigningKey| {
+            Transaction::Pay(Tx::new_signed(faucet_pk, sqn, L1_CHAIN_ID, Some(valid_until), None, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, sk))
+        };
+
+        // expires with the block being built, still in
+        genesis.l1.txns = vec![pay(0, sqn, &mut genesis.faucet.sk)];
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+
+        // the same bound one block later is too late, and fails the whole block
+        genesis.l1.txns = vec![pay(1, sqn + 5, &mut genesis.faucet.sk), pay(2, sqn, &mut genesis.faucet.sk)];
+        let root = *genesis.l1.account_book.root();
+        assert_eq!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap_err(), EngineError::Expired);
+        assert_eq!(*genesis.l1.account_book.root(), root);
+    }
+
+    #[test]
+    fn create_account_registers_an_empty_account_once() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let bob = TxSigner::new(SigningKey::random(&mut OsRng));
+        let bob_id = pk_to_hash(&bob.pk);
+        let faucet = genesis.l1.account_book.accounts[&pk_to_hash(&faucet_pk)].clone();
+
+        genesis.l1.txns = vec![Transaction::CreateAccount(l1_tx(faucet_pk, 0, CreateAccount { pubkey: bob.pk }, &mut genesis.faucet.sk))];
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let book = &genesis.l1.account_book;
+        let a = &book.accounts[&bob_id];
+        assert_eq!((a.amount, a.sqn_expect), (Balance::ZERO, 0));
+        // only the sqn of the sender moves
+        let sender = &book.accounts[&pk_to_hash(&faucet_pk)];
+        assert_eq!((sender.amount, sender.sqn_expect), (faucet.amount, faucet.sqn_expect + 1));
+        assert!(book.verify_partial_root());
+
+        // a second time, or for a funded account, fails without using the sqn
+        let again = l1_tx(faucet_pk, 1, CreateAccount { pubkey: bob.pk }, &mut genesis.faucet.sk);
+        assert_eq!(genesis.l1.account_book.process_create_account(&again, 1).unwrap_err(), EngineError::AccountExists);
+        let own = l1_tx(faucet_pk, 1, CreateAccount { pubkey: faucet_pk }, &mut genesis.faucet.sk);
+        assert_eq!(genesis.l1.account_book.process_create_account(&own, 1).unwrap_err(), EngineError::AccountExists);
+        assert_eq!(genesis.l1.account_book.accounts[&pk_to_hash(&faucet_pk)].sqn_expect, 1);
+    }
+
+    #[test]
+    fn withdrawal_to_another_l1_account() {
+        let mut alice = TxSigner::new(SigningKey::random(&mut OsRng));
+        let bob = TxSigner::new(SigningKey::random(&mut OsRng));
+        let mut l2 = AccountBook::new_batch(vec![alice.pk], 100);
+        let mut w_records = vec![];
+
+        let tx = Tx::new(alice.pk, 0, L2ToL1Withdrawal { amount: Balance(5), to: Some(bob.pk) }, &mut alice.sk);
+        l2.process_withdrawal(&tx, 1, &mut w_records).unwrap();
+        let tx = Tx::new(alice.pk, 1, L2ToL1Withdrawal { amount: Balance(6), to: None }, &mut alice.sk);
+        l2.process_withdrawal(&tx, 1, &mut w_records).unwrap();
+        assert_eq!(w_records.iter().map(|w| (w.to, w.amount)).collect::<Vec<_>>(), vec![(bob.pk, Balance(5)), (alice.pk, Balance(6))]);
+        assert_eq!(l2.accounts[&pk_to_hash(&alice.pk)].amount, Balance(89));
+        assert!(!l2.accounts.contains_key(&pk_to_hash(&bob.pk)));
+
+        // the destination is signed over
+        let mut tx = Tx::new(alice.pk, 2, L2ToL1Withdrawal { amount: Balance(5), to: Some(bob.pk) }, &mut alice.sk);
+        tx.payload.to = Some(alice.pk);
+        assert_eq!(l2.process_withdrawal(&tx, 1, &mut w_records).unwrap_err(), EngineError::BadSig);
+    }
+
+    #[test]
+    fn full_inbox_rejects_deposits_until_an_update_drains_it() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let rollup_id = pk_to_hash(&rollup_pk);
+        let sk = &mut genesis.faucet.sk;
+        let book = &mut genesis.l1.account_book;
+
+        let seeded = |n: usize| (0..n).map(|i| ((i as u64).to_be_bytes().repeat(4).try_into().unwrap(), 1)).collect::<Vec<(Hash, u128)>>();
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: seeded(MAX_INBOX + 1), genesis_amount: MAX_INBOX as u128 + 1 }, sk);
+        assert_eq!(book.process_create_rollup_account(&tx, 0).unwrap_err(), EngineError::InboxFull);
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: seeded(MAX_INBOX - 1), genesis_amount: MAX_INBOX as u128 - 1 }, sk);
+        book.process_create_rollup_account(&tx, 0).unwrap();
+        assert_eq!(book.inbox_len(&rollup_id), Some(MAX_INBOX - 1));
+        assert_eq!(book.inbox_len(&pk_to_hash(&faucet_pk)), None);
+
+        // the last slot, then nothing
+        let tx = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: 1 }, sk);
+        book.process_deposit_l1(&tx, 0).unwrap();
+        assert_eq!(book.inbox_len(&rollup_id), Some(MAX_INBOX));
+        let tx = l1_tx(faucet_pk, 2, L1ToL2Deposit { rollup_pk, amount: 1 }, sk);
+        let before = book.accounts[&rollup_id].hash();
+        assert_eq!(book.process_deposit_l1(&tx, 0).unwrap_err(), "inbox_full");
+        assert_eq!(book.accounts[&rollup_id].hash(), before);
+
+        // an update consuming the inbox makes room again
+        let mut hasher = DefaultHasher::new();
+        for (msg, _) in &book.accounts[&rollup_id].rollup.as_ref().unwrap().inbox {
+            hasher.update(msg);
+        }
+        let header = BlockHeaderL2 {
+            inbox_msg_hash: hasher.finalize().as_slice().try_into().unwrap(),
+            inbox_msg_count: MAX_INBOX as u32,
+            ..Default::default()
+        };
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: bincode_config::serialize(&header).unwrap() }, &mut genesis.rollup.sk);
+        book.process_rollup_state_update(&tx, 0, |d| bincode_config::deserialize(d)).unwrap();
+        assert_eq!(book.inbox_len(&rollup_id), Some(0));
+        let tx = l1_tx(faucet_pk, 2, L1ToL2Deposit { rollup_pk, amount: 1 }, &mut genesis.faucet.sk);
+        book.process_deposit_l1(&tx, 0).unwrap();
+        assert_eq!(book.inbox_len(&rollup_id), Some(1));
+    }
+
+    #[test]
+    fn inbox_count_past_the_inbox_is_a_clean_error() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let rollup_id = pk_to_hash(&rollup_pk);
+        let genesis_inbox = vec![([1u8; 32], 1), ([2u8; 32], 1)];
+        let book = &mut genesis.l1.account_book;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: genesis_inbox.clone(), genesis_amount: 2 }, &mut genesis.faucet.sk);
+        book.process_create_rollup_account(&tx, 0).unwrap();
+        let mut hasher = DefaultHasher::new();
+        for (msg, _) in &genesis_inbox {
+            hasher.update(msg);
+        }
+        let all: Hash = hasher.finalize().as_slice().try_into().unwrap();
+
+        // one header claiming a third message
+        let forged = BlockHeaderL2 { inbox_msg_hash: all, inbox_msg_count: 3, ..Default::default() };
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: bincode_config::serialize(&forged).unwrap() }, &mut genesis.rollup.sk);
+        let before = book.accounts[&rollup_id].hash();
+        assert_eq!(book.process_rollup_state_update(&tx, 0, |d| bincode_config::deserialize(d)).unwrap_err(), EngineError::InboxCount);
+        assert_eq!(book.accounts[&rollup_id].hash(), before);
+
+        // or a batch whose second header reads past what the first left
+        let first = BlockHeaderL2 { inbox_msg_hash: all, inbox_msg_count: 2, ..Default::default() };
+        let second = BlockHeaderL2 { parent: first.hash(), sqn: 1, timestamp: 1, inbox_msg_count: 1, ..Default::default() };
+        let receipts = [first, second].iter().map(|h| bincode_config::serialize(h).unwrap()).collect();
+        let tx = l1_tx(rollup_pk, 0, RollupBatchUpdate { proof_receipts: receipts }, &mut genesis.rollup.sk);
+        assert_eq!(book.process_rollup_batch_update(&tx, 0, |d| bincode_config::deserialize(d)).unwrap_err(), "inbox_count");
+        assert_eq!(book.inbox_len(&rollup_id), Some(2));
+    }
+
+    #[test]
+    fn withdrawals_that_wrap_a_u128_are_rejected() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let rollup_id = pk_to_hash(&rollup_pk);
+        let book = &mut genesis.l1.account_book;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        book.process_create_rollup_account(&tx, 0).unwrap();
+        book.accounts.get_mut(&rollup_id).unwrap().amount = Balance(10);
+
+        // a wrapping sum would come to 0 and pass the balance check
+        let w = WithdrawalRecord { to: faucet_pk, amount: Balance(u128::MAX / 2 + 1) };
+        let header = BlockHeaderL2 {
+            inbox_msg_hash: DefaultHasher::new().finalize().as_slice().try_into().unwrap(),
+            withdrawals: vec![w.clone(), w],
+            ..Default::default()
+        };
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: bincode_config::serialize(&header).unwrap() }, &mut genesis.rollup.sk);
+        let before = book.accounts[&rollup_id].hash();
+        assert_eq!(book.process_rollup_state_update(&tx, 0, |d| bincode_config::deserialize(d)).unwrap_err(), "withdraw_overflow");
+        assert_eq!(book.accounts[&rollup_id].hash(), before);
+    }
+
+    #[test]
+    fn books_under_another_hasher_verify_on_their_own_roots() {
+        use sha2::{Sha256, Sha512_256};
+        let signers = TxSigner::batch(4, &mut OsRng);
+        let keys: Vec<VerifyingKey> = signers.iter().map(|s| s.pk).collect();
+        let builder = || keys.iter().fold(AccountBookBuilder::new(), |b, k| b.with_account(*k, PAY_AMOUNT, None));
+        let mut book = builder().build();
+        let mut other = builder().build_with::<Sha512_256>();
+        // the same accounts, leaves hashed differently
+        assert_eq!(book.root(), AccountBook::new_batch(keys.clone(), PAY_AMOUNT).root());
+        assert_ne!(book.root(), other.root());
+        assert!(book.verify_partial_root() && other.verify_partial_root());
+
+        // a payment, on the full books and on a partial of the other one
+        let mut sk = signers[0].sk.clone();
+        let pay = Tx::new(keys[0], 0, Payment { to: keys[1], amount: Balance(1), expected_total: None }, &mut sk);
+        let txns = vec![Transaction::Pay(pay.clone())];
+        let mut partial = other.get_partial(&txns);
+        assert!(partial.verify_partial_root());
+        let r = book.process_payment(&pay, 0).unwrap();
+        book.update_tree(r);
+        let r = other.process_payment(&pay, 0).unwrap();
+        let leaves = other.update_tree(r);
+        assert!(leaves.iter().all(|(id, h)| *h == other.accounts[id].hash_with::<Sha512_256>()));
+        let r = partial.process_payment(&pay, 0).unwrap();
+        partial.update_tree(r);
+        assert_ne!(book.root(), other.root());
+        assert_eq!(partial.root(), other.root());
+        assert!(book.verify_partial_root() && other.verify_partial_root() && partial.verify_partial_root());
+        assert_eq!(other.recompute_root(), *other.root());
+
+        // proofs out of the other book verify under its hasher only
+        let id = pk_to_hash(&keys[0]);
+        let proof = other.account_proof(&id).unwrap();
+        assert!(proof.verify_with::<Sha512_256>(other.root()));
+        assert!(!proof.verify(other.root()));
+        assert!(book.account_proof(&id).unwrap().verify(book.root()));
+
+        let txns = vec![Transaction::Pay(pay), Transaction::Unknown { tag: 99, bytes: vec![1, 2] }];
+        assert_eq!(tx_set_hash_with::<Sha256>(&txns), tx_set_hash(&txns));
+        assert_eq!(txns[1].id_with::<Sha256>(), txns[1].id());
+        assert_ne!(tx_set_hash_with::<Sha512_256>(&txns), tx_set_hash(&txns));
+        let ws: Vec<WithdrawalRecord> = keys.iter().map(|k| WithdrawalRecord { to: *k, amount: Balance(3) }).collect();
+        assert_eq!(withdrawals_root_with::<Sha256>(&ws), withdrawals_root(&ws));
+        assert_ne!(withdrawals_root_with::<Sha512_256>(&ws), withdrawals_root(&ws));
+    }
+
+    #[test]
+    fn get_account_pair_borrows_two_distinct_accounts() {
+        let signers = TxSigner::batch(3, &mut OsRng);
+        let ids: Vec<AccountID> = signers.iter().map(|s| pk_to_hash(&s.pk)).collect();
+        let mut book = AccountBook::new_batch(signers[..2].iter().map(|s| s.pk).collect(), 10);
+
+        // either order, each borrow is the account asked for
+        for (a, b) in [(ids[0], ids[1]), (ids[1], ids[0])] {
+            let (x, y) = book.get_account_pair(&a, &b).unwrap();
+            assert_eq!((x.id(), y.id()), (a, b));
+            x.amount = Balance(3);
+            y.amount = Balance(4);
+            assert_eq!((book.accounts[&a].amount, book.accounts[&b].amount), (Balance(3), Balance(4)));
+        }
+        assert_eq!(book.get_account_pair(&ids[0], &ids[0]).unwrap_err(), "same");
+        assert_eq!(book.get_account_pair(&ids[0], &ids[2]).unwrap_err(), EngineError::MissingAccount);
+        assert_eq!(book.get_account_pair(&ids[2], &ids[1]).unwrap_err(), EngineError::MissingAccount);
+    }
+
+    #[test]
+    fn rollup_cannot_deposit_into_itself() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let book = &mut genesis.l1.account_book;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        book.process_create_rollup_account(&tx, 0).unwrap();
+        book.accounts.get_mut(&pk_to_hash(&rollup_pk)).unwrap().amount = Balance(10);
+        let tx = l1_tx(rollup_pk, 0, L1ToL2Deposit { rollup_pk, amount: 1 }, &mut genesis.rollup.sk);
+        assert_eq!(book.process_deposit_l1(&tx, 0).unwrap_err(), EngineError::SameAccount);
+        assert_eq!(book.inbox_len(&pk_to_hash(&rollup_pk)), Some(0));
+    }
+
+    #[test]
+    fn account_book_builder_seeds_mixed_balances() {
+        let signers = TxSigner::batch(4, &mut OsRng);
+        let rollup = RollupState { inbox: std::collections::VecDeque::from([([7u8; 32], 5)]), header_hash: Hash::default(), sqn: 0, bond: 100,
+            pending_deposits: 5, last_timestamp: 0 };
+        let book = AccountBookBuilder::new()
+            .with_account(signers[0].pk, 1, None)
+            .with_account(signers[1].pk, 20, None)
+            .with_account(signers[2].pk, 300, None)
+            .with_account(signers[3].pk, 5, Some(rollup))
+            .with_params(ChainParams { chain_id: 7, ..Default::default() })
+            .build();
+        assert_eq!(book.params().chain_id, 7);
+
+        let root = *book.root();
+        for (s, amount) in signers.iter().zip([1u128, 20, 300, 5]) {
+            let proof = book.account_proof(&pk_to_hash(&s.pk)).unwrap();
+            assert!(proof.verify(&root));
+            assert_eq!(proof.account.amount, Balance(amount));
+        }
+        assert_eq!(book.inbox_len(&pk_to_hash(&signers[3].pk)), Some(1));
+        assert_eq!(book.inbox_len(&pk_to_hash(&signers[0].pk)), None);
+        // a plain account is the leaf new_batch would give it
+        let same = AccountBook::new_batch(vec![signers[0].pk], 1);
+        assert_eq!(same.account_proof(&pk_to_hash(&signers[0].pk)).unwrap().hash, book.account_proof(&pk_to_hash(&signers[0].pk)).unwrap().hash);
+    }
+
+    #[test]
+    fn lenient_blocks_give_a_receipt_per_txn() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice = genesis.alices[0].pk;
+        let sk = &mut genesis.faucet.sk;
+        let txns = vec![
+            Transaction::Pay(l1_tx(faucet_pk, 0, Payment { to: alice, amount: Balance(1), expected_total: None }, sk)),
+            // sqn already used
+            Transaction::Pay(l1_tx(faucet_pk, 0, Payment { to: alice, amount: Balance(2), expected_total: None }, sk)),
+            Transaction::Unknown { tag: 42, bytes: vec![] },
+        ];
+        genesis.l1.txns = txns.clone();
+        let (_, _, stats) = crate::l1_engine::process_lenient(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+
+        assert_eq!(stats.receipts.len(), txns.len());
+        assert_eq!(stats.receipts.iter().map(|r| r.id).collect::<Vec<_>>(), txns.iter().map(|t| t.id()).collect::<Vec<_>>());
+        let mut paid = vec![pk_to_hash(&faucet_pk), pk_to_hash(&alice)];
+        paid.sort();
+        assert_eq!(stats.receipts[0], TxReceipt { id: txns[0].id(), outcome: TxOutcome::Applied, accounts: paid });
+        assert_eq!((stats.receipts[1].outcome, stats.receipts[1].accounts.len()), (TxOutcome::Failed(EngineError::BadSqn), 0));
+        assert_eq!(stats.receipts[2].outcome, TxOutcome::Failed(EngineError::UnknownTx));
+
+        // l2 the same way
+        genesis.l2.txns = vec![txns[2].clone()];
+        let (_, _, stats) = crate::l2_engine::process_lenient(&mut genesis.l2).unwrap();
+        assert_eq!(stats.receipts, vec![TxReceipt { id: txns[2].id(), outcome: TxOutcome::Failed(EngineError::UnknownTx), accounts: vec![] }]);
+    }
+
+    #[test]
+    fn l1_header_hash_covers_its_events() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let sk = &mut genesis.faucet.sk;
+        let d1 = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: 5 }, sk);
+        let d2 = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: 6 }, sk);
+        let h = |events: Vec<Tx<L1ToL2Deposit>>| BlockHeaderL1 { sqn: 3, txns_hash: [1u8; 32], events, ..Default::default() }.hash();
+
+        assert_ne!(h(vec![d1.clone()]), h(vec![d2.clone()]));
+        assert_ne!(h(vec![]), h(vec![d1.clone()]));
+        assert_ne!(h(vec![d1.clone(), d2.clone()]), h(vec![d2.clone(), d1.clone()]));
+        assert_eq!(h(vec![d1.clone()]), h(vec![d1]));
+    }
+
+    #[test]
+    fn prune_keeps_only_what_the_block_changed() {
+        let mut signers = TxSigner::batch(200, &mut OsRng);
+        let mut data = EngineData::new_batch(signers.iter().map(|s| s.pk).collect(), 1000, Layer::L2);
+        let to = signers[1].pk;
+        let from = &mut signers[0];
+        data.txns = vec![Transaction::Pay(l2_tx(from.pk, 0, Payment { to, amount: Balance(5), expected_total: None }, &mut from.sk))];
+        crate::l2_engine::process(&mut data).unwrap();
+
+        let book = &mut data.account_book;
+        let root = *book.root();
+        let before = bincode_config::serialize(book).unwrap().len();
+        book.prune();
+        let after = bincode_config::serialize(book).unwrap().len();
+        assert!(after < before / 2, "book {} bytes, pruned {} bytes", before, after);
+
+        assert_eq!(*book.root(), root);
+        assert!(book.verify_partial_root());
+        let mut paid = vec![pk_to_hash(&signers[0].pk), pk_to_hash(&to)];
+        paid.sort();
+        assert_eq!(book.accounts.keys().cloned().collect::<Vec<_>>(), paid);
+        assert!(book.account_ref(&pk_to_hash(&signers[2].pk)).is_none());
+        // nothing changed since, the next prune keeps the lowest id to verify the root with
+        book.prune();
+        assert_eq!(book.accounts.keys().cloned().collect::<Vec<_>>(), paid[..1]);
+        assert_eq!(*book.root(), root);
+        assert!(book.verify_partial_root());
+        // and the same for a book no block has changed
+        let mut fresh = AccountBook::new_batch(signers.iter().map(|s| s.pk).collect(), 1000);
+        let root = *fresh.root();
+        fresh.prune();
+        assert_eq!(fresh.accounts.len(), 1);
+        assert_eq!(*fresh.root(), root);
+        assert!(fresh.verify_partial_root());
+    }
+
+    #[test]
+    fn same_block_gives_the_same_change_set() {
+        let mut genesis = Genesis::new(4);
+        let faucet_pk = genesis.faucet.pk;
+        let mut txns = vec![];
+        for (i, alice) in genesis.alices.iter().enumerate() {
+            let tx = l1_tx(faucet_pk, i as u32, Payment { to: alice.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+            txns.push(Transaction::Pay(tx));
+        }
+        let run = || EngineData::new(faucet_pk, GENESIS_AMOUNT, Layer::L1).account_book.apply_transactions(&txns, Layer::L1, 0).unwrap();
+        let first = run();
+        assert_eq!(first, run());
+        assert_eq!(first.len(), 5);
+        assert!(first.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn zero_amounts_are_rejected_unless_allowed() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let rollup_pk = genesis.rollup.pk;
+        let sk = &mut genesis.faucet.sk;
+        let book = &mut genesis.l2.account_book;
+        let pay = l2_tx(faucet_pk, 0, Payment { to: alice_pk, amount: Balance(0), expected_total: None }, sk);
+        assert_eq!(book.process_payment(&pay, 0).unwrap_err(), "zero");
+        let withdraw = l2_tx(faucet_pk, 0, L2ToL1Withdrawal { amount: Balance(0), to: None }, sk);
+        assert_eq!(book.process_withdrawal(&withdraw, 0, &mut vec![]).unwrap_err(), "zero");
+        let deposit = l1_tx(faucet_pk, 0, L1ToL2Deposit { rollup_pk, amount: 0 }, sk);
+        assert_eq!(genesis.l1.account_book.process_deposit_l1(&deposit, 0).unwrap_err(), "zero");
+        let batch = l2_tx(faucet_pk, 0, BatchPayment { outputs: vec![(rollup_pk, 1), (alice_pk, 0)] }, sk);
+        assert_eq!(book.process_batch_payment(&batch, 0).unwrap_err(), "zero");
+        let conditional = l2_tx(faucet_pk, 0, ConditionalPayment { to: alice_pk, amount: Balance(0), max_recipient_balance: 0 }, sk);
+        assert_eq!(book.process_conditional_payment(&conditional, 0).unwrap_err(), "zero");
+        // either leg of a swap
+        let mut rollup_sk = genesis.rollup.sk.clone();
+        for (give, want) in [(0, 1), (1, 0)] {
+            let swap = l2_tx(faucet_pk, 0, Swap::new(&faucet_pk, 0, L2_CHAIN_ID, None, give, want, &mut rollup_sk), sk);
+            assert_eq!(book.process_swap(&swap, 0).unwrap_err(), "zero");
+        }
+        // no recipient account, no sqn bump
+        assert!(!book.accounts.contains_key(&pk_to_hash(&alice_pk)));
+        assert_eq!(book.accounts[&pk_to_hash(&faucet_pk)].sqn_expect, 0);
+
+        book.set_params(ChainParams { allow_zero_amount: true, ..ChainParams::for_layer(Layer::L2) });
+        book.process_payment(&pay, 0).unwrap();
+        assert_eq!(book.accounts[&pk_to_hash(&alice_pk)].amount, 0);
+        assert_eq!(book.accounts[&pk_to_hash(&faucet_pk)].sqn_expect, 1);
+    }
+
+    #[test]
+    fn apply_matches_the_process_methods() {
+        let mut genesis = Genesis::new(1);
+        let samples = one_of_each(&mut genesis);
+
+        let valid = |_: &Vec<u8>| Err(EngineError::InvalidReceipt);
+        for t in &samples {
+            let (l1, _) = replay_layers(t);
+            let (layer, book) = match l1 {
+                true => (Layer::L1, &genesis.l1.account_book),
+                false => (Layer::L2, &genesis.l2.account_book),
+            };
+            let mut ids: Vec<AccountID> = book.accounts.keys().cloned().collect();
+            ids.extend(book.get_affected_account_ids(&vec![t.clone()]));
+            let (mut via_apply, mut direct) = (book.partial_for(&ids), book.partial_for(&ids));
+
+            let mut ctx = ApplyCtx::new(Some(layer), 0).with_receipts(&valid);
+            let applied = via_apply.apply(t, &mut ctx);
+            let mut w_records = vec![];
+            let expected = match t {
+                Transaction::Pay(tx) => direct.process_payment(tx, 0),
+                Transaction::ConditionalPay(tx) => direct.process_conditional_payment(tx, 0),
+                Transaction::Swap(tx) => direct.process_swap(tx, 0),
+                Transaction::SetDelegate(tx) => direct.process_set_delegate(tx, 0),
+                Transaction::BatchPay(tx) => direct.process_batch_payment(tx, 0),
+                Transaction::CreateAccount(tx) => direct.process_create_account(tx, 0),
+                Transaction::CloseAccount(tx) => direct.process_close_account(tx, 0),
+                Transaction::Deposit(tx) => direct.process_deposit_l1(tx, 0),
+                Transaction::RollupCreate(tx) => direct.process_create_rollup_account(tx, 0),
+                Transaction::RollupCreateFund(tx) => direct.process_create_and_fund_rollup(tx, 0),
+                Transaction::RollupUpdate(tx) => direct.process_rollup_state_update(tx, 0, valid),
+                Transaction::RollupBatchUpdate(tx) => direct.process_rollup_batch_update(tx, 0, valid),
+                Transaction::DepositL2(tx) => direct.process_deposit_l2(tx),
+                Transaction::RollupCreateFundL2(tx) => direct.process_deposit_l2(tx),
+                Transaction::Withdrawal(tx) => direct.process_withdrawal(tx, 0, &mut w_records),
+                Transaction::Unknown { .. } => Err(EngineError::UnknownTx),
+            };
+            assert_eq!(applied, expected, "{:?}", t.kind());
+            assert_eq!(ctx.w_records.len(), w_records.len());
+            let deposited = matches!(t, Transaction::Deposit(_)) && expected.is_ok();
+            assert_eq!(ctx.deposits.len(), deposited as usize);
+            let consumed = matches!(t, Transaction::DepositL2(_) | Transaction::RollupCreateFundL2(_)) && expected.is_ok();
+            assert_eq!(ctx.inbox_msgs, if consumed { vec![t.id()] } else { vec![] });
+        }
+
+        // the layer is checked before anything else, None takes both
+        let withdraw = &samples[6];
+        assert_eq!(genesis.l1.account_book.apply(withdraw, &mut ApplyCtx::new(Some(Layer::L1), 0)).unwrap_err(), withdraw.kind().wrong_layer());
+        assert_eq!(genesis.l1.account_book.apply(&samples[3], &mut ApplyCtx::new(None, 0)).unwrap_err(), "tx type");
+    }
+
+    #[test]
+    fn incremental_root_matches_a_full_recompute() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let (alice_pk, bob_pk) = (genesis.alices[0].pk, genesis.alices[1].pk);
+        let carol_pk = TxSigner::new(SigningKey::random(&mut OsRng)).pk;
+        let sk = &mut genesis.faucet.sk;
+        let deposit = l1_tx(faucet_pk, 6, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, sk);
+        genesis.l1.txns = vec![
+            Transaction::RollupCreate(l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, sk)),
+            Transaction::Pay(l1_tx(faucet_pk, 1, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, sk)),
+            Transaction::BatchPay(l1_tx(faucet_pk, 2, BatchPayment { outputs: vec![(alice_pk, 1), (bob_pk, 2)] }, sk)),
+            Transaction::ConditionalPay(l1_tx(faucet_pk, 3, ConditionalPayment { to: bob_pk, amount: Balance(3), max_recipient_balance: 10 }, sk)),
+            Transaction::SetDelegate(l1_tx(faucet_pk, 4, SetDelegate { delegate: alice_pk, allowance: 1 }, sk)),
+            Transaction::CreateAccount(l1_tx(faucet_pk, 5, CreateAccount { pubkey: carol_pk }, sk)),
+            Transaction::Deposit(deposit.clone()),
+        ];
+        crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        let book = &genesis.l1.account_book;
+        assert_eq!(book.recompute_root(), *book.root());
+
+        let sk = &mut genesis.faucet.sk;
+        genesis.l2.txns = vec![
+            Transaction::DepositL2(deposit),
+            Transaction::Pay(l2_tx(faucet_pk, 0, Payment { to: alice_pk, amount: Balance(2), expected_total: None }, sk)),
+            Transaction::Withdrawal(l2_tx(faucet_pk, 1, L2ToL1Withdrawal { amount: Balance(1), to: None }, sk)),
+        ];
+        crate::l2_engine::process(&mut genesis.l2).unwrap();
+        let book = &mut genesis.l2.account_book;
+        assert_eq!(book.recompute_root(), *book.root());
+
+        // a change the tree was not told about
+        book.accounts.get_mut(&pk_to_hash(&alice_pk)).unwrap().amount = Balance(1);
+        assert_ne!(book.recompute_root(), *book.root());
+    }
+
+    #[test]
+    fn new_genesis_seeds_several_faucets() {
+        let mut faucets = TxSigner::batch(3, &mut OsRng);
+        let amounts = [GENESIS_AMOUNT, 2 * GENESIS_AMOUNT, 7];
+        let allocations: Vec<_> = faucets.iter().zip(amounts).map(|(f, amount)| (f.pk, amount, None)).collect();
+        let mut engine = EngineData::new_genesis(allocations.clone(), Layer::L2).unwrap();
+        let book = &engine.account_book;
+        assert_eq!(book.total_supply().unwrap(), amounts.iter().sum::<u128>());
+        assert_eq!(book.recompute_root(), *book.root());
+        for (f, amount) in faucets.iter().zip(amounts) {
+            let proof = book.account_proof(&pk_to_hash(&f.pk)).unwrap();
+            assert!(proof.verify(book.root()));
+            assert_eq!(proof.account.amount, Balance(amount));
+        }
+        // the root depends on the allocations only, not on their order
+        let mut reversed = allocations.clone();
+        reversed.reverse();
+        assert_eq!(EngineData::new_genesis(reversed, Layer::L2).unwrap().account_book.root(), book.root());
+
+        // any faucet can pay from block 0
+        let tx = l2_tx(faucets[2].pk, 0, Payment { to: faucets[0].pk, amount: Balance(7), expected_total: None }, &mut faucets[2].sk);
+        engine.txns.push(Transaction::Pay(tx));
+        crate::l2_engine::process(&mut engine).unwrap();
+        assert_eq!(engine.account_book.accounts[&pk_to_hash(&faucets[0].pk)].amount, GENESIS_AMOUNT + 7);
+
+        let mut twice = allocations.clone();
+        twice.push((faucets[0].pk, 1, None));
+        assert_eq!(EngineData::new_genesis(twice, Layer::L2).unwrap_err(), "account_exist");
+        assert_eq!(EngineData::new_genesis(vec![(faucets[0].pk, u128::MAX, None), (faucets[1].pk, 1, None)], Layer::L2).unwrap_err(), "overflow");
+    }
+
+    #[test]
+    fn closed_account_leaves_a_tombstone_and_keeps_its_sqn() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        let alice = pk_to_hash(&alice_pk);
+        let valid = |_: &Vec<u8>| Err(EngineError::InvalidReceipt);
+        let fund = l1_tx(faucet_pk, 0, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns = vec![Transaction::Pay(fund)];
+        crate::l1_engine::process(&mut genesis.l1, valid).unwrap();
+
+        // not while it holds anything
+        let sk = &mut genesis.alices[0].sk;
+        let early = l1_tx(alice_pk, 0, CloseAccount {}, sk);
+        assert_eq!(genesis.l1.account_book.process_close_account(&early, 1).unwrap_err(), "not_empty");
+        let refund = l1_tx(alice_pk, 0, Payment { to: faucet_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, sk);
+        let close = l1_tx(alice_pk, 1, CloseAccount {}, sk);
+        genesis.l1.txns = vec![Transaction::Pay(refund.clone()), Transaction::CloseAccount(close)];
+        crate::l1_engine::process(&mut genesis.l1, valid).unwrap();
+
+        let book = &genesis.l1.account_book;
+        let root = *book.root();
+        assert!(book.account_ref(&alice).is_none());
+        assert_eq!(book.proof_tree.get(&alice), Some(Account::closed_leaf(&alice, 2)));
+        let proof = book.absence_proof(&alice);
+        assert_eq!(proof.closed, Some(2));
+        assert!(proof.verify(&root));
+        // a tombstone doesn't pass for a never used id
+        assert!(!AbsenceProof { closed: None, ..proof.clone() }.verify(&root));
+        assert!(book.verify_partial_root());
+        assert_eq!(book.recompute_root(), root);
+
+        // paid again, the id reopens at the tombstone's sqn, so alice's old txns don't replay
+        let again = l1_tx(faucet_pk, 1, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        let txns = vec![Transaction::Pay(again)];
+        let partial = book.get_partial(&txns);
+        assert_eq!(partial.closed.get(&alice), Some(&2));
+        assert!(partial.verify_partial_root());
+        genesis.l1.txns = txns;
+        crate::l1_engine::process(&mut genesis.l1, valid).unwrap();
+        let book = &mut genesis.l1.account_book;
+        assert_eq!(book.accounts[&alice].sqn_expect, 2);
+        assert!(book.closed.is_empty());
+        assert!(!proof.verify(book.root()));
+        assert_eq!(book.process_payment(&refund, 3).unwrap_err(), "sqn");
+        assert_eq!(book.recompute_root(), *book.root());
+    }
+
+    #[test]
+    fn encode_round_trips_every_kind() {
+        let mut genesis = Genesis::new(1);
+        let samples = one_of_each(&mut genesis);
+        let (known, unknown) = samples.split_at(samples.len() - 1);
+        for t in known {
+            let bytes = t.encode().unwrap();
+            assert_eq!(bytes[0], TX_ENCODING_VERSION);
+            let decoded = Transaction::decode(&bytes).unwrap();
+            assert_eq!((decoded.kind(), decoded.id()), (t.kind(), t.id()));
+            assert!(decoded.sig_verify());
+            assert_eq!(decoded.encode().unwrap(), bytes);
+        }
+        assert_eq!(unknown[0].encode().unwrap_err(), "unknown tx");
+
+        let mut bytes = known[0].encode().unwrap();
+        // a tag no build knows, or one past the last kind
+        for tag in [15u8, 255] {
+            bytes[1] = tag;
+            assert_eq!(Transaction::decode(&bytes).unwrap_err(), EngineError::UnknownTx);
+        }
+        bytes[1] = 0;
+        bytes[0] = TX_ENCODING_VERSION + 1;
+        assert_eq!(Transaction::decode(&bytes).unwrap_err(), "version");
+        bytes[0] = TX_ENCODING_VERSION;
+        bytes.push(0);
+        assert_eq!(Transaction::decode(&bytes).unwrap_err(), "deserialize");
+        for short in [&bytes[..0], &bytes[..1], &bytes[..2], &bytes[..bytes.len() / 2]] {
+            assert!(Transaction::decode(short).is_err());
+        }
+    }
+
+    #[test]
+    fn decode_rejects_an_appended_byte() {
+        let mut genesis = Genesis::new(1);
+        let samples = one_of_each(&mut genesis);
+        for t in &samples[..samples.len() - 1] {
+            let mut bytes = t.encode().unwrap();
+            bytes.push(0);
+            assert_eq!(Transaction::decode(&bytes).unwrap_err(), "deserialize");
+            // the binary serde form of a block goes through the same decode
+            let data = bincode_config::serialize(&vec![bytes]).unwrap();
+            assert!(bincode_config::deserialize::<Vec<Transaction>>(&data).is_err());
+        }
+    }
+
+    #[test]
+    fn partial_root_matches_full_root_with_fees() {
+        let mut genesis = Genesis::new(20);
+        genesis.l2 = EngineData::new_batch(genesis.alices.iter().map(|a| a.pk).collect(), PAY_AMOUNT, Layer::L2);
+        // the collector already holds a balance, the partial must carry its account
+        let collector = genesis.alices[19].pk;
+        genesis.l2.account_book.set_params(ChainParams { fee_policy: FeePolicy::Flat(3), fee_collector: Some(collector), ..ChainParams::for_layer(Layer::L2) });
+        for i in 0..5 {
+            let (from, to) = (&genesis.alices[i], genesis.alices[i + 10].pk);
+            let tx = l2_tx(from.pk, 0, Payment { to, amount: Balance(1), expected_total: None }, &mut from.sk.clone());
+            genesis.l2.txns.push(Transaction::Pay(tx));
+        }
+
+        let mut partial = genesis.l2.get_partial();
+        assert!(partial.account_book.verify_partial_root());
+        assert_eq!(partial.account_book.get_num_accounts(), 11);
+        let (p_bh2, _) = crate::l2_engine::process(&mut partial).unwrap();
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        assert_eq!(p_bh2.state_root, bh2.state_root);
+        assert!(genesis.l2.account_book.account_hash_verify(&collector, |a| a.amount == PAY_AMOUNT + 5 * 3));
+    }
+
+    #[test]
+    fn partial_settles_withdrawals_to_existing_accounts() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        let tx = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+        genesis.l2.txns.push(Transaction::DepositL2(tx.clone()));
+        genesis.l1.txns.push(Transaction::Deposit(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Ok(BlockHeaderL2::default())).is_ok());
+        // back to the faucet, which has a balance on L1 already
+        let tx = l2_tx(faucet_pk, 0, L2ToL1Withdrawal { amount: Balance(PAY_AMOUNT), to: None }, &mut genesis.faucet.sk);
+        genesis.l2.txns.push(Transaction::Withdrawal(tx));
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        assert_eq!(bh2.withdrawals.len(), 1);
+
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        genesis.l1.txns.push(Transaction::RollupUpdate(tx));
+        let mut partial = genesis.l1.get_partial();
+        assert!(partial.account_book.verify_partial_root());
+        assert!(partial.account_book.get_account(&pk_to_hash(&faucet_pk)).is_some());
+
+        let (p_bh1, _) = crate::l1_engine::process(&mut partial, |data| bincode_config::deserialize(data)).unwrap();
+        let (bh1, _) = crate::l1_engine::process(&mut genesis.l1, |data| bincode_config::deserialize(data)).unwrap();
+        assert_eq!(p_bh1.state_root, bh1.state_root);
+        assert!(genesis.l1.account_book.account_hash_verify(&faucet_pk, |a| a.amount == GENESIS_AMOUNT));
+    }
+
+    #[test]
+    fn slashing_large_bonds_and_rates() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let book = &mut genesis.l1.account_book;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        book.process_create_rollup_account(&tx, 0).unwrap();
+        let rid = pk_to_hash(&rollup_pk);
+        book.accounts.get_mut(&rid).unwrap().rollup.as_mut().unwrap().bond = u128::MAX;
+        let forged = bincode_config::serialize(&BlockHeaderL2 { parent: [7u8; 32], ..Default::default() }).unwrap();
+
+        // half of a bond the multiplication would overflow on
+        book.set_params(ChainParams { rollup_slash_bps: 5_000, ..ChainParams::for_layer(Layer::L1) });
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: forged.clone() }, &mut genesis.rollup.sk);
+        let r = book.process_rollup_state_update(&tx, 0, |data| bincode_config::deserialize(data)).unwrap();
+        book.update_tree(r);
+        assert!(book.account_hash_verify(&rollup_pk, |a| a.sqn_expect == 1 && a.rollup.as_ref().is_some_and(|ru| ru.bond == u128::MAX - u128::MAX / 2)));
+
+        // more than 100% burns what is left and no more
+        book.set_params(ChainParams { rollup_slash_bps: 20_000, ..ChainParams::for_layer(Layer::L1) });
+        let tx = l1_tx(rollup_pk, 1, RollupStateUpdate { proof_receipt: forged }, &mut genesis.rollup.sk);
+        let r = book.process_rollup_state_update(&tx, 0, |data| bincode_config::deserialize(data)).unwrap();
+        book.update_tree(r);
+        assert!(book.account_hash_verify(&rollup_pk, |a| a.sqn_expect == 2 && a.rollup.as_ref().is_some_and(|ru| ru.bond == 0)));
+    }
+
+    #[test]
+    fn block_builder_sees_a_closed_account() {
+        use crate::sequencer::BlockBuilder;
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let (alice_pk, bob) = (genesis.alices[0].pk, genesis.alices[1].pk);
+        let valid = |_: &Vec<u8>| Err(EngineError::InvalidReceipt);
+        let fund = l1_tx(faucet_pk, 0, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns = vec![Transaction::Pay(fund)];
+        crate::l1_engine::process(&mut genesis.l1, valid).unwrap();
+
+        // emptied and closed; after that neither a second close nor a payment goes through
+        let sk = &mut genesis.alices[0].sk;
+        let pool = vec![
+            Transaction::Pay(l1_tx(alice_pk, 0, Payment { to: faucet_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, sk)),
+            Transaction::CloseAccount(l1_tx(alice_pk, 1, CloseAccount {}, sk)),
+            Transaction::CloseAccount(l1_tx(alice_pk, 1, CloseAccount {}, sk)),
+            Transaction::Pay(l1_tx(alice_pk, 2, Payment { to: bob, amount: Balance(1), expected_total: None }, sk)),
+        ];
+        let block = BlockBuilder::new(&genesis.l1.account_book, Layer::L1, genesis.l1.sqn).build(pool);
+        assert_eq!(block.len(), 2);
+        assert!(matches!(block[1], Transaction::CloseAccount(_)));
+        genesis.l1.txns = block;
+        crate::l1_engine::process(&mut genesis.l1, valid).unwrap();
+        assert_eq!(genesis.l1.account_book.closed.get(&pk_to_hash(&alice_pk)), Some(&2));
+    }
+
+    #[test]
+    fn block_builder_keeps_the_inbox_order() {
+        use crate::sequencer::BlockBuilder;
+        let mut genesis = Genesis::new(3);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let create = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(create));
+        let mut deposits = vec![];
+        for (i, alice) in genesis.alices.iter_mut().enumerate() {
+            let fund = l1_tx(faucet_pk, i as u32 + 1, Payment { to: alice.pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+            genesis.l1.txns.push(Transaction::Pay(fund));
+            deposits.push(l1_tx(alice.pk, 0, L1ToL2Deposit { rollup_pk, amount: 1 }, &mut alice.sk));
+        }
+        // enqueued in descending id order, what order_for_block would reverse
+        deposits.sort_by_key(|d| std::cmp::Reverse(d.id()));
+        genesis.l1.txns.extend(deposits.iter().cloned().map(Transaction::Deposit));
+        let valid = |data: &Vec<u8>| bincode_config::deserialize(data);
+        crate::l1_engine::process(&mut genesis.l1, valid).unwrap();
+
+        let mut pool: Vec<Transaction> = deposits.iter().cloned().map(Transaction::DepositL2).collect();
+        pool.push(Transaction::Pay(l2_tx(genesis.alices[0].pk, 0, Payment { to: faucet_pk, amount: Balance(1), expected_total: None }, &mut genesis.alices[0].sk)));
+        let block = BlockBuilder::new(&genesis.l2.account_book, Layer::L2, genesis.l2.sqn).build(pool.clone());
+        assert_eq!(block.iter().map(|t| t.id()).collect::<Vec<_>>(), pool.iter().map(|t| t.id()).collect::<Vec<_>>());
+        // cut at max_txns, the messages stay a prefix
+        let first = BlockBuilder::new(&genesis.l2.account_book, Layer::L2, genesis.l2.sqn).max_txns(1).build(pool);
+        assert_eq!(first[0].id(), deposits[0].id());
+
+        genesis.l2.txns = block;
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+        assert_eq!(bh2.inbox_msg_count, 3);
+        let data = bincode_config::serialize(&bh2).unwrap();
+        genesis.l1.txns = vec![Transaction::RollupUpdate(l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk))];
+        crate::l1_engine::process(&mut genesis.l1, valid).unwrap();
+        assert!(genesis.l1.account_book.account_hash_verify(&rollup_pk, |a| a.rollup.as_ref().is_some_and(|ru| ru.inbox.is_empty() && ru.header_hash == bh2.hash())));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn update_tree_skips_unknown_ids() {
+        let keys = random_keys(3);
+        let mut book = AccountBook::new_batch(keys.clone(), PAY_AMOUNT);
+        let root = *book.root();
+        let unknown = pk_to_hash(&random_keys(1)[0]);
+        assert!(book.update_tree(vec![(unknown, [1u8; 32])]).is_empty());
+        assert_eq!(*book.root(), root);
+        let id = pk_to_hash(&keys[0]);
+        assert_eq!(book.update_tree(vec![(unknown, [1u8; 32]), (id, UNHASHED)]).len(), 1);
+        assert_eq!(*book.root(), root);
+    }
+
+    #[test]
+    fn settle_rejects_more_than_pending() {
+        let mut genesis = Genesis::new(0);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::RollupCreate(tx));
+        let tx = l1_tx(faucet_pk, 1, L1ToL2Deposit { rollup_pk, amount: PAY_AMOUNT }, &mut genesis.faucet.sk);
+        genesis.l2.txns.push(Transaction::DepositL2(tx.clone()));
+        genesis.l1.txns.push(Transaction::Deposit(tx));
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Ok(BlockHeaderL2::default())).is_ok());
+        let (bh2, _) = crate::l2_engine::process(&mut genesis.l2).unwrap();
+
+        // a book whose rollup lost track of the deposit, e.g. a bad partial
+        let book = &mut genesis.l1.account_book;
+        let rid = pk_to_hash(&rollup_pk);
+        book.accounts.get_mut(&rid).unwrap().rollup.as_mut().unwrap().pending_deposits = PAY_AMOUNT - 1;
+        let before = book.accounts[&rid].clone();
+        let data = bincode_config::serialize(&bh2).unwrap();
+        let tx = l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk);
+        assert_eq!(book.process_rollup_state_update(&tx, 0, |data| bincode_config::deserialize(data)).unwrap_err(), "pending");
+        assert_eq!(book.accounts[&rid].hash(), before.hash());
+    }
+
+    // a rollup update whose one withdrawal bob can take, but not once he has accrued
+    // his interest for block 1
+    fn settle_with_an_overflowing_credit(genesis: &mut Genesis) -> Tx<RollupStateUpdate> {
+        const BOB: u128 = 1_000_000_000_000_000_000;
+        let withdrawn = u128::MAX - BOB - 10;
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        let bob = genesis.alices[0].pk;
+        let book = &mut genesis.l1.account_book;
+        let tx = l1_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        book.process_create_rollup_account(&tx, 0).unwrap();
+        book.set_params(ChainParams { interest_ppb: 1, ..ChainParams::for_layer(Layer::L1) });
+        let rid = pk_to_hash(&rollup_pk);
+        let rollup = book.accounts.get_mut(&rid).unwrap();
+        rollup.amount = Balance(withdrawn);
+        let rollup_h = rollup.hash();
+        let a_bob = book.get_account_or_new(bob);
+        a_bob.amount = Balance(BOB);
+        let bob_h = a_bob.hash();
+        book.update_tree(vec![(rid, rollup_h), (pk_to_hash(&bob), bob_h)]);
+
+        let header = BlockHeaderL2 {
+            timestamp: 1,
+            inbox_msg_hash: DefaultHasher::new().finalize().as_slice().try_into().unwrap(),
+            withdrawals: vec![WithdrawalRecord { to: bob, amount: Balance(withdrawn) }],
+            ..Default::default()
+        };
+        let data = bincode_config::serialize(&header).unwrap();
+        l1_tx(rollup_pk, 0, RollupStateUpdate { proof_receipt: data }, &mut genesis.rollup.sk)
+    }
+
+    #[test]
+    fn settle_checks_credits_against_accrued_balances() {
+        let mut genesis = Genesis::new(1);
+        let tx = settle_with_an_overflowing_credit(&mut genesis);
+        let bob = pk_to_hash(&genesis.alices[0].pk);
+        let rid = pk_to_hash(&genesis.rollup.pk);
+        let book = &mut genesis.l1.account_book;
+        // the recipient is an affected id, the engines accrue it before the txn
+        let accrued = book.accrue_for(&Transaction::RollupUpdate(tx.clone()), 1).unwrap();
+        assert!(accrued.iter().any(|(id, _)| *id == bob));
+        let (before_bob, before_rollup) = (book.accounts[&bob].clone(), book.accounts[&rid].clone());
+        assert_eq!(book.process_rollup_state_update(&tx, 1, |d| bincode_config::deserialize(d)).unwrap_err(), "overflow");
+        assert_eq!(book.accounts[&bob].hash(), before_bob.hash());
+        assert_eq!(book.accounts[&rid].hash(), before_rollup.hash());
+    }
+
+    #[test]
+    fn failed_settle_keeps_the_root_in_lenient_mode() {
+        let mut genesis = Genesis::new(1);
+        let tx = settle_with_an_overflowing_credit(&mut genesis);
+        let bob = pk_to_hash(&genesis.alices[0].pk);
+        let minted = genesis.l1.account_book.interest_minted;
+        genesis.l1.sqn = 1;
+        genesis.l1.txns = vec![Transaction::RollupUpdate(tx)];
+        crate::l1_engine::process_lenient(&mut genesis.l1, |d| bincode_config::deserialize(d)).unwrap();
+        assert!(genesis.l1.txns.is_empty());
+        let book = &genesis.l1.account_book;
+        // bob's interest stays, in his leaf as well as in his account
+        assert!(book.interest_minted > minted);
+        assert!(book.accounts[&bob].amount.0 > 1_000_000_000_000_000_000);
+        assert_eq!(book.recompute_root(), *book.root());
+    }
+
+    #[test]
+    fn block_summary_saturates() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice = genesis.alices[0].pk;
+        // the same balance paid back and forth, more in total than a u128 holds
+        let mut book = AccountBook::new_batch(vec![faucet_pk, alice], u128::MAX / 2);
+        book.set_params(ChainParams::for_layer(Layer::L1));
+        let mut txns = vec![];
+        for i in 0..2 {
+            let tx = l1_tx(faucet_pk, i, Payment { to: alice, amount: Balance(u128::MAX / 2), expected_total: None }, &mut genesis.faucet.sk);
+            txns.push(Transaction::Pay(tx));
+            let tx = l1_tx(alice, i, Payment { to: faucet_pk, amount: Balance(u128::MAX / 2), expected_total: None }, &mut genesis.alices[0].sk);
+            txns.push(Transaction::Pay(tx));
+        }
+        book.apply_transactions(&txns, Layer::L1, 0).unwrap();
+        let summary = BlockSummary::new(&txns, &book).unwrap();
+        assert_eq!(summary.num_txns, 4);
+        assert_eq!(summary.total_paid, u128::MAX);
+    }
+
+    #[test]
+    fn block_summary_fees_overflow() {
+        let mut summary = BlockSummary::default();
+        summary.add_fee(u128::MAX).unwrap();
+        assert_eq!(summary.add_fee(1), Err(EngineError::Overflow));
+        assert_eq!(summary.total_fees, u128::MAX);
+    }
+
+    #[test]
+    fn validate_rejects_a_txn_of_the_other_layer() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let rollup_pk = genesis.rollup.pk;
+        // signed for L2, so only the layer can turn it away there
+        let create = Transaction::RollupCreate(l2_tx(faucet_pk, 0, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk));
+        assert_eq!(genesis.l2.validate(&create).unwrap_err(), EngineError::WrongLayer(TxKind::RollupCreate));
+        genesis.l2.txns = vec![create];
+        assert_eq!(crate::l2_engine::process(&mut genesis.l2).unwrap_err(), EngineError::WrongLayer(TxKind::RollupCreate));
+
+        let withdrawal = Transaction::Withdrawal(l1_tx(faucet_pk, 0, L2ToL1Withdrawal { amount: Balance(1), to: None }, &mut genesis.faucet.sk));
+        assert_eq!(genesis.l1.validate(&withdrawal).unwrap_err(), EngineError::WrongLayer(TxKind::Withdrawal));
+    }
+
+    #[test]
+    fn validate_uses_the_height_of_the_next_block() {
+        let mut genesis = Genesis::new(1);
+        let faucet_pk = genesis.faucet.pk;
+        let alice_pk = genesis.alices[0].pk;
+        // empty blocks move the engine on, the book's accrual height stays behind
+        for _ in 0..2 {
+            crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap();
+        }
+        let sqn = genesis.l1.sqn;
+        let pay = |valid_until: u32, sk: &mut SigningKey| {
+            Transaction::Pay(Tx::new_signed(faucet_pk, 0, L1_CHAIN_ID, Some(valid_until), None, Payment { to: alice_pk, amount: Balance(PAY_AMOUNT), expected_total: None }, sk))
+        };
+        let late = pay(sqn - 1, &mut genesis.faucet.sk);
+        let last_call = pay(sqn, &mut genesis.faucet.sk);
+        assert_eq!(genesis.l1.validate(&late).unwrap_err(), EngineError::Expired);
+        assert_eq!(genesis.l1.validate(&last_call), Ok(()));
+        assert_eq!(genesis.l1.account_book.validate(&last_call, Layer::L1, sqn + 1).unwrap_err(), EngineError::Expired);
+
+        genesis.l1.txns = vec![late];
+        assert_eq!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).unwrap_err(), EngineError::Expired);
+        genesis.l1.txns = vec![last_call];
+        assert!(crate::l1_engine::process(&mut genesis.l1, |_| Err(EngineError::InvalidReceipt)).is_ok());
+    }
+
+    #[test]
+    fn delegate_allowance_covers_the_fee() {
+        let mut genesis = Genesis::new(2);
+        let faucet_pk = genesis.faucet.pk;
+        let (mut bot, bob) = (genesis.alices[0].clone(), genesis.alices[1].pk);
+        let (fid, bot_id) = (pk_to_hash(&faucet_pk), pk_to_hash(&bot.pk));
+        let book = &mut genesis.l1.account_book;
+        book.set_params(ChainParams { fee_policy: FeePolicy::Flat(3), ..ChainParams::for_layer(Layer::L1) });
+        let tx = l1_tx(faucet_pk, 0, SetDelegate { delegate: bot.pk, allowance: 10 }, &mut genesis.faucet.sk);
+        let updates = book.process_set_delegate(&tx, 0).unwrap();
+        book.update_tree(updates);
+
+        // 8 is within the allowance, 8 and the fee is not
+        let tx = delegated(faucet_pk, 1, Payment { to: bob, amount: Balance(8), expected_total: None }, &mut bot.sk);
+        assert_eq!(book.process_payment(&tx, 0).unwrap_err(), EngineError::AllowanceExceeded);
+        // the fee is charged to the allowance along with the amount
+        let before = book.accounts[&fid].amount;
+        let tx = delegated(faucet_pk, 1, Payment { to: bob, amount: Balance(4), expected_total: None }, &mut bot.sk);
+        let updates = book.process_payment(&tx, 0).unwrap();
+        book.update_tree(updates);
+        assert_eq!(book.accounts[&fid].amount, before.0 - 7);
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.delegates[&bot_id] == 3));
+        // a batch pays the fee once, on the sum of its outputs
+        let tx = delegated(faucet_pk, 2, BatchPayment { outputs: vec![(bob, 1)] }, &mut bot.sk);
+        assert_eq!(book.process_batch_payment(&tx, 0).unwrap_err(), EngineError::AllowanceExceeded);
+        // no fee on a deposit, its amount alone uses the allowance up
+        let rollup_pk = genesis.rollup.pk;
+        let tx = l1_tx(faucet_pk, 2, CreateRollupAccount { rollup_pk, bond: 0, genesis_inbox: vec![], genesis_amount: 0 }, &mut genesis.faucet.sk);
+        let updates = book.process_create_rollup_account(&tx, 0).unwrap();
+        book.update_tree(updates);
+        let tx = delegated(faucet_pk, 3, L1ToL2Deposit { rollup_pk, amount: 3 }, &mut bot.sk);
+        let updates = book.process_deposit_l1(&tx, 0).unwrap();
+        book.update_tree(updates);
+        assert!(book.account_hash_verify(&faucet_pk, |a| a.delegates.is_empty()));
+    }
+
+    #[test]
+    fn engine_with_observers_runs_on_another_thread() {
+        let mut genesis = Genesis::new(1);
+        let capture = Capture::default();
+        genesis.l1.observers.push(Box::new(capture.clone()));
+        let tx = l1_tx(genesis.faucet.pk, 0, Payment { to: genesis.alices[0].pk, amount: Balance(PAY_AMOUNT), expected_total: None }, &mut genesis.faucet.sk);
+        genesis.l1.txns.push(Transaction::Pay(tx));
+        let mut l1 = genesis.l1;
+        let worker = std::thread::spawn(move || {
+            crate::l1_engine::process(&mut l1, |_| Err(EngineError::InvalidReceipt)).map(|_| l1)
+        });
+        let l1 = worker.join().unwrap().unwrap();
+        assert_eq!(l1.sqn, 1);
+        assert!(!capture.0.lock().unwrap().is_empty());
+    }
+
+    // A block as a node of this encoding version wrote it, a payment and a txn of a tag
+    // from a later version. A change that breaks decoding it must bump TX_ENCODING_VERSION
+    #[test]
+    fn golden_block_decodes() {
+        let hex = concat!(
+            "02000000000000001d01000000000000010058000000000000003056301006072a8648ce3d020106052b8104000a0342",
+            "00041b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f70beaf8f588b541507fed6a642c5",
+            "ab42dfdf8120a7f639de5122d47a69a8e8d10000000001000000000058000000000000003056301006072a8648ce3d02",
+            "0106052b8104000a034200044d4b6cd1361032ca9bd2aeb9d900aa4d45d9ead80ac9423374c451a7254d07662a3eada2",
+            "d0fe208b6d257ceb0f064284662e857f57b66b54c198bd310ded36d00a00000000000000000000000000000000c1685d",
+            "039895d3d03aa8c4b7d8c81a3336fcb95c2d1d5724e0f654a7c610d9783d765d00f169847862646b04cd6a90536880f1",
+            "13f6641b1260fd0aca1d79bd47050000000000000001c8010203",
+        );
+        let data: Vec<u8> = (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect();
+        let block: Vec<Transaction> = bincode_config::deserialize(&data).unwrap();
+        assert_eq!(bincode_config::serialize(&block).unwrap(), data);
+
+        let faucet_pk = *SigningKey::from_slice(&[1u8; 32]).unwrap().verifying_key();
+        let alice_pk = *SigningKey::from_slice(&[2u8; 32]).unwrap().verifying_key();
+        let Transaction::Pay(pay) = &block[0] else { panic!("{:?}", block[0]) };
+        assert_eq!((pay.sender, pay.sqn, pay.chain_id, pay.valid_until, pay.delegate), (faucet_pk, 0, L1_CHAIN_ID, None, None));
+        assert_eq!((pay.payload.to, pay.payload.amount, pay.payload.expected_total), (alice_pk, Balance(PAY_AMOUNT), None));
+        assert!(pay.sig_verify());
+        assert_eq!(block[0].id(), [
+            0x9f, 0xd5, 0x56, 0x13, 0x1b, 0x4e, 0x8e, 0x6c, 0x5f, 0x3a, 0x3b, 0x76, 0x88, 0xc3, 0x6a, 0xbc,
+            0x92, 0x08, 0x41, 0x04, 0x4f, 0x51, 0x34, 0x92, 0x67, 0xfc, 0x12, 0xb7, 0x1f, 0x01, 0x67, 0x59,
+        ]);
+        assert!(matches!(&block[1], Transaction::Unknown { tag: 200, bytes } if *bytes == vec![1, 2, 3]));
+        assert_eq!(Transaction::decode(&block[0].encode().unwrap()).unwrap().id(), block[0].id());
     }
 }
@@ -0,0 +1,62 @@
+// Allocation counts of AccountBook::new_batch. A binary of its own, so the counting
+// allocator sees nothing but these tests.
+use common::common::*;
+use k256::ecdsa::{SigningKey, VerifyingKey};
+use rand::rngs::OsRng;
+
+// counts allocations made by the current thread while enabled
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOC_COUNT: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOC_COUNT.try_with(|c| c.set(c.get().map(|n| n + 1)));
+        std::alloc::System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn count_allocs<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    ALLOC_COUNT.with(|c| c.set(Some(0)));
+    let r = f();
+    let n = ALLOC_COUNT.with(|c| c.replace(None)).unwrap();
+    (r, n)
+}
+
+fn random_keys(n: usize) -> Vec<VerifyingKey> {
+    let mut csprng = OsRng;
+    (0..n).map(|_| *SigningKey::random(&mut csprng).verifying_key()).collect()
+}
+
+// about two tree nodes per leaf and a share of a map node, with room to spare. A path
+// update per key, what new_batch avoids, allocates a node per level of the tree
+const ALLOCS_PER_ACCOUNT: usize = 8;
+
+fn check_new_batch(n: usize) {
+    let keys = random_keys(n);
+    let (book, allocs) = count_allocs(|| AccountBook::new_batch(keys.clone(), 10));
+    assert_eq!(book.get_num_accounts(), n);
+    assert!(book.verify_partial_root());
+    assert!(allocs <= ALLOCS_PER_ACCOUNT * n, "{} allocations for {} accounts", allocs, n);
+}
+
+#[test]
+fn new_batch_allocations_are_bounded() {
+    check_new_batch(1_000);
+}
+
+// key generation alone takes minutes in debug builds, run with
+// cargo test --release --test new_batch_allocs -- --ignored
+#[test]
+#[ignore]
+fn new_batch_large_genesis() {
+    check_new_batch(100_000);
+}
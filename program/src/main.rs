@@ -23,8 +23,16 @@ pub fn main() {
     if !verified{
         panic!("cannot verify input");
     }
-    let output = common::l2_engine::process(&mut input).unwrap();
+    // the host supplies the book, not the rules it is processed under
+    if input.layer != Layer::L2 || *input.account_book.params() != ChainParams::rollup() {
+        panic!("input params are not the rollup's");
+    }
+    let (output, summary) = common::l2_engine::process(&mut input).unwrap();
+    // the fixed L2Commitments::LEN bytes first, at offset 0 for the L1 verifier,
+    // then the header and summary for the host
+    sp1_zkvm::io::commit_slice(&output.commitments().to_bytes());
     sp1_zkvm::io::commit(&output);
+    sp1_zkvm::io::commit(&summary);
 
 
     // let n = sp1_zkvm::io::read::<u32>();
@@ -15,6 +15,7 @@ use clap::Parser;
 use sp1_sdk::{ProverClient, SP1Stdin};
 // use sp1_sdk::{HashableKey, ProverClient, SP1ProofWithPublicValues, SP1Stdin, SP1VerifyingKey};
 
+use common::bincode_config;
 use common::common::*;
 use rand::rngs::OsRng;
 
@@ -43,14 +44,16 @@ fn create_input(network_size: usize, num_txns: usize) -> EngineData {
         keys.push(f.pk);
         signers.push(f);
     }
-    let mut engine_data = EngineData::new_batch(keys, 1_000_000_000_000);
+    let mut engine_data = EngineData::new_batch(keys, 1_000_000_000_000, Layer::L2);
+    // what the guest checks its input against
+    assert_eq!(*engine_data.account_book.params(), ChainParams::rollup());
 
     let mut txns = vec![];
-    let amount = 1u128;
+    let amount = Balance(1);
     for i in 0..num_txns {
         let to = signers[(i + 1) % network_size].pk;
         let from = &mut signers[i % network_size];
-        txns.push(Transaction::Pay(Tx::new(from.pk, from.sqn, Payment { to, amount }, &mut from.sk)));
+        txns.push(Transaction::Pay(Tx::new_on_chain(L2_CHAIN_ID, from.pk, from.sqn, Payment { to, amount, expected_total: None }, &mut from.sk)));
         from.sqn += 1;
     }
     engine_data.txns = txns;
@@ -75,6 +78,10 @@ fn main() {
     stdin.write(&input);
 
 
+    // the block the guest proves, run natively to check its public values against
+    let mut native: EngineData = bincode_config::deserialize(&bincode_config::serialize(&input).unwrap()).unwrap();
+    let (host_header, host_summary) = common::l2_engine::process(&mut native).expect("native run");
+
     let time_start = clock();
     let proof = client
             .prove(&pk, stdin)
@@ -89,12 +96,16 @@ fn main() {
     let time = clock() - time_start;
     println!("Prover, verify time {}", time );
 
-    // let header: BlockHeaderL2 = ;
-    // let host_header = common::l2_engine::process(&mut input).expect("native run");
-    // if header.hash() != host_header.hash() {
-    //     println!("Prover, header hash: {:?}", header.hash());
-    //     println!("Host,   header hash: {:?}", host_header.hash());
-    // }
+    // in the order the guest commits them
+    let mut public_values = proof.public_values.clone();
+    let mut commitments = [0u8; L2Commitments::LEN];
+    public_values.read_slice(&mut commitments);
+    let header: BlockHeaderL2 = public_values.read();
+    let summary: BlockSummary = public_values.read();
+    assert_eq!(L2Commitments::from_bytes(&commitments).expect("commitments"), host_header.commitments());
+    assert_eq!(header.hash(), host_header.hash(), "header");
+    assert_eq!(summary, host_summary);
+    println!("Prover, public values match the native run");
 }
 
 